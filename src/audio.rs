@@ -0,0 +1,83 @@
+use std::sync::mpsc;
+use std::thread;
+
+use cpal::traits::{HostTrait, DeviceTrait, StreamTrait};
+
+use crate::player::Player;
+use crate::synth;
+
+/// starts playing a player through the default output device, on a separate
+/// thread. sending a message on the returned channel stops playback. the
+/// player belongs to the audio thread from then on, so it should be
+/// controlled through a `Remote` made beforehand.
+pub fn start(player: Player) -> anyhow::Result<mpsc::Sender<()>> {
+    let host = cpal::default_host();
+    let device = host.default_output_device()
+        .ok_or(anyhow::anyhow!("No audio output device found"))?;
+    
+    start_audio(device, player)
+}
+
+pub fn start_audio(device: cpal::Device, player: Player)
+  -> anyhow::Result<mpsc::Sender<()>> {
+    let config = device.default_output_config()?;
+    println!("Using {}", device.name()?);
+    
+    let (tx, rx) = mpsc::channel();
+    
+    thread::spawn(move || {
+        let stream = match config.sample_format() {
+            cpal::SampleFormat::F32 => make_stream::<f32>(&device, &config.into(), player),
+            cpal::SampleFormat::I16 => make_stream::<i16>(&device, &config.into(), player),
+            cpal::SampleFormat::U16 => make_stream::<u16>(&device, &config.into(), player),
+        }.expect("could not make stream");
+        
+        stream.play().expect("could not play stream");
+        rx.recv().expect("failed to receive stop message");
+    });
+    
+    Ok(tx)
+}
+
+fn make_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, mut player: Player)
+  -> anyhow::Result<cpal::Stream>
+where T : cpal::Sample {
+    let sample_rate = config.sample_rate.0 as f32;
+    let dt = 1.0 / sample_rate as f64;
+    let channels = config.channels as usize;
+        
+    println!("Sample rate: {}Hz", sample_rate);
+        
+    let err_fn = |err| eprintln!("an error occurred: {}", err);
+    
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            write_data(data, channels, dt, &mut player);
+        },
+        err_fn)?;
+    
+    Ok(stream)
+}
+
+/// fills a buffer of output from the player. this runs on the audio thread,
+/// so it never waits for anything: changes to the player arrive as messages.
+fn write_data<T>(output: &mut [T], channels: usize, dt: f64, player: &mut Player)
+where
+    T: cpal::Sample,
+{
+    for frame in output.chunks_mut(channels) {
+        let s = player.sample(dt);
+        
+        // mono devices get both sides mixed together, and any channels past
+        // the first two (e.g. on a surround device) are left silent.
+        if channels == 1 {
+            frame[0] = cpal::Sample::from::<f32>(&synth::downmix(s));
+            continue;
+        }
+        
+        for (i, sample) in frame.iter_mut().enumerate() {
+            *sample = cpal::Sample::from::<f32>(&s.get(i).copied().unwrap_or(0.0));
+        }
+    }
+}
\ No newline at end of file
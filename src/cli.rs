@@ -0,0 +1,324 @@
+use std::fs::File;
+
+use anyhow::{anyhow, bail, Context};
+
+use cancrizans::export::{self, ExportSettings, SampleFormat};
+use cancrizans::midi::{self, PartMapping};
+use cancrizans::song::Song;
+use cancrizans::synth::{self, Instrument};
+
+pub const USAGE: &str = "usage:
+    cancrizans [FILE.crz]
+        open the editor, optionally loading a song.
+    cancrizans render FILE.crz OUT.wav [--rate HZ] [--format i16|i24|f32] [--mono]
+        render a song to a stereo WAV file without opening a window.
+    cancrizans import FILE.mid OUT.crz [--by tracks|channels]
+        convert a standard MIDI file to a song.
+    cancrizans export FILE.crz OUT.mid
+        convert a song to a type 1 standard MIDI file.
+    cancrizans info FILE.crz...
+        print information about songs.
+    cancrizans validate FILE.crz...
+        check that songs can be loaded and are well-formed.
+    cancrizans help
+        show this message.";
+
+/// a command given to cancrizans on the command line.
+pub enum Command {
+    /// open the GUI, optionally loading a song file.
+    Gui { file: Option<String> },
+    
+    /// render a song to a WAV file, without a window or audio device.
+    Render { input: String, output: String, settings: ExportSettings },
+    
+    /// convert a MIDI file to a song.
+    Import { input: String, output: String, mapping: PartMapping },
+    
+    /// convert a song to a MIDI file.
+    Export { input: String, output: String },
+    
+    /// print information about some songs.
+    Info { files: Vec<String> },
+    
+    /// check that some songs load and are well-formed.
+    Validate { files: Vec<String> },
+    
+    /// print usage information.
+    Help,
+}
+
+impl Command {
+    /// parses a command from a list of arguments (not including the program name.)
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> anyhow::Result<Command> {
+        let first = match args.next() {
+            Some(arg) => arg,
+            None => return Ok(Command::Gui { file: None }),
+        };
+        
+        match &first[..] {
+            "help" | "-h" | "--help" => Ok(Command::Help),
+            "render" => {
+                let mut settings = ExportSettings::default();
+                let mut files = Vec::new();
+                
+                while let Some(arg) = args.next() {
+                    match &arg[..] {
+                        "--rate" => {
+                            let rate = args.next().ok_or(anyhow!("--rate needs a value"))?;
+                            settings.sample_rate = rate.parse()
+                                .with_context(|| format!("invalid sample rate '{}'", rate))?;
+                            
+                            if settings.sample_rate == 0 {
+                                bail!("the sample rate must be greater than zero");
+                            }
+                        },
+                        "--format" => {
+                            let format = args.next().ok_or(anyhow!("--format needs a value"))?;
+                            settings.format = match &format[..] {
+                                "i16" => SampleFormat::Int16,
+                                "i24" => SampleFormat::Int24,
+                                "f32" => SampleFormat::Float32,
+                                _ => bail!("unknown sample format '{}'. expected i16, i24 or f32", format),
+                            };
+                        },
+                        "--mono" => settings.mono = true,
+                        _ if arg.starts_with("--") => bail!("unknown option '{}'", arg),
+                        _ => files.push(arg),
+                    }
+                }
+                
+                match &files[..] {
+                    [input, output] => Ok(Command::Render {
+                        input: input.clone(),
+                        output: output.clone(),
+                        settings,
+                    }),
+                    _ => bail!("render needs exactly one input and one output file\n\n{}", USAGE),
+                }
+            },
+            "import" => {
+                let mut mapping = PartMapping::Auto;
+                let mut files = Vec::new();
+                
+                while let Some(arg) = args.next() {
+                    match &arg[..] {
+                        "--by" => {
+                            let by = args.next().ok_or(anyhow!("--by needs a value"))?;
+                            mapping = match &by[..] {
+                                "tracks" => PartMapping::Tracks,
+                                "channels" => PartMapping::Channels,
+                                _ => bail!("unknown part mapping '{}'. expected tracks or channels", by),
+                            };
+                        },
+                        _ if arg.starts_with("--") => bail!("unknown option '{}'", arg),
+                        _ => files.push(arg),
+                    }
+                }
+                
+                match &files[..] {
+                    [input, output] => Ok(Command::Import {
+                        input: input.clone(),
+                        output: output.clone(),
+                        mapping,
+                    }),
+                    _ => bail!("import needs exactly one input and one output file\n\n{}", USAGE),
+                }
+            },
+            "export" => {
+                let files: Vec<String> = args.collect();
+                
+                match &files[..] {
+                    [input, output] => Ok(Command::Export {
+                        input: input.clone(),
+                        output: output.clone(),
+                    }),
+                    _ => bail!("export needs exactly one input and one output file\n\n{}", USAGE),
+                }
+            },
+            "info" | "validate" => {
+                let files: Vec<String> = args.collect();
+                
+                if files.is_empty() {
+                    bail!("{} needs at least one file\n\n{}", first, USAGE);
+                }
+                
+                if first == "info" {
+                    Ok(Command::Info { files })
+                } else {
+                    Ok(Command::Validate { files })
+                }
+            },
+            _ if first.starts_with('-') => bail!("unknown option '{}'\n\n{}", first, USAGE),
+            _ => match args.next() {
+                None => Ok(Command::Gui { file: Some(first) }),
+                Some(_) => bail!("unexpected arguments\n\n{}", USAGE),
+            },
+        }
+    }
+}
+
+/// loads a song file, with a descriptive error if it can't be read.
+pub fn load_song(path: &str) -> anyhow::Result<Song> {
+    let file = File::open(path).with_context(|| format!("could not open {}", path))?;
+    Song::load(file).with_context(|| format!("could not load {}", path))
+}
+
+/// renders the song at `input` to a WAV file at `output`. the song's own
+/// instruments are used where it has them, and default ones otherwise.
+pub fn render(input: &str, output: &str, settings: ExportSettings,
+    mut instruments: Vec<Instrument>) -> anyhow::Result<()> {
+    let song = load_song(input)?;
+    let problems = song.validate();
+    
+    if !problems.is_empty() {
+        bail!("{} is invalid:\n  {}", input, problems.join("\n  "));
+    }
+    
+    synth::load_instruments(&mut instruments, &song.instruments);
+    
+    export::export_wav(output, &song, instruments, settings)
+        .with_context(|| format!("could not write {}", output))?;
+    
+    println!("rendered {} to {} ({}Hz, {}-bit, {})", input, output,
+        settings.sample_rate, settings.format.bits_per_sample(),
+        if settings.mono { "mono" } else { "stereo" });
+    
+    Ok(())
+}
+
+/// converts the MIDI file at `input` to a song, saved at `output`.
+pub fn import(input: &str, output: &str, mapping: PartMapping) -> anyhow::Result<()> {
+    let (song, report) = midi::import(input, mapping)
+        .with_context(|| format!("could not import {}", input))?;
+    
+    let file = File::create(output).with_context(|| format!("could not create {}", output))?;
+    song.save(file).with_context(|| format!("could not write {}", output))?;
+    
+    println!("{}", report);
+    
+    Ok(())
+}
+
+/// converts the song at `input` to a MIDI file at `output`.
+pub fn export(input: &str, output: &str) -> anyhow::Result<()> {
+    let song = load_song(input)?;
+    
+    midi::export(&song, output).with_context(|| format!("could not write {}", output))?;
+    println!("exported {} to {}", input, output);
+    
+    Ok(())
+}
+
+/// prints a summary of each song.
+pub fn info(files: &[String]) -> anyhow::Result<()> {
+    for (i, path) in files.iter().enumerate() {
+        let song = load_song(path)?;
+        let duration = song.duration();
+        
+        if i > 0 {
+            println!();
+        }
+        
+        println!("{}", path);
+        println!("  bpm:            {}", song.bpm);
+        
+        if !song.tempo_changes.is_empty() {
+            println!("  tempo changes:  {}", song.tempo_changes.len());
+        }
+        
+        println!("  time signature: {}", song.meter);
+        
+        if !song.meter_changes.is_empty() {
+            println!("  meter changes:  {}", song.meter_changes.len());
+        }
+        
+        println!("  duration:       {:.2}s", duration);
+        println!("  instruments:    {}", song.instruments.len());
+        
+        for (n, part) in song.parts.iter().enumerate() {
+            println!("  part {}:         {} notes", n + 1, part.len());
+        }
+    }
+    
+    Ok(())
+}
+
+/// checks each song, printing any problems. fails if any song is invalid.
+pub fn validate(files: &[String]) -> anyhow::Result<()> {
+    let mut num_invalid = 0;
+    
+    for path in files {
+        match load_song(path) {
+            Ok(song) => {
+                let problems = song.validate();
+                
+                if problems.is_empty() {
+                    println!("{}: ok", path);
+                } else {
+                    num_invalid += 1;
+                    
+                    for problem in problems {
+                        println!("{}: {}", path, problem);
+                    }
+                }
+            },
+            Err(err) => {
+                num_invalid += 1;
+                println!("{}: {:#}", path, err);
+            },
+        }
+    }
+    
+    if num_invalid > 0 {
+        bail!("{} of {} files are invalid", num_invalid, files.len());
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn parse(args: &[&str]) -> anyhow::Result<Command> {
+        Command::parse(args.iter().map(|a| a.to_string()))
+    }
+    
+    #[test]
+    fn parses_good_arguments() {
+        assert!(matches!(parse(&[]), Ok(Command::Gui { file: None })));
+        assert!(matches!(parse(&["song.crz"]), Ok(Command::Gui { file: Some(f) }) if f == "song.crz"));
+        assert!(matches!(parse(&["--help"]), Ok(Command::Help)));
+        
+        match parse(&["render", "in.crz", "--rate", "8000", "out.wav", "--format", "f32", "--mono"]) {
+            Ok(Command::Render { input, output, settings }) => {
+                assert_eq!((&input[..], &output[..]), ("in.crz", "out.wav"));
+                assert_eq!(settings.sample_rate, 8000);
+                assert!(settings.format == SampleFormat::Float32 && settings.mono);
+            },
+            _ => panic!("expected a render command"),
+        }
+        
+        assert!(matches!(parse(&["import", "in.mid", "out.crz", "--by", "channels"]),
+            Ok(Command::Import { mapping: PartMapping::Channels, .. })));
+        assert!(matches!(parse(&["validate", "a.crz", "b.crz"]), Ok(Command::Validate { files }) if files.len() == 2));
+    }
+    
+    #[test]
+    fn rejects_bad_arguments() {
+        for args in [
+            &["render", "in.crz"][..],
+            &["render", "in.crz", "out.wav", "--rate"],
+            &["render", "in.crz", "out.wav", "--rate", "0"],
+            &["render", "in.crz", "out.wav", "--format", "i8"],
+            &["render", "in.crz", "out.wav", "--loud"],
+            &["import", "in.mid", "out.crz", "--by", "colour"],
+            &["export", "in.crz"],
+            &["info"],
+            &["--verbose"],
+            &["a.crz", "b.crz"],
+        ] {
+            assert!(parse(args).is_err(), "{:?} should be rejected", args);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::path::Path;
+
+use crate::player::Player;
+use crate::song::Song;
+use crate::synth::{self, Instrument, Stereo};
+
+/// the longest time, in seconds, that rendering will carry on past the end
+/// of the last note while waiting for envelopes and effects to die away.
+pub const MAX_TAIL: f64 = 20.0;
+
+/// the level below which the output is considered to be silent.
+const SILENCE_THRESHOLD: f32 = 1.0e-4;
+
+/// how long, in seconds, the output must stay silent after the last note
+/// for the tail to be considered finished.
+const SILENCE_DURATION: f64 = 0.25;
+
+/// the format of the samples written to a WAV file.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SampleFormat {
+    /// 16-bit signed integer PCM.
+    Int16,
+    
+    /// 24-bit signed integer PCM.
+    Int24,
+    
+    /// 32-bit IEEE float.
+    Float32,
+}
+
+/// the settings used when rendering a song to a file.
+#[derive(Clone, Copy)]
+pub struct ExportSettings {
+    /// the sample rate of the rendered audio, in Hz.
+    pub sample_rate: u32,
+    
+    /// the format in which each sample is stored.
+    pub format: SampleFormat,
+    
+    /// whether to mix both channels down into a single one.
+    pub mono: bool,
+}
+
+impl ExportSettings {
+    pub fn new(sample_rate: u32, format: SampleFormat) -> ExportSettings {
+        ExportSettings { sample_rate, format, mono: false }
+    }
+    
+    /// the number of channels written to the file.
+    pub fn channels(self) -> u16 {
+        if self.mono { 1 } else { 2 }
+    }
+}
+
+impl Default for ExportSettings {
+    fn default() -> ExportSettings {
+        ExportSettings::new(44100, SampleFormat::Int16)
+    }
+}
+
+impl SampleFormat {
+    pub fn bits_per_sample(self) -> u16 {
+        match self {
+            SampleFormat::Int16 => 16,
+            SampleFormat::Int24 => 24,
+            SampleFormat::Float32 => 32,
+        }
+    }
+}
+
+/// renders a whole song offline, using the given instruments, and returns
+/// the resulting samples. rendering continues past the end of the song until
+/// every envelope has been released and the effects have fallen silent (or
+/// until `MAX_TAIL` seconds have passed.) no audio device is needed. a song
+/// which never ends, because its tempo drops to zero, can't be rendered.
+pub fn render(song: &Song, instruments: Vec<Instrument>, sample_rate: u32) -> anyhow::Result<Vec<Stereo>> {
+    if song.bpm == 0 || song.tempo_changes.iter().any(|c| !(c.bpm > 0.0 && c.bpm.is_finite())) {
+        anyhow::bail!("the tempo must always be greater than zero");
+    }
+    
+    let end = song.duration();
+    
+    if !end.is_finite() {
+        anyhow::bail!("the song never ends");
+    }
+    
+    let (mut player, note_channel) = Player::new();
+    let dt = 1.0 / sample_rate as f64;
+    
+    player.instruments = instruments;
+    player.mixer = song.mixer.clone();
+    player.tempo = song.tempo_map();
+    player.flush_notes();
+    song.sequence(note_channel);
+    player.paused = false;
+    
+    let release = player.instruments
+        .iter()
+        .map(|instr| instr.release_time() as f64)
+        .fold(0.0, f64::max);
+    
+    let min_end = end + release;
+    let max_end = end + release + MAX_TAIL;
+    let silence_samples = (SILENCE_DURATION * sample_rate as f64) as usize;
+    
+    let mut samples = Vec::with_capacity(((min_end + SILENCE_DURATION) * sample_rate as f64) as usize);
+    let mut silent_for = 0;
+    
+    loop {
+        let s = player.sample(dt);
+        samples.push(s);
+        
+        if s[0].abs() < SILENCE_THRESHOLD && s[1].abs() < SILENCE_THRESHOLD {
+            silent_for += 1;
+        } else {
+            silent_for = 0;
+        }
+        
+        if player.playhead >= max_end ||
+            (player.playhead >= min_end && silent_for >= silence_samples) {
+            break;
+        }
+    }
+    
+    Ok(samples)
+}
+
+/// writes a set of stereo samples to a WAV file at the given path, mixing
+/// them down to mono if the settings ask for it. samples outside the range
+/// -1.0 to 1.0 are clipped for the integer formats.
+pub fn write_wav<P: AsRef<Path>>(path: P, samples: &[Stereo], settings: ExportSettings) -> anyhow::Result<()> {
+    let spec = hound::WavSpec {
+        channels: settings.channels(),
+        sample_rate: settings.sample_rate,
+        bits_per_sample: settings.format.bits_per_sample(),
+        sample_format: match settings.format {
+            SampleFormat::Float32 => hound::SampleFormat::Float,
+            _ => hound::SampleFormat::Int,
+        },
+    };
+    
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    
+    let mut write = |s: f32| match settings.format {
+        SampleFormat::Int16 => writer.write_sample((s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16),
+        SampleFormat::Int24 => writer.write_sample((s.clamp(-1.0, 1.0) * 8388607.0) as i32),
+        SampleFormat::Float32 => writer.write_sample(s),
+    };
+    
+    for &frame in samples {
+        if settings.mono {
+            write(synth::downmix(frame))?;
+        } else {
+            for s in frame {
+                write(s)?;
+            }
+        }
+    }
+    
+    writer.finalize()?;
+    
+    Ok(())
+}
+
+/// renders a song with the given instruments and saves it as a WAV file.
+pub fn export_wav<P: AsRef<Path>>(path: P, song: &Song, instruments: Vec<Instrument>,
+    settings: ExportSettings) -> anyhow::Result<()> {
+    let samples = render(song, instruments, settings.sample_rate)?;
+    write_wav(path, &samples, settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::{Meter, Note, TempoChange, Time, BEAT_DIVISIONS};
+    use crate::synth::{Operator, WaveType};
+    
+    const RATE: u32 = 8000;
+    
+    fn instrument() -> Instrument {
+        let mut instr = Instrument::new(4);
+        Operator::new(WaveType::Sine, false, 1.0).env(0.0, 0.0, 1.0, 0.5).send(0, 1.0).add(&mut instr);
+        instr
+    }
+    
+    #[test]
+    fn renders_until_the_tail_dies_away() {
+        let mut song = Song::new(1, 120, Meter::new(4, 4));
+        song.add_note(0, Note::new(48, 0, 0, 2 * BEAT_DIVISIONS, 1.0));
+        
+        let samples = render(&song, vec![instrument()], RATE).unwrap();
+        
+        // the note lasts a second, then takes half a second to release, and
+        // is followed by a short stretch of silence.
+        let end = ((1.5 + SILENCE_DURATION) * RATE as f64) as usize;
+        assert!(samples.len() >= end && samples.len() < end + RATE as usize / 10);
+        assert!(samples[..RATE as usize].iter().any(|s| s[0].abs() > 0.1));
+        assert!(samples[samples.len() - RATE as usize / 10..].iter().all(|s| s[0].abs() < SILENCE_THRESHOLD));
+    }
+    
+    #[test]
+    fn refuses_songs_which_never_end() {
+        let mut song = Song::new(1, 0, Meter::new(4, 4));
+        song.add_note(0, Note::new(48, 0, 0, BEAT_DIVISIONS, 1.0));
+        assert!(render(&song, vec![instrument()], RATE).is_err());
+        
+        song.bpm = 120;
+        song.tempo_changes.push(TempoChange { time: Time::new(0, 48), bpm: 0.0, ramp: false });
+        assert!(render(&song, vec![instrument()], RATE).is_err());
+    }
+    
+    #[test]
+    fn writes_each_frame_in_the_chosen_format() {
+        let path = std::env::temp_dir().join(format!("cancrizans-export-{}.wav", std::process::id()));
+        let samples = [[0.5, -0.25], [2.0, 0.0]];
+        
+        write_wav(&path, &samples, ExportSettings::new(RATE, SampleFormat::Float32)).unwrap();
+        let written: Vec<f32> = hound::WavReader::open(&path).unwrap().samples().map(|s| s.unwrap()).collect();
+        assert_eq!(written, [0.5, -0.25, 2.0, 0.0]);
+        
+        // mono files get the average of each frame, clipped for integers.
+        let settings = ExportSettings { mono: true, ..ExportSettings::new(RATE, SampleFormat::Int16) };
+        write_wav(&path, &samples, settings).unwrap();
+        let written: Vec<i16> = hound::WavReader::open(&path).unwrap().samples().map(|s| s.unwrap()).collect();
+        assert_eq!(written, [i16::MAX / 8, i16::MAX]);
+        
+        std::fs::remove_file(&path).unwrap();
+    }
+}
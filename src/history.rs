@@ -0,0 +1,392 @@
+use std::collections::VecDeque;
+
+use crate::mixer::Channel;
+use crate::remote::Remote;
+use crate::song::{Meter, Note, Song};
+use crate::synth::{Mono, Operator, StealPolicy};
+use crate::synth::lfo::Lfo;
+use crate::synth::modulation::Route;
+
+/// a single change to a song or its instruments, which remembers enough
+/// about what came before that it can be undone.
+#[derive(Clone)]
+pub enum Edit {
+    /// takes some notes out of a part and puts others in. adding, removing
+    /// and moving notes are all done this way.
+    Notes {
+        part: usize,
+        removed: Vec<Note>,
+        added: Vec<Note>,
+    },
+    
+    /// changes the beats-per-minute at the start of the song.
+    Bpm {
+        from: u32,
+        to: u32,
+    },
+    
+    /// changes the time signature at the start of the song.
+    Meter {
+        from: Meter,
+        to: Meter,
+    },
+    
+    /// changes one of the operators of one of the player's instruments.
+    Operator {
+        instrument: usize,
+        index: usize,
+        from: Box<Operator>,
+        to: Box<Operator>,
+    },
+    
+    /// changes the number of voices of one of the player's instruments, and
+    /// how it steals them.
+    Polyphony {
+        instrument: usize,
+        from: (usize, StealPolicy),
+        to: (usize, StealPolicy),
+    },
+    
+    /// changes whether one of the player's instruments is mono, and how it
+    /// glides between notes.
+    Mono {
+        instrument: usize,
+        from: Mono,
+        to: Mono,
+    },
+    
+    /// changes one of the LFOs of one of the player's instruments.
+    Lfo {
+        instrument: usize,
+        index: usize,
+        from: Lfo,
+        to: Lfo,
+    },
+    
+    /// changes one of the routes in the modulation matrix of one of the
+    /// player's instruments.
+    Route {
+        instrument: usize,
+        index: usize,
+        from: Route,
+        to: Route,
+    },
+    
+    /// changes the mixer settings of one of the player's instruments.
+    Channel {
+        instrument: usize,
+        from: Channel,
+        to: Channel,
+    },
+}
+
+impl Edit {
+    /// an edit adding a note to a part.
+    pub fn add_note(part: usize, note: Note) -> Edit {
+        Edit::Notes { part, removed: Vec::new(), added: vec![note] }
+    }
+    
+    /// an edit removing a note from a part.
+    pub fn remove_note(part: usize, note: Note) -> Edit {
+        Edit::Notes { part, removed: vec![note], added: Vec::new() }
+    }
+    
+    /// an edit replacing one note in a part with another.
+    pub fn move_note(part: usize, from: Note, to: Note) -> Edit {
+        Edit::Notes { part, removed: vec![from], added: vec![to] }
+    }
+    
+    /// makes the edit which puts things back the way they were.
+    pub fn inverse(&self) -> Edit {
+        match self.clone() {
+            Edit::Notes { part, removed, added } => Edit::Notes { part, removed: added, added: removed },
+            Edit::Bpm { from, to } => Edit::Bpm { from: to, to: from },
+            Edit::Meter { from, to } => Edit::Meter { from: to, to: from },
+            Edit::Operator { instrument, index, from, to } => Edit::Operator { instrument, index, from: to, to: from },
+            Edit::Polyphony { instrument, from, to } => Edit::Polyphony { instrument, from: to, to: from },
+            Edit::Mono { instrument, from, to } => Edit::Mono { instrument, from: to, to: from },
+            Edit::Lfo { instrument, index, from, to } => Edit::Lfo { instrument, index, from: to, to: from },
+            Edit::Route { instrument, index, from, to } => Edit::Route { instrument, index, from: to, to: from },
+            Edit::Channel { instrument, from, to } => Edit::Channel { instrument, from: to, to: from },
+        }
+    }
+    
+    /// makes the change to the song and the player, letting the player know
+    /// about anything which affects what it's playing.
+    pub fn apply(&self, song: &mut Song, player: &mut Remote) {
+        match self {
+            Edit::Notes { part, removed, added } => {
+                let notes = &mut song.parts[*part];
+                
+                for note in removed {
+                    if let Some(i) = notes.iter().position(|n| n == note) {
+                        notes.remove(i);
+                    }
+                }
+                
+                notes.extend_from_slice(added);
+                
+                if !player.paused() {
+                    player.sequence(song);
+                }
+            },
+            Edit::Bpm { to, .. } => {
+                song.bpm = *to;
+                player.set_tempo(song.tempo_map());
+            },
+            Edit::Meter { to, .. } => song.meter = *to,
+            Edit::Operator { instrument, index, to, .. } => {
+                player.edit_operator(*instrument, *index, |op| *op = to.as_ref().clone());
+            },
+            Edit::Polyphony { instrument, to: (voices, stealing), .. } => {
+                player.set_polyphony(*instrument, *voices, *stealing);
+            },
+            Edit::Mono { instrument, to, .. } => player.set_mono(*instrument, *to),
+            Edit::Lfo { instrument, index, to, .. } => player.set_lfo(*instrument, *index, *to),
+            Edit::Route { instrument, index, to, .. } => player.set_route(*instrument, *index, *to),
+            Edit::Channel { instrument, to, .. } => {
+                player.edit_channel(*instrument, |c| *c = *to);
+            },
+        }
+    }
+    
+    /// whether the edit doesn't actually change anything.
+    fn is_empty(&self) -> bool {
+        match self {
+            Edit::Notes { removed, added, .. } => removed == added,
+            Edit::Bpm { from, to } => from == to,
+            Edit::Meter { from, to } => from == to,
+            Edit::Operator { from, to, .. } => from == to,
+            Edit::Polyphony { from, to, .. } => from == to,
+            Edit::Mono { from, to, .. } => from == to,
+            Edit::Lfo { from, to, .. } => from == to,
+            Edit::Route { from, to, .. } => from == to,
+            Edit::Channel { from, to, .. } => from == to,
+        }
+    }
+    
+    /// combines a following edit of the same setting into this one, so that
+    /// e.g. turning a knob can be undone all at once. notes are never merged.
+    fn merge(&mut self, next: &Edit) -> bool {
+        match (self, next) {
+            (Edit::Bpm { to, .. }, Edit::Bpm { to: next, .. }) => *to = *next,
+            (Edit::Meter { to, .. }, Edit::Meter { to: next, .. }) => *to = *next,
+            (Edit::Operator { instrument, index, to, .. },
+             Edit::Operator { instrument: i, index: j, to: next, .. }) if instrument == i && index == j => {
+                *to = next.clone();
+            },
+            (Edit::Polyphony { instrument, to, .. },
+             Edit::Polyphony { instrument: i, to: next, .. }) if instrument == i => *to = *next,
+            (Edit::Mono { instrument, to, .. },
+             Edit::Mono { instrument: i, to: next, .. }) if instrument == i => *to = *next,
+            (Edit::Lfo { instrument, index, to, .. },
+             Edit::Lfo { instrument: i, index: j, to: next, .. }) if instrument == i && index == j => *to = *next,
+            (Edit::Route { instrument, index, to, .. },
+             Edit::Route { instrument: i, index: j, to: next, .. }) if instrument == i && index == j => *to = *next,
+            (Edit::Channel { instrument, to, .. },
+             Edit::Channel { instrument: i, to: next, .. }) if instrument == i => *to = *next,
+            _ => return false,
+        }
+        
+        true
+    }
+}
+
+/// the edits which have been made to a song, so that they can be undone and
+/// redone. only the most recent few edits are remembered.
+pub struct History {
+    done: VecDeque<Edit>,
+    undone: Vec<Edit>,
+    limit: usize,
+    
+    /// whether the next edit should start a new step, rather than possibly
+    /// being merged into the last one.
+    closed: bool,
+}
+
+impl History {
+    /// makes an empty history which remembers up to `limit` edits.
+    pub fn new(limit: usize) -> History {
+        History {
+            done: VecDeque::new(),
+            undone: Vec::new(),
+            limit,
+            closed: true,
+        }
+    }
+    
+    /// makes an edit, and remembers it so that it can be undone.
+    pub fn make(&mut self, edit: Edit, song: &mut Song, player: &mut Remote) {
+        if edit.is_empty() {
+            return;
+        }
+        
+        edit.apply(song, player);
+        self.undone.clear();
+        
+        if !self.closed {
+            if let Some(last) = self.done.back_mut() {
+                if last.merge(&edit) {
+                    if last.is_empty() {
+                        self.done.pop_back();
+                    }
+                    
+                    return;
+                }
+            }
+        }
+        
+        self.push(edit);
+        self.closed = false;
+    }
+    
+    /// remembers an edit which has already been made bit by bit, e.g. a
+    /// recording, so that it can be undone all at once.
+    pub fn remember(&mut self, edit: Edit) {
+        if !edit.is_empty() {
+            self.undone.clear();
+            self.push(edit);
+        }
+        
+        self.closed = true;
+    }
+    
+    /// stops the next edit from being merged into the last one. this should
+    /// be called at the end of each gesture, e.g. when a knob is let go of.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+    
+    /// undoes the last edit, if there is one. returns whether anything was
+    /// undone.
+    pub fn undo(&mut self, song: &mut Song, player: &mut Remote) -> bool {
+        self.closed = true;
+        
+        match self.done.pop_back() {
+            Some(edit) => {
+                edit.inverse().apply(song, player);
+                self.undone.push(edit);
+                true
+            },
+            None => false,
+        }
+    }
+    
+    /// redoes the last edit which was undone, if there is one. returns
+    /// whether anything was redone.
+    pub fn redo(&mut self, song: &mut Song, player: &mut Remote) -> bool {
+        self.closed = true;
+        
+        match self.undone.pop() {
+            Some(edit) => {
+                edit.apply(song, player);
+                self.push(edit);
+                true
+            },
+            None => false,
+        }
+    }
+    
+    /// forgets every edit, e.g. when a different song is opened.
+    pub fn clear(&mut self) {
+        self.done.clear();
+        self.undone.clear();
+        self.closed = true;
+    }
+    
+    fn push(&mut self, edit: Edit) {
+        self.done.push_back(edit);
+        
+        while self.done.len() > self.limit {
+            self.done.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::Player;
+    use crate::synth::{Instrument, WaveType};
+    
+    fn setup() -> (Song, Remote) {
+        let (mut player, _) = Player::new();
+        let mut instr = Instrument::new(4);
+        Operator::new(WaveType::Sine, false, 1.0).add(&mut instr);
+        player.instruments.push(instr);
+        (Song::new(4, 60, Meter::new(4, 4)), player.remote())
+    }
+    
+    #[test]
+    fn undoes_and_redoes_note_edits() {
+        let (mut song, mut player) = setup();
+        let mut history = History::new(16);
+        let a = Note::new(40, 0, 0, 24, 1.0);
+        let b = Note::new(42, 1, 0, 24, 1.0);
+        
+        history.make(Edit::add_note(0, a), &mut song, &mut player);
+        history.make(Edit::move_note(0, a, b), &mut song, &mut player);
+        assert!(song.parts[0] == vec![b]);
+        
+        assert!(history.undo(&mut song, &mut player));
+        assert!(song.parts[0] == vec![a]);
+        assert!(history.undo(&mut song, &mut player));
+        assert!(song.parts[0].is_empty());
+        assert!(!history.undo(&mut song, &mut player));
+        
+        assert!(history.redo(&mut song, &mut player));
+        assert!(song.parts[0] == vec![a]);
+        
+        // a new edit forgets anything which was undone.
+        history.make(Edit::remove_note(0, a), &mut song, &mut player);
+        assert!(!history.redo(&mut song, &mut player));
+        assert!(song.parts[0].is_empty());
+    }
+    
+    #[test]
+    fn merges_edits_until_closed() {
+        let (mut song, mut player) = setup();
+        let mut history = History::new(16);
+        
+        for bpm in 61..=70 {
+            history.make(Edit::Bpm { from: song.bpm, to: bpm }, &mut song, &mut player);
+        }
+        
+        history.close();
+        history.make(Edit::Bpm { from: song.bpm, to: 80 }, &mut song, &mut player);
+        assert_eq!(player.tempo().bpm_at(0.0), 80.0);
+        
+        history.undo(&mut song, &mut player);
+        assert_eq!(song.bpm, 70);
+        history.undo(&mut song, &mut player);
+        assert_eq!(song.bpm, 60);
+        assert_eq!(player.tempo().bpm_at(0.0), 60.0);
+        
+        // operator edits are only merged with edits of the same operator.
+        let op = player.instruments()[0].operators[0].clone();
+        let mut louder = op.clone();
+        louder.transpose += 1.0;
+        
+        history.make(Edit::Operator { instrument: 0, index: 0, from: Box::new(op.clone()), to: Box::new(louder.clone()) }, &mut song, &mut player);
+        history.make(Edit::Channel { instrument: 0, from: Channel::default(), to: Channel { gain: 0.5, ..Channel::default() } }, &mut song, &mut player);
+        assert!(player.instruments()[0].operators[0] == louder);
+        
+        history.undo(&mut song, &mut player);
+        assert_eq!(player.mixer().channel(0).gain, 1.0);
+        history.undo(&mut song, &mut player);
+        assert!(player.instruments()[0].operators[0] == op);
+    }
+    
+    #[test]
+    fn forgets_the_oldest_edits() {
+        let (mut song, mut player) = setup();
+        let mut history = History::new(3);
+        
+        for i in 0..5 {
+            history.make(Edit::add_note(0, Note::new(40 + i, 0, 0, 24, 1.0)), &mut song, &mut player);
+        }
+        
+        while history.undo(&mut song, &mut player) {}
+        assert_eq!(song.parts[0].len(), 2);
+    }
+}
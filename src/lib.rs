@@ -0,0 +1,23 @@
+//! cancrizans is an FM synthesiser and sequencer. the song model, the synth
+//! engine and offline rendering are always available; realtime audio output
+//! and the editor are behind the "audio" and "gui" features respectively.
+
+extern crate serde;
+extern crate serde_json;
+
+pub mod song;
+pub mod synth;
+pub mod player;
+pub mod mixer;
+pub mod remote;
+pub mod scope;
+pub mod history;
+pub mod record;
+pub mod export;
+pub mod midi;
+
+#[cfg(feature = "audio")]
+pub mod audio;
+
+#[cfg(feature = "gui")]
+pub mod window;
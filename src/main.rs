@@ -1,44 +1,48 @@
-extern crate serde;
-extern crate serde_json;
-extern crate cpal;
+mod cli;
 
-pub mod song;
-pub mod synth;
-pub mod player;
-pub mod window;
+use cancrizans::{song, synth::*};
 
-use std::sync::{Arc, Mutex, mpsc};
-use std::thread;
-
-use cpal::traits::{HostTrait, DeviceTrait, StreamTrait};
-
-use synth::*;
-use player::*;
-use window::*;
+#[cfg(feature = "gui")]
+use cancrizans::{audio, player::Player, window::Window};
 
 pub fn main() -> anyhow::Result<()> {
+    match cli::Command::parse(std::env::args().skip(1))? {
+        cli::Command::Gui { file } => run_gui(file),
+        cli::Command::Render { input, output, settings } =>
+            cli::render(&input, &output, settings, default_instruments()),
+        cli::Command::Import { input, output, mapping } => cli::import(&input, &output, mapping),
+        cli::Command::Export { input, output } => cli::export(&input, &output),
+        cli::Command::Info { files } => cli::info(&files),
+        cli::Command::Validate { files } => cli::validate(&files),
+        cli::Command::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
+        },
+    }
+}
+
+#[cfg(feature = "gui")]
+fn run_gui(file: Option<String>) -> anyhow::Result<()> {
     let sdl = sdl2::init()
         .expect("could not initialise SDL2");
     
     let video = sdl.video()
         .expect("video could not be initialised");
     
-    let (mut player, note_channel) = Player::new();
+    let (mut player, _) = Player::new();
+    player.instruments = default_instruments();
     
-    player.instruments.push(make_organ());
-    player.instruments.push(make_organ());
-    player.instruments.push(make_organ());
-    player.instruments.push(make_organ());
-    
-    let player_mutex = Arc::new(Mutex::new(player));
+    let remote = player.remote();
+    let tx = audio::start(player)?;
+
+    let mut win = Window::new(video, remote).expect("could not create window");
     
-    let host = cpal::default_host();
-    let device = host.default_output_device()
-        .expect("No audio output device found");
+    if let Some(path) = file {
+        if let Err(err) = win.open(path) {
+            eprintln!("{}", err);
+        }
+    }
     
-    let tx = start_audio(device, player_mutex.clone())?;
-
-    let mut win = Window::new(video, player_mutex.clone(), note_channel).expect("could not create window");
     win.start(sdl).expect("could not start window");
     
     tx.send(())?;
@@ -46,6 +50,16 @@ pub fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[cfg(not(feature = "gui"))]
+fn run_gui(_file: Option<String>) -> anyhow::Result<()> {
+    anyhow::bail!("cancrizans was built without the \"gui\" feature, so the editor isn't available.\n\n{}", cli::USAGE)
+}
+
+/// the instruments which are used for songs that don't specify their own.
+fn default_instruments() -> Vec<Instrument> {
+    (0..song::NUM_PARTS).map(|_| make_organ()).collect()
+}
+
 fn make_organ() -> Instrument {
     let mut instr = Instrument::new(8);
     
@@ -69,59 +83,3 @@ fn make_organ() -> Instrument {
     
     instr
 }
-
-fn start_audio(device: cpal::Device, player: Arc<Mutex<Player>>)
-  -> anyhow::Result<mpsc::Sender<()>> {
-    let config = device.default_output_config()?;
-    println!("Using {}", device.name()?);
-    
-    let (tx, rx) = mpsc::channel();
-    
-    thread::spawn(move || {
-        let stream = match config.sample_format() {
-            cpal::SampleFormat::F32 => make_stream::<f32>(&device, &config.into(), player),
-            cpal::SampleFormat::I16 => make_stream::<i16>(&device, &config.into(), player),
-            cpal::SampleFormat::U16 => make_stream::<u16>(&device, &config.into(), player),
-        }.expect("could not make stream");
-        
-        stream.play().expect("could not play stream");
-        rx.recv().expect("failed to receive stop message");
-    });
-    
-    Ok(tx)
-}
-
-fn make_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, player: Arc<Mutex<Player>>)
-  -> anyhow::Result<cpal::Stream>
-where T : cpal::Sample {
-    let sample_rate = config.sample_rate.0 as f32;
-    let dt = 1.0 / sample_rate as f64;
-    let channels = config.channels as usize;
-        
-    println!("Sample rate: {}Hz", sample_rate);
-        
-    let err_fn = |err| eprintln!("an error occurred: {}", err);
-    
-    let stream = device.build_output_stream(
-        config,
-        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            write_data(data, channels, dt, player.clone());
-        },
-        err_fn)?;
-    
-    Ok(stream)
-}
-
-fn write_data<T>(output: &mut [T], channels: usize, dt: f64, player: Arc<Mutex<Player>>)
-where
-    T: cpal::Sample,
-{
-    let mut p = player.lock().unwrap();
-    
-    for frame in output.chunks_mut(channels) {
-        let value: T = cpal::Sample::from::<f32>(&p.sample(dt));
-        for sample in frame.iter_mut() {
-            *sample = value;
-        }
-    }
-}
\ No newline at end of file
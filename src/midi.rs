@@ -0,0 +1,556 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use midly::num::{u4, u7, u15, u24, u28};
+
+use crate::song::{self, Meter, MeterChange, Note, Song, TempoChange, Time, BEAT_DIVISIONS, NUM_PARTS};
+
+/// the MIDI key number of C0, which is pitch 0 in a song.
+pub const MIDI_C0: u32 = 12;
+
+/// how the notes in a MIDI file are split into the parts of a song.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PartMapping {
+    /// each track which contains notes becomes a part.
+    Tracks,
+    
+    /// each MIDI channel which contains notes becomes a part.
+    Channels,
+    
+    /// split by channel for type 0 (single-track) files, and by track otherwise.
+    Auto,
+}
+
+/// a summary of what was lost or changed while importing a MIDI file.
+#[derive(Default)]
+pub struct ImportReport {
+    /// the number of notes which were imported.
+    pub imported: usize,
+    
+    /// the number of notes whose start or length didn't land exactly on a
+    /// division, and so had to be rounded.
+    pub quantised: usize,
+    
+    /// the number of notes which couldn't be imported at all.
+    pub dropped: usize,
+    
+    /// a description of each thing which went wrong.
+    pub messages: Vec<String>,
+}
+
+/// a note which has started playing in the MIDI file, but hasn't yet stopped.
+struct OpenNote {
+    start: u64,
+    velocity: u8,
+}
+
+/// reads a MIDI file from disk and converts it to a song.
+pub fn import<P: AsRef<Path>>(path: P, mapping: PartMapping) -> anyhow::Result<(Song, ImportReport)> {
+    let data = std::fs::read(path)?;
+    import_bytes(&data, mapping)
+}
+
+/// converts the contents of a standard MIDI file (type 0 or 1) to a song.
+/// notes, tempo changes and time signature changes are rounded to the
+/// nearest division.
+pub fn import_bytes(data: &[u8], mapping: PartMapping) -> anyhow::Result<(Song, ImportReport)> {
+    let smf = Smf::parse(data).map_err(|err| anyhow!("invalid MIDI file: {}", err))?;
+    
+    let ticks_per_beat = match smf.header.timing {
+        Timing::Metrical(tpb) if tpb.as_int() > 0 => tpb.as_int() as u64,
+        Timing::Metrical(_) => bail!("invalid MIDI file: zero ticks per beat"),
+        Timing::Timecode(..) => bail!("MIDI files with SMPTE timecode timing aren't supported"),
+    };
+    
+    let by_channel = match (mapping, smf.header.format) {
+        (_, Format::Sequential) => bail!("type 2 (sequential) MIDI files aren't supported"),
+        (PartMapping::Auto, Format::SingleTrack) => true,
+        (PartMapping::Auto, Format::Parallel) => false,
+        (PartMapping::Channels, _) => true,
+        (PartMapping::Tracks, _) => false,
+    };
+    
+    let mut report = ImportReport::default();
+    let mut tempos = Vec::new();
+    let mut time_sigs = Vec::new();
+    
+    // notes are collected by source (a track or a channel), and only the
+    // sources which actually contain notes are made into parts.
+    let mut sources: BTreeMap<usize, Vec<(u64, u64, u8, u8)>> = BTreeMap::new();
+    
+    for (track_index, track) in smf.tracks.iter().enumerate() {
+        let mut tick: u64 = 0;
+        let mut open: HashMap<(u8, u8), VecDeque<OpenNote>> = HashMap::new();
+        
+        for event in track {
+            tick += event.delta.as_int() as u64;
+            
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(us_per_beat)) if us_per_beat.as_int() > 0 => {
+                    tempos.push((tick, 60_000_000.0 / us_per_beat.as_int() as f64));
+                },
+                TrackEventKind::Meta(MetaMessage::TimeSignature(num, denom, _, _)) => {
+                    time_sigs.push((tick, num, denom));
+                },
+                TrackEventKind::Midi { channel, message } => {
+                    let channel = channel.as_int();
+                    
+                    let (key, vel, on) = match message {
+                        MidiMessage::NoteOn { key, vel } => (key.as_int(), vel.as_int(), vel.as_int() > 0),
+                        MidiMessage::NoteOff { key, vel } => (key.as_int(), vel.as_int(), false),
+                        _ => continue,
+                    };
+                    
+                    let queue = open.entry((channel, key)).or_default();
+                    
+                    if on {
+                        queue.push_back(OpenNote { start: tick, velocity: vel });
+                    } else if let Some(note) = queue.pop_front() {
+                        let source = if by_channel { channel as usize } else { track_index };
+                        sources.entry(source).or_default().push((note.start, tick, key, note.velocity));
+                    }
+                },
+                _ => {},
+            }
+        }
+        
+        let unterminated: usize = open.values().map(|q| q.len()).sum();
+        if unterminated > 0 {
+            report.dropped += unterminated;
+            report.messages.push(format!("track {}: {} notes were never released", track_index + 1, unterminated));
+        }
+    }
+    
+    // gets the nearest division to a tick, and whether it had to be rounded.
+    let to_divs = |ticks: u64| -> anyhow::Result<(u32, bool)> {
+        let scaled = ticks.saturating_mul(BEAT_DIVISIONS as u64);
+        let divs = u32::try_from((scaled + ticks_per_beat / 2) / ticks_per_beat)
+            .map_err(|_| anyhow!("invalid MIDI file: tick {} is too late to fit in a song", ticks))?;
+        
+        Ok((divs, !scaled.is_multiple_of(ticks_per_beat)))
+    };
+    
+    // tempo and time signature events can be spread over several tracks, so
+    // they're only put in order once they've all been found. the sorts are
+    // stable, so the last of several events at the same tick wins, as it
+    // would when playing.
+    tempos.sort_by_key(|&(tick, _)| tick);
+    time_sigs.sort_by_key(|&(tick, _, _)| tick);
+    
+    let mut bpm = 120;
+    let mut tempo_changes: Vec<TempoChange> = Vec::new();
+    
+    for (tick, tempo) in tempos {
+        let time = Time::from_divs(to_divs(tick)?.0);
+        
+        // a song's starting tempo is a whole number of beats per minute, so a
+        // starting tempo between two is kept exactly by a change right at
+        // the start. tempos are only stored to the microsecond, so one which
+        // rounds to the same number of microseconds as the whole bpm is left
+        // as it is.
+        if time.as_divs() == 0 {
+            bpm = (tempo.round() as u32).max(1);
+            tempo_changes.clear();
+            
+            if (60_000_000.0 / bpm as f64).round() != (60_000_000.0 / tempo).round() {
+                tempo_changes.push(TempoChange { time, bpm: tempo, ramp: false });
+            }
+        } else if tempo_changes.last().is_some_and(|c| c.time.as_divs() == time.as_divs()) {
+            tempo_changes.last_mut().unwrap().bpm = tempo;
+        } else {
+            tempo_changes.push(TempoChange { time, bpm: tempo, ramp: false });
+        }
+    }
+    
+    let mut meter = Meter::new(4, 4);
+    let mut meter_changes: Vec<MeterChange> = Vec::new();
+    
+    for (tick, num, denom) in time_sigs {
+        // the denominator is stored as a power of two.
+        if num == 0 || denom > 6 {
+            report.messages.push(format!("an unsupported time signature of {}/2^{} was ignored", num, denom));
+            continue;
+        }
+        
+        let time = Time::from_divs(to_divs(tick)?.0);
+        let new_meter = Meter::new(num as u32, 1 << denom);
+        
+        if time.as_divs() == 0 {
+            meter = new_meter;
+        } else if meter_changes.last().is_some_and(|c| c.time.as_divs() == time.as_divs()) {
+            meter_changes.last_mut().unwrap().meter = new_meter;
+        } else {
+            meter_changes.push(MeterChange { time, meter: new_meter });
+        }
+    }
+    
+    let mut song = Song::new(NUM_PARTS, bpm, meter);
+    song.tempo_changes = tempo_changes;
+    song.meter_changes = meter_changes;
+    
+    for (part, (source, notes)) in sources.into_iter().enumerate() {
+        let source_name = if by_channel {
+            format!("channel {}", source + 1)
+        } else {
+            format!("track {}", source + 1)
+        };
+        
+        if part >= NUM_PARTS {
+            report.dropped += notes.len();
+            report.messages.push(format!("{}: {} notes were dropped, since only {} parts are supported",
+                source_name, notes.len(), NUM_PARTS));
+            continue;
+        }
+        
+        let mut too_low = 0;
+        
+        for (start, end, key, velocity) in notes {
+            if (key as u32) < MIDI_C0 {
+                too_low += 1;
+                continue;
+            }
+            
+            let (start_divs, start_moved) = to_divs(start)?;
+            let (end_divs, end_moved) = to_divs(end)?;
+            let mut duration = end_divs.saturating_sub(start_divs);
+            let mut quantised = start_moved || end_moved;
+            
+            if duration == 0 {
+                duration = 1;
+                quantised = true;
+            }
+            
+            if quantised {
+                report.quantised += 1;
+            }
+            
+            song.add_note(part, Note {
+                pitch: key as u32 - MIDI_C0,
+                start: Time::from_divs(start_divs),
+                duration,
+                velocity: velocity as f32 / 127.0,
+            });
+            
+            report.imported += 1;
+        }
+        
+        if too_low > 0 {
+            report.dropped += too_low;
+            report.messages.push(format!("{}: {} notes were dropped, since they are below {}0",
+                source_name, too_low, song::NOTE_NAMES[0]));
+        }
+        
+        song.parts[part].sort_by_key(|n| n.start.as_divs());
+    }
+    
+    Ok((song, report))
+}
+
+/// writes a song to disk as a type 1 MIDI file. see `export_to`.
+pub fn export<P: AsRef<Path>>(song: &Song, path: P) -> anyhow::Result<()> {
+    let file = std::fs::File::create(path)?;
+    export_to(song, file)
+}
+
+/// writes a song as a type 1 MIDI file, with one quarter note per MIDI beat.
+/// the first track holds the tempo and time signature changes, and each part
+/// gets a track of its own on its own channel. MIDI has no gradual tempo
+/// changes, so ramps are written as a step at every beat. notes which are
+/// too high to be represented in MIDI are left out.
+pub fn export_to<W: Write>(song: &Song, writer: W) -> anyhow::Result<()> {
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(u15::new(BEAT_DIVISIONS as u16))));
+    let names: Vec<String> = (0..song.parts.len()).map(|i| format!("part {}", i + 1)).collect();
+    
+    let mut meters = vec![(0, song.meter)];
+    meters.extend(song.meter_changes.iter().map(|c| (c.time.as_divs(), c.meter)));
+    
+    let mut meta: Vec<(u32, MetaMessage)> = meters
+        .into_iter()
+        .map(|(t, meter)| {
+            let num = meter.numerator.clamp(1, 255) as u8;
+            let denom = meter.denominator.max(1).trailing_zeros().min(255) as u8;
+            let clocks = (96 / meter.denominator.max(1)).clamp(1, 255) as u8;
+            
+            (t, MetaMessage::TimeSignature(num, denom, clocks, 8))
+        })
+        .collect();
+    
+    for (t, bpm) in tempo_steps(song) {
+        let us_per_beat = (60_000_000.0 / bpm).round().clamp(1.0, 0xFFFFFF as f64) as u32;
+        meta.push((t, MetaMessage::Tempo(u24::new(us_per_beat))));
+    }
+    
+    meta.sort_by_key(|&(t, _)| t);
+    
+    let mut tempo_track = Vec::new();
+    let mut last = 0;
+    
+    for (t, message) in meta {
+        tempo_track.push(TrackEvent {
+            delta: u28::new(t - last),
+            kind: TrackEventKind::Meta(message),
+        });
+        
+        last = t;
+    }
+    
+    tempo_track.push(TrackEvent {
+        delta: u28::new(0),
+        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+    });
+    
+    smf.tracks.push(tempo_track);
+    
+    for (i, part) in song.parts.iter().enumerate() {
+        let channel = u4::new((i % 16) as u8);
+        
+        // each note becomes a (time, is_on, key, velocity) pair. sorting them
+        // puts note-offs before note-ons at the same time, so that repeated
+        // notes of the same pitch don't cut each other off.
+        let mut events: Vec<(u32, bool, u8, u8)> = part
+            .iter()
+            .filter(|n| n.pitch + MIDI_C0 <= 127)
+            .flat_map(|n| {
+                let key = (n.pitch + MIDI_C0) as u8;
+                let vel = ((n.velocity * 127.0).round() as i32).clamp(1, 127) as u8;
+                let start = n.start.as_divs();
+                
+                [(start, true, key, vel), (start + n.duration, false, key, 0)]
+            })
+            .collect();
+        
+        events.sort_by_key(|&(t, on, _, _)| (t, on));
+        
+        let mut track = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::TrackName(names[i].as_bytes())),
+        }];
+        
+        let mut last = 0;
+        
+        for (t, on, key, vel) in events {
+            let message = if on {
+                MidiMessage::NoteOn { key: u7::new(key), vel: u7::new(vel) }
+            } else {
+                MidiMessage::NoteOff { key: u7::new(key), vel: u7::new(vel) }
+            };
+            
+            track.push(TrackEvent {
+                delta: u28::new(t - last),
+                kind: TrackEventKind::Midi { channel, message },
+            });
+            
+            last = t;
+        }
+        
+        track.push(TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+        });
+        
+        smf.tracks.push(track);
+    }
+    
+    smf.write_std(writer)?;
+    
+    Ok(())
+}
+
+/// lists the tempo of a song as (division, bpm) steps, starting at zero.
+/// each ramp is split into beat-long steps, each of which lasts as long as
+/// that beat does in the song, so that the steps stay in time with the ramp.
+fn tempo_steps(song: &Song) -> Vec<(u32, f64)> {
+    let tempo = song.tempo_map();
+    let mut changes = song.tempo_changes.clone();
+    changes.sort_by_key(|c| c.time.as_divs());
+    
+    let mut steps = vec![(0, song.bpm.max(1) as f64)];
+    let mut prev = 0;
+    
+    for change in changes {
+        let end = change.time.as_divs();
+        
+        if change.ramp {
+            for t in (prev..end).step_by(BEAT_DIVISIONS as usize) {
+                let step_end = (t + BEAT_DIVISIONS).min(end);
+                let beats = (step_end - t) as f64 / BEAT_DIVISIONS as f64;
+                let seconds = tempo.seconds_at(Time::from_divs(step_end).as_beats())
+                    - tempo.seconds_at(Time::from_divs(t).as_beats());
+                
+                steps.push((t, beats / seconds * 60.0));
+            }
+        }
+        
+        steps.push((end, change.bpm));
+        prev = end;
+    }
+    
+    // later steps at the same time replace earlier ones.
+    steps.dedup_by(|later, earlier| {
+        if later.0 == earlier.0 {
+            earlier.1 = later.1;
+            true
+        } else {
+            false
+        }
+    });
+    
+    steps
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "imported {} notes ({} quantised, {} dropped)", self.imported, self.quantised, self.dropped)?;
+        
+        for message in &self.messages {
+            write!(f, "\n  {}", message)?;
+        }
+        
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// builds a type 0 MIDI file out of (delta, event) pairs.
+    fn single_track(ticks_per_beat: u16, events: &[(u32, TrackEventKind<'static>)]) -> Vec<u8> {
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(ticks_per_beat))));
+        
+        smf.tracks.push(events
+            .iter()
+            .map(|&(delta, kind)| TrackEvent { delta: u28::new(delta), kind })
+            .collect());
+        
+        let mut buf = Vec::new();
+        smf.write_std(&mut buf).unwrap();
+        buf
+    }
+    
+    fn note(channel: u8, key: u8, vel: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi {
+            channel: u4::new(channel),
+            message: MidiMessage::NoteOn { key: u7::new(key), vel: u7::new(vel) },
+        }
+    }
+    
+    #[test]
+    fn imports_single_track_files_by_channel() {
+        let data = single_track(480, &[
+            (0, TrackEventKind::Meta(MetaMessage::Tempo(u24::new(500_000)))),
+            (0, note(0, 60, 127)),
+            (240, note(3, 64, 64)),
+            (3, note(3, 64, 0)),
+            (237, note(0, 60, 0)),
+            (0, note(0, 5, 100)),
+            (10, note(0, 5, 0)),
+            (0, note(0, 12, 100)),
+            (10, note(0, 12, 0)),
+            (0, note(3, 70, 100)),
+            (0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]);
+        
+        let (song, report) = import_bytes(&data, PartMapping::Auto).unwrap();
+        
+        assert_eq!(song.bpm, 120);
+        assert_eq!(song.parts[0].len(), 2);
+        assert_eq!(song.parts[0][0].pitch, 48);
+        assert_eq!(song.parts[0][1].pitch, 0);
+        assert_eq!(song.parts[0][0].duration, BEAT_DIVISIONS);
+        
+        // 243 ticks is 48.6 divisions, and the note is too short to round
+        // down to nothing.
+        assert_eq!(song.parts[1].len(), 1);
+        assert_eq!(song.parts[1][0].start.as_divs(), 48);
+        assert_eq!(song.parts[1][0].duration, 1);
+        assert!((song.parts[1][0].velocity - 64.0 / 127.0).abs() < 1e-6);
+        
+        // the note below C0 and the one which is never released are dropped.
+        assert_eq!((report.imported, report.quantised, report.dropped), (3, 1, 2));
+        assert_eq!(report.messages.len(), 2);
+    }
+    
+    #[test]
+    fn keeps_starting_tempos_between_whole_bpms() {
+        let data = single_track(480, &[
+            (0, TrackEventKind::Meta(MetaMessage::Tempo(u24::new(600_000)))),
+            (0, TrackEventKind::Meta(MetaMessage::Tempo(u24::new(480_000)))),
+            (0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]);
+        
+        // 480,000 microseconds a beat is 125bpm, which needs no change.
+        let (song, _) = import_bytes(&data, PartMapping::Auto).unwrap();
+        assert_eq!(song.bpm, 125);
+        assert!(song.tempo_changes.is_empty());
+        
+        let data = single_track(480, &[
+            (0, TrackEventKind::Meta(MetaMessage::Tempo(u24::new(498_000)))),
+            (0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]);
+        
+        let (song, _) = import_bytes(&data, PartMapping::Auto).unwrap();
+        assert_eq!(song.bpm, 120);
+        assert_eq!(song.tempo_changes.len(), 1);
+        assert_eq!(song.tempo_changes[0].time.as_divs(), 0);
+        assert!((song.tempo_changes[0].bpm - 60e6 / 498_000.0).abs() < 1e-9);
+        assert!((song.tempo_map().seconds_at(1.0) - 0.498).abs() < 1e-9);
+    }
+    
+    #[test]
+    fn rejects_files_too_long_for_a_song() {
+        let data = single_track(1, &[
+            (u28::max_value().as_int(), TrackEventKind::Meta(MetaMessage::Tempo(u24::new(500_000)))),
+            (0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]);
+        
+        assert!(import_bytes(&data, PartMapping::Auto).is_err());
+    }
+    
+    #[test]
+    fn round_trips_through_midi() {
+        let mut song = Song::new(NUM_PARTS, 90, Meter::new(6, 8));
+        song.add_note(0, Note::new(48, 0, 0, 96, 1.0));
+        song.add_note(0, Note::new(48, 1, 0, 48, 0.5));
+        song.add_note(0, Note::new(55, 1, 24, 24, 0.25));
+        song.add_note(2, Note::new(36, 4, 12, 200, 0.75));
+        song.tempo_changes.push(TempoChange { time: Time::new(2, 48), bpm: 150.0, ramp: false });
+        song.meter_changes.push(MeterChange { time: Time::new(3, 0), meter: Meter::new(7, 16) });
+        
+        let mut buf = Vec::new();
+        export_to(&song, &mut buf).unwrap();
+        
+        let (imported, report) = import_bytes(&buf, PartMapping::Tracks).unwrap();
+        
+        assert_eq!(imported.bpm, 90);
+        assert!(imported.meter == Meter::new(6, 8));
+        assert_eq!(imported.meter_changes.len(), 1);
+        assert_eq!(imported.meter_changes[0].time.as_divs(), Time::new(3, 0).as_divs());
+        assert!(imported.meter_changes[0].meter == Meter::new(7, 16));
+        assert_eq!(imported.tempo_changes.len(), 1);
+        assert_eq!(imported.tempo_changes[0].time.as_divs(), Time::new(2, 48).as_divs());
+        assert!((imported.tempo_changes[0].bpm - 150.0).abs() < 0.01);
+        assert_eq!(report.imported, 4);
+        assert_eq!(report.quantised, 0);
+        assert_eq!(report.dropped, 0);
+        
+        // empty parts don't get parts of their own when importing.
+        let original = song.parts.iter().filter(|p| !p.is_empty());
+        
+        for (a, b) in original.zip(imported.parts.iter()) {
+            assert_eq!(a.len(), b.len());
+            
+            for (x, y) in a.iter().zip(b.iter()) {
+                assert_eq!(x.pitch, y.pitch);
+                assert_eq!(x.start.as_divs(), y.start.as_divs());
+                assert_eq!(x.duration, y.duration);
+                assert!((x.velocity - y.velocity).abs() < 0.01);
+            }
+        }
+    }
+}
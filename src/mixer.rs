@@ -0,0 +1,122 @@
+use serde::{Serialize, Deserialize};
+
+use crate::synth::Stereo;
+
+/// the mixer's settings for a single instrument.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Channel {
+    /// the volume of the instrument. 1.0 is normal.
+    pub gain: f32,
+    
+    /// where the instrument sits in the stereo field, from -1.0 (hard left)
+    /// to 1.0 (hard right).
+    pub pan: f32,
+    
+    /// whether the instrument is silenced.
+    #[serde(default)]
+    pub mute: bool,
+    
+    /// whether the instrument is soloed. while any instrument is soloed, only
+    /// soloed instruments can be heard.
+    #[serde(default)]
+    pub solo: bool,
+}
+
+impl Default for Channel {
+    fn default() -> Channel {
+        Channel {
+            gain: 1.0,
+            pan: 0.0,
+            mute: false,
+            solo: false,
+        }
+    }
+}
+
+/// balances the volumes of a song's instruments against each other. the
+/// mixer is applied after each instrument's effects, so muting an instrument
+/// doesn't stop it from playing, and it comes back in exactly where it would
+/// have been.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Mixer {
+    /// the settings for each instrument, in order. instruments without any
+    /// settings are played as they are.
+    pub channels: Vec<Channel>,
+}
+
+impl Mixer {
+    /// gets the settings for an instrument.
+    pub fn channel(&self, i: usize) -> Channel {
+        self.channels.get(i).copied().unwrap_or_default()
+    }
+    
+    /// gets the settings for an instrument to change them, adding default
+    /// settings for any instruments which don't have them yet.
+    pub fn channel_mut(&mut self, i: usize) -> &mut Channel {
+        if i >= self.channels.len() {
+            self.channels.resize(i + 1, Channel::default());
+        }
+        
+        &mut self.channels[i]
+    }
+    
+    /// whether any instrument is soloed.
+    pub fn is_soloing(&self) -> bool {
+        self.channels.iter().any(|c| c.solo)
+    }
+    
+    /// whether an instrument can be heard, taking mutes and solos into account.
+    pub fn is_audible(&self, i: usize) -> bool {
+        let channel = self.channel(i);
+        !channel.mute && (channel.solo || !self.is_soloing())
+    }
+    
+    /// applies an instrument's gain and pan to a sample of its output. in
+    /// the centre, both sides are left as they are, and panning turns the
+    /// other side down.
+    pub fn apply(&self, i: usize, sample: Stereo) -> Stereo {
+        if !self.is_audible(i) {
+            return [0.0; 2];
+        }
+        
+        let channel = self.channel(i);
+        let pan = channel.pan.clamp(-1.0, 1.0);
+        
+        [
+            sample[0] * channel.gain * (1.0 - pan).min(1.0),
+            sample[1] * channel.gain * (1.0 + pan).min(1.0),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn solos_and_mutes() {
+        let mut mixer = Mixer::default();
+        assert!((0..4).all(|i| mixer.is_audible(i)));
+        
+        mixer.channel_mut(1).mute = true;
+        assert_eq!((0..4).map(|i| mixer.is_audible(i)).collect::<Vec<_>>(), vec![true, false, true, true]);
+        
+        // a muted instrument stays quiet even when soloed.
+        mixer.channel_mut(1).solo = true;
+        mixer.channel_mut(2).solo = true;
+        assert_eq!((0..4).map(|i| mixer.is_audible(i)).collect::<Vec<_>>(), vec![false, false, true, false]);
+        assert_eq!(mixer.apply(0, [1.0, 1.0]), [0.0, 0.0]);
+    }
+    
+    #[test]
+    fn applies_gain_and_pan() {
+        let mut mixer = Mixer::default();
+        assert_eq!(mixer.apply(3, [0.5, -0.5]), [0.5, -0.5]);
+        
+        *mixer.channel_mut(0) = Channel { gain: 0.5, pan: -0.5, mute: false, solo: false };
+        assert_eq!(mixer.apply(0, [1.0, 1.0]), [0.5, 0.25]);
+        
+        mixer.channel_mut(0).pan = 1.0;
+        assert_eq!(mixer.apply(0, [1.0, 1.0]), [0.0, 0.5]);
+    }
+}
@@ -1,16 +1,22 @@
 use crate::synth;
 use crate::song;
+use crate::mixer::Mixer;
+use crate::remote::{Garbage, Message, Monitor, Remote, SEQUENCE_HEADROOM};
 
-use std::sync::mpsc;
+use std::f64::consts::TAU;
+use std::sync::{Arc, mpsc};
 
-const QUANTIZE: u32 = 256;
+/// the pitch of the clicks played during a count-in, in hertz.
+const CLICK_FREQ: f64 = 1760.0;
 
 /// a player collects together a number of instruments and plays
 /// them together, allocating notes to them and handling control
-/// signals.
+/// signals. a player running on another thread can be controlled
+/// through a `Remote`.
 pub struct Player {
-    /// how many beats (quarter notes) are played per second?
-    pub bps: f64,
+    /// the tempo map of the song being played, used to work out when each
+    /// beat (quarter note) falls.
+    pub tempo: song::TempoMap,
     
     /// the volume of the output. 1.0 is normal.
     pub volume: f32,
@@ -20,19 +26,50 @@ pub struct Player {
     /// speakers.
     pub mute: bool,
     
-    /// whether the player is paused. a paused instrument will not
-    /// advance its playhead, and will not run its synthesisers.
+    /// whether the player is paused. a paused player will not advance its
+    /// playhead, and only runs its synthesisers while notes are being played
+    /// live.
     pub paused: bool,
     
     /// the set of instruments which the player owns.
     pub instruments: Vec<synth::Instrument>,
     
+    /// the gain, pan, mute and solo of each instrument.
+    pub mixer: Mixer,
+    
     /// the location, in seconds, of the player's playhead.
     pub playhead: f64,
     
+    /// the start and end of a region of the song to play over and over. when
+    /// the playhead reaches the end, it jumps straight back to the start.
+    pub loop_region: Option<(song::Time, song::Time)>,
+    
     note_recv: mpsc::Receiver<(usize, song::Note)>,
-    next_note: Option<(usize, song::Note, f64)>,
-    quantize_count: u32,
+    
+    message_recv: mpsc::Receiver<Message>,
+    message_send: mpsc::Sender<Message>,
+    
+    /// where things which the player has finished with are sent, to be
+    /// dropped by a remote.
+    garbage: mpsc::SyncSender<Garbage>,
+    
+    /// the playhead and scopes, which are shared with any remotes.
+    monitor: Arc<Monitor>,
+    
+    /// every note sent to the player since it was last flushed, in order of
+    /// their start times, so that they can be played again when looping.
+    sequence: Vec<(usize, song::Note)>,
+    
+    /// the index in `sequence` of the next note to be handed to an instrument.
+    cursor: usize,
+    
+    /// how long the instruments have been running for while paused. live
+    /// notes are timed as if the playhead had carried on by this much.
+    idle: f64,
+    
+    /// the count-in being played before the player starts, if there is one:
+    /// the length of each beat, and how many seconds of it are left.
+    count_in: Option<(f64, f64)>,
 }
 
 impl Player {
@@ -40,56 +77,253 @@ impl Player {
     /// for sending notes. by default, the player is paused.
     pub fn new() -> (Player, mpsc::Sender<(usize, song::Note)>) {
         let (tx_note, rx_note) = mpsc::channel();
+        let (tx_message, rx_message) = mpsc::channel();
+        let (monitor, garbage) = Monitor::new();
         
         (Player {
-            bps: 1.0,
+            tempo: song::TempoMap::constant(60.0),
             volume: 1.0,
             mute: false,
             paused: true,
             instruments: Vec::new(),
+            mixer: Mixer::default(),
             
             playhead: 0.0,
+            loop_region: None,
             note_recv: rx_note,
-            next_note: None,
-            quantize_count: QUANTIZE,
+            message_recv: rx_message,
+            message_send: tx_message,
+            garbage,
+            monitor: Arc::new(monitor),
+            sequence: Vec::with_capacity(SEQUENCE_HEADROOM),
+            cursor: 0,
+            idle: 0.0,
+            count_in: None,
         }, tx_note)
     }
     
+    /// makes a remote for controlling the player from another thread, which
+    /// starts off with the player's current settings.
+    pub fn remote(&self) -> Remote {
+        Remote::new(self.message_send.clone(), self.monitor.clone(), self)
+    }
+    
+    /// gets how many notes are in the player's sequence.
+    pub(crate) fn sequence_len(&self) -> usize {
+        self.sequence.len()
+    }
+    
+    /// gets how many notes the sequence has room for without allocating.
+    pub(crate) fn sequence_capacity(&self) -> usize {
+        self.sequence.capacity()
+    }
+    
+    /// hands something the player has finished with to a remote to drop. if
+    /// nobody has collected the garbage for a while, it's dropped here.
+    fn discard(&self, garbage: Garbage) {
+        let _ = self.garbage.try_send(garbage);
+    }
+    
     /// advances the player's instruments and playhead, and returns
-    /// the next sample. the playhead is advanced by `dt` seconds.
-    pub fn sample(&mut self, dt: f64) -> f32 {
-        if self.quantize_count >= QUANTIZE {
-            self.quantum();
-            self.quantize_count = 0;
-        } else {
-            self.quantize_count += 1;
-        }
+    /// the next stereo sample, with every instrument mixed together. the
+    /// playhead is advanced by `dt` seconds.
+    pub fn sample(&mut self, dt: f64) -> synth::Stereo {
+        self.receive_messages();
+        self.receive_notes();
         
-        let mut s = 0.0;
+        let mut s = [0.0; 2];
         
         if !self.paused {
-            for instr in &mut self.instruments {
-                s += instr.next_output(self.playhead, dt);
-            }
-    
+            // only wrap when the end is crossed, so that it's still possible
+            // to play past a loop by seeking beyond it.
+            let loop_seconds = self.loop_seconds()
+                .filter(|&(_, end)| synth::onset_frame(end, self.playhead, dt) > 0.0);
+            
+            // instruments render a block at a time, so notes are handed over
+            // a block in advance. a block never runs past the end of the loop,
+            // since the playhead is about to jump back from there.
+            let (block, until) = match loop_seconds {
+                Some((_, end)) => {
+                    let block = (synth::onset_frame(end, self.playhead, dt) as usize).min(synth::HOLD_BUFFER_SIZE);
+                    (block, end.min(self.playhead + block as f64 * dt))
+                },
+                None => (synth::HOLD_BUFFER_SIZE, self.playhead + synth::HOLD_BUFFER_SIZE as f64 * dt),
+            };
+            
+            self.schedule_until(until);
+            s = self.mix(self.playhead, dt, block);
             self.playhead += dt;
+            
+            if let Some((start, end)) = loop_seconds {
+                if synth::onset_frame(end, self.playhead, dt) <= 0.0 {
+                    self.wrap(start, end);
+                }
+            }
+        } else if let Some((beat, left)) = self.count_in {
+            // the playhead waits during a count-in, but live notes can still
+            // be played along with the clicks.
+            let beats_left = left / beat;
+            let click = click((beats_left.ceil() - beats_left) * beat);
+            
+            s = self.mix(self.now(), dt, synth::HOLD_BUFFER_SIZE).map(|c| c + click);
+            self.idle += dt;
+            
+            if left > dt {
+                self.count_in = Some((beat, left - dt));
+            } else {
+                self.set_paused(false);
+            }
+        } else if self.instruments.iter().any(|i| i.is_sounding()) {
+            // notes can still be played live while paused, so the instruments
+            // carry on without the playhead until they go quiet.
+            s = self.mix(self.now(), dt, synth::HOLD_BUFFER_SIZE);
+            self.idle += dt;
         }
         
+        self.monitor.set_playhead(self.playhead);
+        
         if self.mute {
-            0.0
+            [0.0; 2]
         } else {
-            s * self.volume
+            s.map(|c| c * self.volume)
+        }
+    }
+    
+    /// gets the next sample from every instrument at `time`, and mixes them
+    /// together.
+    fn mix(&mut self, time: f64, dt: f64, block: usize) -> synth::Stereo {
+        let mut s = [0.0; 2];
+        let bpm = self.tempo.bpm_at(self.tempo.beats_at(time));
+        
+        for (i, instr) in self.instruments.iter_mut().enumerate() {
+            instr.set_bpm(bpm);
+            let [left, right] = self.mixer.apply(i, instr.next_output(time, dt, block));
+            s[0] += left;
+            s[1] += right;
+            
+            if let Some(scope) = self.monitor.scopes.get(i) {
+                scope.push(synth::downmix([left, right]));
+            }
+        }
+        
+        s
+    }
+    
+    /// gets the time which live notes are played at, which carries on even
+    /// while the player is paused.
+    fn now(&self) -> f64 {
+        self.playhead + self.idle
+    }
+    
+    /// starts playing a note live on an instrument, until `note_off` is
+    /// called with the same pitch.
+    pub fn note_on(&mut self, instrument: usize, pitch: u32, velocity: f32) {
+        let now = self.now();
+        
+        if let Some(instr) = self.instruments.get_mut(instrument) {
+            instr.note_on(pitch, velocity, now);
+        }
+    }
+    
+    /// lets go of a note which is being played live.
+    pub fn note_off(&mut self, instrument: usize, pitch: u32) {
+        let now = self.now();
+        
+        if let Some(instr) = self.instruments.get_mut(instrument) {
+            instr.note_off(pitch, now);
         }
     }
     
+    /// pauses or unpauses the player. pausing stops any notes from the song,
+    /// which start again part-way through when the player is unpaused.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.count_in = None;
+        
+        if paused && !self.paused {
+            self.seek(self.playhead);
+        } else if !paused && self.paused {
+            // live notes which are still held carry on from where they were.
+            for instr in self.instruments.iter_mut() {
+                instr.shift(-self.idle);
+            }
+        }
+        
+        self.idle = 0.0;
+        self.paused = paused;
+    }
+    
+    /// starts the player once it has clicked out `beats` beats at the tempo
+    /// of the playhead. nothing happens if it's already playing.
+    pub fn count_in(&mut self, beats: f64) {
+        if self.paused {
+            let beat = 60.0 / self.tempo.bpm_at(self.tempo.beats_at(self.playhead));
+            self.count_in = Some((beat, beats * beat));
+        }
+    }
+    
+    /// adds a note to the sequence without handing it to its instrument, even
+    /// if it should already have started. this is for notes which have just
+    /// been heard anyway, e.g. ones being recorded as they're played live.
+    pub fn add_note(&mut self, instrument: usize, note: song::Note) {
+        let index = self.sequence.partition_point(|(_, n)| n.start.as_divs() <= note.start.as_divs());
+        self.sequence.insert(index, (instrument, note));
+        
+        if index < self.cursor {
+            self.cursor += 1;
+        }
+    }
+    
+    /// takes a note out of the sequence. if it has already started, it's
+    /// left to finish.
+    pub fn remove_note(&mut self, instrument: usize, note: song::Note) {
+        if let Some(index) = self.sequence.iter().position(|&(i, n)| i == instrument && n == note) {
+            self.sequence.remove(index);
+            
+            if index < self.cursor {
+                self.cursor -= 1;
+            }
+        }
+    }
+    
+    /// replaces the tempo map, keeping the playhead on the same beat. notes
+    /// which are playing carry on as they were, and are let go of on the
+    /// same beat as they would have been before.
+    pub fn set_tempo(&mut self, tempo: song::TempoMap) {
+        let old = std::mem::replace(&mut self.tempo, tempo);
+        let playhead = self.tempo.seconds_at(old.beats_at(self.playhead));
+        let by = playhead - self.playhead;
+        
+        for instr in self.instruments.iter_mut() {
+            // shifting moves the queue along too, so that's taken off first.
+            instr.retime(|t| self.tempo.seconds_at(old.beats_at(t)) - by);
+            instr.shift(by);
+        }
+        
+        self.playhead = playhead;
+        self.discard(Garbage::Tempo(old));
+    }
+    
     /// resets the player to a default state.
     pub fn reset(&mut self) {
-        self.playhead = 0.0;
+        self.seek(0.0);
         self.paused = true;
+        self.idle = 0.0;
+        self.count_in = None;
         self.volume = 1.0;
         self.mute = false;
-        self.bps = 1.0;
+        self.loop_region = None;
         self.flush_notes();
+        
+        // these are emptied in place, so that nothing needs to be freed.
+        self.tempo.make_constant(60.0);
+        self.mixer.channels.clear();
+    }
+    
+    /// replaces the player's instruments with ones built from the given specs.
+    /// if there are fewer specs than instruments, the remaining instruments
+    /// are left as they are.
+    pub fn load_instruments(&mut self, specs: &[synth::InstrumentSpec]) {
+        synth::load_instruments(&mut self.instruments, specs);
     }
     
     /// empties the note input stream, so a new song can be started
@@ -97,38 +331,359 @@ impl Player {
     pub fn flush_notes(&mut self) {
         for _ in self.note_recv.try_iter() {}
         
-        self.next_note = None;
-        self.quantize_count = QUANTIZE;
+        self.sequence.clear();
+        self.cursor = 0;
         
         for instr in self.instruments.iter_mut() {
             instr.flush();
         }
     }
     
-    fn quantum(&mut self) {
-        if let Some((i, next, start)) = self.next_note {
-            if start <= self.playhead {
-                if let Some(instr) = self.instruments.get_mut(i) {
-                    instr.schedule(next, self.bps);
-                }
-                
-                self.next_note = None;
-            }
+    /// moves the playhead to the given time, in seconds. any notes which
+    /// would still be sounding at that time are started part-way through.
+    pub fn seek(&mut self, seconds: f64) {
+        self.playhead = seconds.max(0.0);
+        
+        for instr in self.instruments.iter_mut() {
+            instr.flush();
         }
         
-        if self.next_note.is_none() {
-            for (i, note) in self.note_recv.try_iter() {
-                let start = note.start_time(self.bps);
-                
-                if start > self.playhead {
-                    self.next_note = Some((i, note, start));
-                    break;
+        self.cursor = 0;
+        self.monitor.set_playhead(self.playhead);
+        self.monitor.count_seek();
+    }
+    
+    /// makes a change which was sent from a remote.
+    fn apply(&mut self, message: Message) {
+        match message {
+            Message::SetPaused(paused) => self.set_paused(paused),
+            Message::SetVolume(volume) => self.volume = volume,
+            Message::SetMute(mute) => self.mute = mute,
+            Message::Seek(seconds) => self.seek(seconds),
+            Message::SetTempo(tempo) => self.set_tempo(tempo),
+            Message::SetLoopRegion(region) => self.loop_region = region,
+            Message::SetOperator { instrument, index, operator } => {
+                if let Some(op) = self.instruments.get_mut(instrument).and_then(|i| i.operators.get_mut(index)) {
+                    *op = operator;
+                }
+            },
+            Message::SetPolyphony { instrument, voices, stealing } => {
+                if let Some(instr) = self.instruments.get_mut(instrument) {
+                    instr.set_polyphony(voices);
+                    instr.stealing = stealing;
                 }
-                
-                if let Some(instr) = self.instruments.get_mut(i) {
-                    instr.schedule(note, self.bps.into());
+            },
+            Message::SetMono { instrument, mono } => {
+                if let Some(instr) = self.instruments.get_mut(instrument) {
+                    instr.mono = mono;
                 }
+            },
+            Message::SetLfo { instrument, index, lfo } => {
+                if let Some(l) = self.instruments.get_mut(instrument).and_then(|i| i.lfos.get_mut(index)) {
+                    *l = lfo;
+                }
+            },
+            Message::SetRoute { instrument, index, route } => {
+                if let Some(r) = self.instruments.get_mut(instrument).and_then(|i| i.routes.get_mut(index)) {
+                    *r = route;
+                }
+            },
+            Message::SetControls { instrument, controls } => {
+                if let Some(instr) = self.instruments.get_mut(instrument) {
+                    instr.controls = controls;
+                }
+            },
+            Message::SetInstruments(instruments) => {
+                let old = std::mem::replace(&mut self.instruments, instruments);
+                self.discard(Garbage::Instruments(old));
+            },
+            Message::SetChannel(i, channel) => *self.mixer.channel_mut(i) = channel,
+            Message::SetMixer(mixer) => {
+                let old = std::mem::replace(&mut self.mixer, mixer);
+                self.discard(Garbage::Mixer(old));
+            },
+            Message::Sequence(notes) => {
+                self.flush_notes();
+                let old = std::mem::replace(&mut self.sequence, notes);
+                self.discard(Garbage::Sequence(old));
+            },
+            Message::MakeRoom(mut room) => {
+                room.extend_from_slice(&self.sequence);
+                let old = std::mem::replace(&mut self.sequence, room);
+                self.discard(Garbage::Sequence(old));
+            },
+            Message::AddNote(instrument, note) => self.add_note(instrument, note),
+            Message::RemoveNote(instrument, note) => self.remove_note(instrument, note),
+            Message::CountIn(beats) => self.count_in(beats),
+            Message::NoteOn { instrument, pitch, velocity } => self.note_on(instrument, pitch, velocity),
+            Message::NoteOff { instrument, pitch } => self.note_off(instrument, pitch),
+            Message::FlushNotes => self.flush_notes(),
+            Message::Reset => self.reset(),
+        }
+    }
+    
+    /// makes every change which has been sent from a remote since the last
+    /// sample.
+    fn receive_messages(&mut self) {
+        while let Ok(message) = self.message_recv.try_recv() {
+            self.apply(message);
+        }
+    }
+    
+    /// gets the loop region in seconds, if there is one and it isn't empty.
+    fn loop_seconds(&self) -> Option<(f64, f64)> {
+        self.loop_region
+            .filter(|(start, end)| end.as_divs() > start.as_divs())
+            .map(|(start, end)| (self.tempo.seconds_at(start.as_beats()), self.tempo.seconds_at(end.as_beats())))
+    }
+    
+    /// moves the playhead from the end of the loop back to the start. notes
+    /// which were playing carry on as if nothing happened, and the notes in
+    /// the loop are played again.
+    fn wrap(&mut self, start: f64, end: f64) {
+        self.playhead += start - end;
+        self.monitor.set_playhead(self.playhead);
+        self.monitor.count_wrap();
+        
+        for instr in self.instruments.iter_mut() {
+            instr.shift(start - end);
+            instr.clear_queue();
+        }
+        
+        self.cursor = self.sequence.partition_point(|(_, n)| n.start_time(&self.tempo) < start);
+        
+        // notes which begin before the loop but carry on into it still need
+        // to be heard, just as they would have been the first time around.
+        for k in 0..self.cursor {
+            let (i, note) = self.sequence[k];
+            self.start_note(i, note, start);
+        }
+    }
+    
+    /// schedules a note if it will still be sounding (including its release)
+    /// at the given time.
+    fn start_note(&mut self, i: usize, note: song::Note, seconds: f64) {
+        if let Some(instr) = self.instruments.get_mut(i) {
+            if note.end_time(&self.tempo) + instr.release_time() as f64 > seconds {
+                instr.schedule(note, &self.tempo);
             }
         }
     }
+    
+    /// takes any notes which have been sent to the player and slots them
+    /// into the sequence.
+    fn receive_notes(&mut self) {
+        while let Ok((i, note)) = self.note_recv.try_recv() {
+            // notes normally arrive in order, but one which starts before the
+            // cursor is slotted in where it belongs, and handed over straight
+            // away.
+            let index = self.sequence.partition_point(|(_, n)| n.start.as_divs() <= note.start.as_divs());
+            self.sequence.insert(index, (i, note));
+            
+            if index < self.cursor {
+                self.cursor += 1;
+                self.start_note(i, note, self.playhead);
+            }
+        }
+    }
+    
+    /// hands every note which starts before `until` (in seconds) to its
+    /// instrument. any which have already finished (e.g. after seeking) are
+    /// skipped.
+    fn schedule_until(&mut self, until: f64) {
+        while let Some(&(i, note)) = self.sequence.get(self.cursor) {
+            if note.start_time(&self.tempo) >= until {
+                break;
+            }
+            
+            self.start_note(i, note, self.playhead);
+            self.cursor += 1;
+        }
+    }
+}
+
+/// the sound of the metronome, `t` seconds after it clicks.
+fn click(t: f64) -> f32 {
+    ((t * CLICK_FREQ * TAU).sin() * (-t * 60.0).exp() * 0.3) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{Instrument, Operator, WaveType};
+    
+    const SAMPLE_RATE: f64 = 8000.0;
+    
+    /// makes a player with a plain sine instrument, playing the given notes
+    /// at 60 bpm.
+    fn play(notes: &[song::Note]) -> Player {
+        let (mut player, chan) = Player::new();
+        let mut instr = Instrument::new(4);
+        
+        Operator::new(WaveType::Sine, true, 203.3)
+            .env(0.0, 0.0, 1.0, 0.0)
+            .send(0, 1.0)
+            .add(&mut instr);
+        
+        let mut song = song::Song::new(1, 60, song::Meter::new(4, 4));
+        notes.iter().for_each(|&n| song.add_note(0, n));
+        
+        player.instruments = vec![instr];
+        player.tempo = song.tempo_map();
+        song.sequence(chan);
+        player.paused = false;
+        player
+    }
+    
+    /// finds the loudest sample between two times, in seconds.
+    fn peak(player: &mut Player, from: f64, to: f64) -> f32 {
+        let samples = ((to - from) * SAMPLE_RATE) as usize;
+        
+        while player.playhead < from {
+            player.sample(1.0 / SAMPLE_RATE);
+        }
+        
+        (0..samples).map(|_| player.sample(1.0 / SAMPLE_RATE)[0].abs()).fold(0.0, f32::max)
+    }
+    
+    #[test]
+    fn is_given_room_for_new_notes() {
+        let (mut player, _) = Player::new();
+        let mut remote = player.remote();
+        let note = song::Note::new(48, 0, 0, 96, 1.0);
+        
+        // the player never makes room in its sequence itself, so once it's
+        // full, the remote sends it a bigger one, which it wouldn't have
+        // grown to by doubling.
+        for _ in 0..SEQUENCE_HEADROOM + 1 {
+            remote.add_note(0, note);
+        }
+        
+        player.sample(1.0 / SAMPLE_RATE);
+        assert_eq!(player.sequence.len(), SEQUENCE_HEADROOM + 1);
+        assert!(player.sequence.capacity() >= SEQUENCE_HEADROOM * 3);
+    }
+    
+    #[test]
+    fn keeps_notes_on_their_beats_when_the_tempo_changes() {
+        // halfway through a two beat note, the tempo doubles. the playhead
+        // stays on the first beat, which is now half a second in, and the
+        // note is let go of on the second.
+        let mut player = play(&[song::Note::new(12, 0, 0, song::BEAT_DIVISIONS * 2, 1.0)]);
+        peak(&mut player, 0.0, 1.0);
+        player.set_tempo(song::TempoMap::constant(120.0));
+        
+        assert!((player.playhead - 0.5).abs() < 1e-3);
+        assert!(peak(&mut player, 0.5, 0.99) > 0.5);
+        assert!(peak(&mut player, 1.01, 1.5) < 1e-6);
+        
+        // the same goes for a note-off which has already been handed to the
+        // instrument, just before it was due.
+        let mut player = play(&[song::Note::new(12, 0, 0, song::BEAT_DIVISIONS * 2, 1.0)]);
+        peak(&mut player, 0.0, 1.99);
+        player.set_tempo(song::TempoMap::constant(30.0));
+        
+        assert!((player.playhead - 3.98).abs() < 1e-3);
+        assert!(peak(&mut player, 3.98, 3.99) > 0.5);
+        assert!(peak(&mut player, 4.01, 4.5) < 1e-6);
+    }
+    
+    #[test]
+    fn loops_back_to_the_start() {
+        let mut player = play(&[song::Note::new(12, 0, 0, song::BEAT_DIVISIONS, 1.0)]);
+        player.loop_region = Some((song::Time::new(0, 0), song::Time::new(2, 0)));
+        
+        assert!(peak(&mut player, 0.1, 0.9) > 0.5);
+        assert!(peak(&mut player, 1.1, 1.9) < 1e-6);
+        
+        // the playhead wraps, rather than carrying on past the loop, and
+        // remotes are told that it did.
+        assert!(peak(&mut player, 0.1, 0.9) > 0.5);
+        assert!(player.playhead < 1.0);
+        assert!(player.remote().jumps() == (0, 1));
+    }
+    
+    #[test]
+    fn wraps_without_clicks() {
+        let mut player = play(&[song::Note::new(12, 0, 0, song::BEAT_DIVISIONS * 8, 1.0)]);
+        player.loop_region = Some((song::Time::new(1, 0), song::Time::new(3, 0)));
+        peak(&mut player, 0.0, 2.9);
+        
+        // a 203.3Hz sine never moves by more than about 0.16 between samples,
+        // so anything bigger would be a click.
+        let mut last = player.sample(1.0 / SAMPLE_RATE)[0];
+        
+        for _ in 0..(0.2 * SAMPLE_RATE) as usize {
+            let s = player.sample(1.0 / SAMPLE_RATE)[0];
+            assert!((s - last).abs() < 0.2);
+            last = s;
+        }
+        
+        assert!(player.playhead < 1.2);
+    }
+    
+    #[test]
+    fn seeking_starts_sounding_notes() {
+        let mut player = play(&[
+            song::Note::new(12, 0, 0, song::BEAT_DIVISIONS * 4, 1.0),
+            song::Note::new(24, 0, 0, song::BEAT_DIVISIONS, 1.0),
+        ]);
+        
+        player.seek(2.0);
+        assert!(peak(&mut player, 2.1, 2.9) > 0.5);
+        
+        player.seek(5.0);
+        assert!(peak(&mut player, 5.1, 5.9) < 1e-6);
+    }
+    
+    #[test]
+    fn pans_instruments() {
+        let mut player = play(&[song::Note::new(12, 0, 0, song::BEAT_DIVISIONS, 1.0)]);
+        player.mixer.channel_mut(0).pan = -1.0;
+        
+        let samples: Vec<synth::Stereo> = (0..(0.5 * SAMPLE_RATE) as usize)
+            .map(|_| player.sample(1.0 / SAMPLE_RATE))
+            .collect();
+        
+        assert!(samples.iter().map(|s| s[0].abs()).fold(0.0, f32::max) > 0.5);
+        assert!(samples.iter().all(|s| s[1] == 0.0));
+    }
+    
+    #[test]
+    fn plays_live_notes_while_paused() {
+        let mut player = play(&[]);
+        player.set_paused(true);
+        
+        let listen = |player: &mut Player, seconds: f64| (0..(seconds * SAMPLE_RATE) as usize)
+            .map(|_| player.sample(1.0 / SAMPLE_RATE)[0].abs())
+            .fold(0.0, f32::max);
+        
+        assert!(listen(&mut player, 0.1) < 1e-6);
+        
+        // a held note carries on until it's let go of.
+        player.note_on(0, 48, 1.0);
+        assert!(listen(&mut player, 0.5) > 0.5);
+        assert!(listen(&mut player, 0.5) > 0.5);
+        
+        player.note_off(0, 48);
+        listen(&mut player, 0.1);
+        assert!(listen(&mut player, 0.5) < 1e-6);
+        assert_eq!(player.playhead, 0.0);
+    }
+    
+    #[test]
+    fn counts_in_before_starting() {
+        let mut player = play(&[song::Note::new(48, 0, 0, 96, 1.0)]);
+        player.set_paused(true);
+        player.count_in(2.0);
+        
+        // the clicks can be heard, but the playhead waits for them to finish.
+        let clicks = (0..(1.5 * SAMPLE_RATE) as usize)
+            .map(|_| player.sample(1.0 / SAMPLE_RATE)[0].abs())
+            .fold(0.0, f32::max);
+        
+        assert!(clicks > 0.1);
+        assert_eq!(player.playhead, 0.0);
+        assert!(peak(&mut player, 0.5, 0.6) > 0.5);
+    }
 }
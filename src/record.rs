@@ -0,0 +1,292 @@
+use crate::history::Edit;
+use crate::remote::Remote;
+use crate::song::{self, Note, Song};
+
+/// how the playhead moved since a take last followed it.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Jump {
+    /// it played on from where it was.
+    None,
+    
+    /// it was moved somewhere else, skipping over everything in between.
+    Seek,
+    
+    /// it reached the end of the loop, and went back to the start.
+    Wrap,
+}
+
+impl Jump {
+    /// works out how the playhead moved from a player's counts of seeks and
+    /// wraps (see `Remote::jumps`) before and after. a seek wins over a wrap,
+    /// since it says where the playhead ended up.
+    pub fn between(before: (u64, u64), after: (u64, u64)) -> Jump {
+        if after.0 != before.0 {
+            Jump::Seek
+        } else if after.1 != before.1 {
+            Jump::Wrap
+        } else {
+            Jump::None
+        }
+    }
+}
+
+/// notes being recorded into one part of a song as they're played live.
+/// each note goes into the song as soon as it's let go of, so that it can
+/// be heard the next time around a loop, and once the take is finished it
+/// can be undone all at once.
+pub struct Take {
+    part: usize,
+    
+    /// the length, in divisions, of the grid which notes are snapped to.
+    quantum: u32,
+    
+    /// whether notes already in the part are taken out as the playhead
+    /// passes them, rather than being kept alongside the new ones.
+    replace: bool,
+    
+    /// the pitch, velocity and starting beat of each note being held down.
+    held: Vec<(u32, f32, f64)>,
+    
+    added: Vec<Note>,
+    removed: Vec<Note>,
+    
+    /// the beat which the playhead had reached when the take last advanced.
+    beat: f64,
+}
+
+impl Take {
+    /// starts a take at the given beat. notes are snapped to `quantize`
+    /// quanta per beat, if it's given. if `replace` is set, the part's old
+    /// notes are replaced by the take, otherwise they're played over.
+    pub fn new(part: usize, beat: f64, quantize: Option<u32>, replace: bool) -> Take {
+        Take {
+            part,
+            quantum: quantize.map_or(1, |q| (song::BEAT_DIVISIONS / q.max(1)).max(1)),
+            replace,
+            held: Vec::new(),
+            added: Vec::new(),
+            removed: Vec::new(),
+            beat,
+        }
+    }
+    
+    /// starts recording a note, played on an instrument at a beat. notes on
+    /// other instruments than the take's are ignored.
+    pub fn note_on(&mut self, instrument: usize, pitch: u32, velocity: f32, beat: f64) {
+        if instrument == self.part {
+            self.held.push((pitch, velocity, beat));
+        }
+    }
+    
+    /// finishes recording a note, and adds it to the song.
+    pub fn note_off(&mut self, instrument: usize, pitch: u32, beat: f64, song: &mut Song, player: &mut Remote) {
+        if instrument != self.part {
+            return;
+        }
+        
+        for (_, velocity, start) in self.held.iter().filter(|(p, ..)| *p == pitch).copied().collect::<Vec<_>>() {
+            // a note which is held over the end of a loop is cut off there.
+            let end = if beat < start {
+                player.loop_region().map_or(start, |(_, end)| end.as_beats())
+            } else {
+                beat
+            };
+            
+            let start = self.snap(start);
+            let end = self.snap(end).max(start + self.quantum);
+            let note = Note::new(pitch, start / song::BEAT_DIVISIONS, start % song::BEAT_DIVISIONS, end - start, velocity);
+            
+            song.add_note(self.part, note);
+            player.add_note(self.part, note);
+            self.added.push(note);
+        }
+        
+        self.held.retain(|(p, ..)| *p != pitch);
+    }
+    
+    /// follows the playhead to a beat, having moved there by `jump`. when
+    /// replacing, any old notes which start between the last beat and this
+    /// one are taken out of the song, going around the loop if it wrapped.
+    /// nothing is taken out for a seek.
+    pub fn advance(&mut self, beat: f64, jump: Jump, song: &mut Song, player: &mut Remote) {
+        let from = self.beat;
+        
+        match jump {
+            Jump::None if beat < from => {
+                // the playhead can be seen going back a moment before the
+                // wrap which took it there is counted, so the take waits to
+                // hear about it.
+                return;
+            },
+            Jump::None => if self.replace {
+                self.clear(from, beat, song, player);
+            },
+            Jump::Wrap => if let (true, Some((start, end))) = (self.replace, player.loop_region()) {
+                self.clear(from, end.as_beats(), song, player);
+                self.clear(start.as_beats(), beat, song, player);
+            },
+            Jump::Seek => {},
+        }
+        
+        self.beat = beat;
+    }
+    
+    /// lets go of any notes which are still held, and gets the edit which
+    /// the whole take made to the song.
+    pub fn finish(mut self, beat: f64, song: &mut Song, player: &mut Remote) -> Edit {
+        let held: Vec<u32> = self.held.iter().map(|&(pitch, ..)| pitch).collect();
+        
+        for pitch in held {
+            self.note_off(self.part, pitch, beat, song, player);
+        }
+        
+        Edit::Notes { part: self.part, removed: self.removed, added: self.added }
+    }
+    
+    /// takes the old notes which start between two beats out of the song.
+    fn clear(&mut self, from: f64, to: f64, song: &mut Song, player: &mut Remote) {
+        let old: Vec<Note> = song.parts[self.part].iter()
+            .filter(|n| n.start.as_beats() >= from && n.start.as_beats() < to && !self.added.contains(n))
+            .copied()
+            .collect();
+        
+        for note in old {
+            if let Some(i) = song.parts[self.part].iter().position(|n| *n == note) {
+                song.parts[self.part].remove(i);
+            }
+            
+            player.remove_note(self.part, note);
+            self.removed.push(note);
+        }
+    }
+    
+    /// snaps a beat to the nearest point on the take's grid, in divisions.
+    fn snap(&self, beat: f64) -> u32 {
+        let quanta = (beat.max(0.0) * song::BEAT_DIVISIONS as f64 / self.quantum as f64).round();
+        quanta as u32 * self.quantum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::History;
+    use crate::player::Player;
+    use crate::song::Meter;
+    
+    fn setup() -> (Song, Remote) {
+        let (player, _) = Player::new();
+        (Song::new(4, 60, Meter::new(4, 4)), player.remote())
+    }
+    
+    /// moves a take along to the player's playhead, as the window does each
+    /// frame.
+    fn follow(take: &mut Take, jumps: &mut (u64, u64), song: &mut Song, remote: &mut Remote) {
+        let now = remote.jumps();
+        let jump = Jump::between(*jumps, now);
+        *jumps = now;
+        
+        let beat = remote.tempo().beats_at(remote.playhead());
+        take.advance(beat, jump, song, remote);
+    }
+    
+    #[test]
+    fn quantises_recorded_notes() {
+        let (mut song, mut player) = setup();
+        let mut take = Take::new(1, 0.0, Some(4), false);
+        
+        take.note_on(1, 40, 0.5, 0.1);
+        take.note_on(0, 41, 1.0, 0.1);
+        take.note_off(1, 40, 0.3, &mut song, &mut player);
+        take.note_off(0, 41, 0.3, &mut song, &mut player);
+        
+        // a note shorter than a quantum still lasts for one.
+        take.note_on(1, 42, 1.0, 1.9);
+        take.note_off(1, 42, 1.95, &mut song, &mut player);
+        
+        assert!(song.parts[1] == vec![Note::new(40, 0, 0, 24, 0.5), Note::new(42, 2, 0, 24, 1.0)]);
+        assert!(song.parts[0].is_empty());
+    }
+    
+    #[test]
+    fn replaces_old_notes_and_undoes_at_once() {
+        let (mut song, mut player) = setup();
+        let mut history = History::new(16);
+        let old = [Note::new(40, 0, 48, 24, 1.0), Note::new(40, 3, 0, 24, 1.0)];
+        old.iter().for_each(|&n| song.add_note(0, n));
+        
+        let mut take = Take::new(0, 0.0, None, true);
+        take.note_on(0, 50, 1.0, 0.25);
+        take.advance(0.75, Jump::None, &mut song, &mut player);
+        take.advance(1.0, Jump::None, &mut song, &mut player);
+        
+        // the note still held is finished off along with the take.
+        history.remember(take.finish(1.0, &mut song, &mut player));
+        assert!(song.parts[0] == vec![old[1], Note::new(50, 0, 24, 72, 1.0)]);
+        
+        assert!(history.undo(&mut song, &mut player));
+        assert!(song.parts[0] == vec![old[1], old[0]]);
+    }
+    
+    #[test]
+    fn tells_seeks_and_wraps_from_playing_on() {
+        let (mut song, mut player) = setup();
+        let old = [Note::new(40, 1, 0, 24, 1.0), Note::new(40, 4, 0, 24, 1.0), Note::new(40, 6, 0, 24, 1.0)];
+        old.iter().for_each(|&n| song.add_note(0, n));
+        player.set_loop_region(Some((song::Time::new(0, 0), song::Time::new(8, 0))));
+        
+        // a slow frame can go a long way, but it's still played through.
+        let mut take = Take::new(0, 0.0, None, true);
+        take.advance(2.5, Jump::None, &mut song, &mut player);
+        assert!(song.parts[0] == vec![old[1], old[2]]);
+        
+        // seeking skips over the notes in between.
+        take.advance(6.5, Jump::Seek, &mut song, &mut player);
+        assert!(song.parts[0] == vec![old[1], old[2]]);
+        
+        // going back without a wrap is waited out, and then the wrap clears
+        // around the end of the loop.
+        take.advance(0.5, Jump::None, &mut song, &mut player);
+        assert!(song.parts[0] == vec![old[1], old[2]]);
+        take.advance(4.5, Jump::Wrap, &mut song, &mut player);
+        assert!(song.parts[0] == vec![old[2]]);
+        
+        let edit = take.finish(4.5, &mut song, &mut player);
+        assert!(matches!(edit, Edit::Notes { removed, .. } if removed == vec![old[0], old[1]]));
+    }
+    
+    #[test]
+    fn seeks_are_seen_once_the_player_makes_them() {
+        let (mut player, _) = Player::new();
+        let mut remote = player.remote();
+        let mut song = Song::new(4, 60, Meter::new(4, 4));
+        let old = Note::new(40, 2, 0, 24, 1.0);
+        song.add_note(0, old);
+        
+        let mut take = Take::new(0, 0.0, None, true);
+        let mut jumps = remote.jumps();
+        remote.set_paused(false);
+        
+        for _ in 0..4000 {
+            player.sample(1.0 / 8000.0);
+        }
+        
+        // until the player has moved the playhead, the take carries on from
+        // where it was, and then it jumps over the old note.
+        remote.seek(3.0);
+        follow(&mut take, &mut jumps, &mut song, &mut remote);
+        assert!(take.beat < 0.6);
+        
+        player.sample(1.0 / 8000.0);
+        follow(&mut take, &mut jumps, &mut song, &mut remote);
+        assert!(take.beat >= 3.0);
+        
+        for _ in 0..4000 {
+            player.sample(1.0 / 8000.0);
+        }
+        
+        follow(&mut take, &mut jumps, &mut song, &mut remote);
+        assert!(take.beat > 3.4);
+        assert!(song.parts[0] == vec![old]);
+    }
+}
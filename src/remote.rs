@@ -0,0 +1,463 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
+
+use crate::mixer::{Channel, Mixer};
+use crate::scope::Scope;
+use crate::song;
+use crate::synth;
+
+/// how many samples of each instrument's output are kept for its scope.
+pub const SCOPE_LENGTH: usize = 4096;
+
+/// how many things the player can have finished with before they've been
+/// collected. past this, it has to drop them itself.
+const GARBAGE_CAPACITY: usize = 16;
+
+/// how many notes can be added to the player's sequence, on top of the ones
+/// it starts with, before it needs to be given more room.
+pub const SEQUENCE_HEADROOM: usize = 1024;
+
+/// a change to a player, sent from another thread. the player picks up any
+/// waiting messages before working out each sample.
+pub enum Message {
+    SetPaused(bool),
+    SetVolume(f32),
+    SetMute(bool),
+    
+    /// moves the playhead to a time, in seconds, as `Player::seek` does.
+    Seek(f64),
+    
+    /// replaces the tempo map, keeping the playhead on the same beat.
+    SetTempo(song::TempoMap),
+    
+    SetLoopRegion(Option<(song::Time, song::Time)>),
+    
+    /// replaces one of the operators of one of the instruments.
+    SetOperator {
+        instrument: usize,
+        index: usize,
+        operator: synth::Operator,
+    },
+    
+    /// changes how many voices an instrument has, and how it steals them.
+    SetPolyphony {
+        instrument: usize,
+        voices: usize,
+        stealing: synth::StealPolicy,
+    },
+    
+    /// changes whether an instrument plays one note at a time.
+    SetMono {
+        instrument: usize,
+        mono: synth::Mono,
+    },
+    
+    /// replaces one of the LFOs of one of the instruments.
+    SetLfo {
+        instrument: usize,
+        index: usize,
+        lfo: synth::lfo::Lfo,
+    },
+    
+    /// replaces one of the routes in an instrument's modulation matrix.
+    SetRoute {
+        instrument: usize,
+        index: usize,
+        route: synth::modulation::Route,
+    },
+    
+    /// moves an instrument's live controllers.
+    SetControls {
+        instrument: usize,
+        controls: synth::modulation::Controls,
+    },
+    
+    /// replaces all of the player's instruments.
+    SetInstruments(Vec<synth::Instrument>),
+    
+    SetChannel(usize, Channel),
+    SetMixer(Mixer),
+    
+    /// forgets about every note, and starts again with these ones, which
+    /// should be in order of their start times.
+    Sequence(Vec<(usize, song::Note)>),
+    
+    /// an empty vector with more room than the player's sequence, which the
+    /// sequence is moved into, so that the player never has to make room for
+    /// new notes itself.
+    MakeRoom(Vec<(usize, song::Note)>),
+    
+    /// adds a note to the player's sequence without flushing the others, as
+    /// `Player::add_note` does.
+    AddNote(usize, song::Note),
+    RemoveNote(usize, song::Note),
+    
+    /// starts the player after a count-in, as `Player::count_in` does.
+    CountIn(f64),
+    
+    /// starts playing a note live, as `Player::note_on` does.
+    NoteOn {
+        instrument: usize,
+        pitch: u32,
+        velocity: f32,
+    },
+    
+    NoteOff {
+        instrument: usize,
+        pitch: u32,
+    },
+    
+    FlushNotes,
+    Reset,
+}
+
+/// something which the player has finished with, and has sent back to be
+/// dropped. freeing memory could hold up the audio thread, so the player
+/// never does it itself.
+pub enum Garbage {
+    Tempo(song::TempoMap),
+    Instruments(Vec<synth::Instrument>),
+    Mixer(Mixer),
+    Sequence(Vec<(usize, song::Note)>),
+}
+
+/// the things which a player shares with other threads as it plays, all of
+/// which can be read without waiting for the player.
+pub struct Monitor {
+    /// the bits of the player's playhead.
+    playhead: AtomicU64,
+    
+    /// how many times the playhead has been moved by a remote, and how many
+    /// times the player has taken it back to the start of the loop.
+    seeks: AtomicU64,
+    wraps: AtomicU64,
+    
+    /// the recent output of each instrument, after mixing. there is one scope
+    /// for each part of a song.
+    pub scopes: Vec<Scope>,
+    
+    /// the things which the player has finished with. only remotes use this,
+    /// so the player never waits for the lock.
+    garbage: Mutex<mpsc::Receiver<Garbage>>,
+}
+
+impl Monitor {
+    /// makes a monitor, and the channel which the player sends its garbage on.
+    pub(crate) fn new() -> (Monitor, mpsc::SyncSender<Garbage>) {
+        let (tx, rx) = mpsc::sync_channel(GARBAGE_CAPACITY);
+        
+        (Monitor {
+            playhead: AtomicU64::new(0.0f64.to_bits()),
+            seeks: AtomicU64::new(0),
+            wraps: AtomicU64::new(0),
+            scopes: (0..song::NUM_PARTS).map(|_| Scope::new(SCOPE_LENGTH)).collect(),
+            garbage: Mutex::new(rx),
+        }, tx)
+    }
+    
+    /// gets the playhead of the player, in seconds.
+    pub fn playhead(&self) -> f64 {
+        f64::from_bits(self.playhead.load(Ordering::Relaxed))
+    }
+    
+    pub fn set_playhead(&self, seconds: f64) {
+        self.playhead.store(seconds.to_bits(), Ordering::Relaxed);
+    }
+    
+    /// gets the number of seeks and loop wraps made so far. these say when
+    /// the playhead has jumped, rather than playing on from where it was.
+    /// only the player moves the playhead and counts jumps, and it always
+    /// moves it first, so the playhead should be read after this.
+    pub fn jumps(&self) -> (u64, u64) {
+        (self.seeks.load(Ordering::Acquire), self.wraps.load(Ordering::Acquire))
+    }
+    
+    pub(crate) fn count_seek(&self) {
+        self.seeks.fetch_add(1, Ordering::Release);
+    }
+    
+    pub(crate) fn count_wrap(&self) {
+        self.wraps.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// controls a player running on another thread (e.g. the audio thread)
+/// without ever making it wait. the remote keeps its own copy of the player's
+/// settings, which can be read at any time, and every change to them is sent
+/// to the player as a message.
+pub struct Remote {
+    messages: mpsc::Sender<Message>,
+    monitor: Arc<Monitor>,
+    
+    tempo: song::TempoMap,
+    volume: f32,
+    mute: bool,
+    paused: bool,
+    instruments: Vec<synth::Instrument>,
+    mixer: Mixer,
+    loop_region: Option<(song::Time, song::Time)>,
+    
+    /// how many notes the player's sequence has (at most), and how many it
+    /// has room for.
+    sequence_len: usize,
+    sequence_capacity: usize,
+}
+
+impl Remote {
+    /// makes a remote for a player, given a copy of its current settings.
+    pub(crate) fn new(messages: mpsc::Sender<Message>, monitor: Arc<Monitor>,
+        player: &crate::player::Player) -> Remote {
+        Remote {
+            messages,
+            monitor,
+            tempo: player.tempo.clone(),
+            volume: player.volume,
+            mute: player.mute,
+            paused: player.paused,
+            instruments: player.instruments.clone(),
+            mixer: player.mixer.clone(),
+            loop_region: player.loop_region,
+            sequence_len: player.sequence_len(),
+            sequence_capacity: player.sequence_capacity(),
+        }
+    }
+    
+    fn send(&self, message: Message) {
+        self.collect_garbage();
+        
+        // if the player has gone, there's nobody left to hear it anyway.
+        let _ = self.messages.send(message);
+    }
+    
+    /// drops everything which the player has finished with. this happens
+    /// whenever a message is sent, but should also be done now and then in
+    /// case there's nothing to send.
+    pub fn collect_garbage(&self) {
+        if let Ok(garbage) = self.monitor.garbage.try_lock() {
+            for _ in garbage.try_iter() {}
+        }
+    }
+    
+    pub fn tempo(&self) -> &song::TempoMap {
+        &self.tempo
+    }
+    
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+    
+    pub fn mute(&self) -> bool {
+        self.mute
+    }
+    
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+    
+    pub fn instruments(&self) -> &[synth::Instrument] {
+        &self.instruments
+    }
+    
+    pub fn mixer(&self) -> &Mixer {
+        &self.mixer
+    }
+    
+    pub fn loop_region(&self) -> Option<(song::Time, song::Time)> {
+        self.loop_region
+    }
+    
+    /// gets the player's playhead, in seconds, as of the last sample it played.
+    pub fn playhead(&self) -> f64 {
+        self.monitor.playhead()
+    }
+    
+    /// gets the number of seeks and loop wraps made so far, as
+    /// `Monitor::jumps` does.
+    pub fn jumps(&self) -> (u64, u64) {
+        self.monitor.jumps()
+    }
+    
+    /// gets the scope showing an instrument's recent output, if it has one.
+    pub fn scope(&self, instrument: usize) -> Option<&Scope> {
+        self.monitor.scopes.get(instrument)
+    }
+    
+    pub fn set_tempo(&mut self, tempo: song::TempoMap) {
+        self.tempo = tempo.clone();
+        self.send(Message::SetTempo(tempo));
+    }
+    
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+        self.send(Message::SetVolume(volume));
+    }
+    
+    pub fn set_mute(&mut self, mute: bool) {
+        self.mute = mute;
+        self.send(Message::SetMute(mute));
+    }
+    
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.send(Message::SetPaused(paused));
+    }
+    
+    pub fn set_loop_region(&mut self, region: Option<(song::Time, song::Time)>) {
+        self.loop_region = region;
+        self.send(Message::SetLoopRegion(region));
+    }
+    
+    /// moves the playhead to the given time, in seconds. the playhead (as
+    /// seen by `playhead`) only moves once the player gets the message.
+    pub fn seek(&mut self, seconds: f64) {
+        self.send(Message::Seek(seconds));
+    }
+    
+    /// changes one of the operators of an instrument.
+    pub fn edit_operator<F>(&mut self, instrument: usize, index: usize, f: F)
+    where F: FnOnce(&mut synth::Operator) {
+        if let Some(operator) = self.instruments.get_mut(instrument).and_then(|i| i.operators.get_mut(index)) {
+            f(operator);
+            
+            let operator = operator.clone();
+            self.send(Message::SetOperator { instrument, index, operator });
+        }
+    }
+    
+    /// changes how many notes an instrument can play at once, and which
+    /// voice it steals for a new note when they're all busy.
+    pub fn set_polyphony(&mut self, instrument: usize, voices: usize, stealing: synth::StealPolicy) {
+        if let Some(instr) = self.instruments.get_mut(instrument) {
+            instr.set_polyphony(voices);
+            instr.stealing = stealing;
+            self.send(Message::SetPolyphony { instrument, voices, stealing });
+        }
+    }
+    
+    /// changes whether an instrument plays one note at a time, and how it
+    /// glides between them.
+    pub fn set_mono(&mut self, instrument: usize, mono: synth::Mono) {
+        if let Some(instr) = self.instruments.get_mut(instrument) {
+            instr.mono = mono;
+            self.send(Message::SetMono { instrument, mono });
+        }
+    }
+    
+    /// replaces one of an instrument's LFOs.
+    pub fn set_lfo(&mut self, instrument: usize, index: usize, lfo: synth::lfo::Lfo) {
+        if let Some(l) = self.instruments.get_mut(instrument).and_then(|i| i.lfos.get_mut(index)) {
+            *l = lfo;
+            self.send(Message::SetLfo { instrument, index, lfo });
+        }
+    }
+    
+    /// replaces one of the routes in an instrument's modulation matrix.
+    pub fn set_route(&mut self, instrument: usize, index: usize, route: synth::modulation::Route) {
+        if let Some(r) = self.instruments.get_mut(instrument).and_then(|i| i.routes.get_mut(index)) {
+            *r = route;
+            self.send(Message::SetRoute { instrument, index, route });
+        }
+    }
+    
+    /// changes the live controllers (the mod wheel and aftertouch) of an
+    /// instrument. these aren't saved, and can't be undone.
+    pub fn edit_controls<F>(&mut self, instrument: usize, f: F) where F: FnOnce(&mut synth::modulation::Controls) {
+        if let Some(instr) = self.instruments.get_mut(instrument) {
+            f(&mut instr.controls);
+            
+            let controls = instr.controls;
+            self.send(Message::SetControls { instrument, controls });
+        }
+    }
+    
+    /// replaces the player's instruments with ones built from the given specs,
+    /// as `Player::load_instruments` does.
+    pub fn load_instruments(&mut self, specs: &[synth::InstrumentSpec]) {
+        synth::load_instruments(&mut self.instruments, specs);
+        self.send(Message::SetInstruments(self.instruments.clone()));
+    }
+    
+    /// changes the mixer settings of an instrument.
+    pub fn edit_channel<F>(&mut self, instrument: usize, f: F) where F: FnOnce(&mut Channel) {
+        // the player would have to make room for a new channel, so it's given
+        // the whole mixer instead.
+        let grows = instrument >= self.mixer.channels.len();
+        let channel = self.mixer.channel_mut(instrument);
+        f(channel);
+        
+        let channel = *channel;
+        
+        if grows {
+            self.send(Message::SetMixer(self.mixer.clone()));
+        } else {
+            self.send(Message::SetChannel(instrument, channel));
+        }
+    }
+    
+    pub fn set_mixer(&mut self, mixer: Mixer) {
+        self.mixer = mixer.clone();
+        self.send(Message::SetMixer(mixer));
+    }
+    
+    /// replaces every note the player knows about with the notes of a song.
+    pub fn sequence(&mut self, song: &song::Song) {
+        let mut notes = song.notes_in_order();
+        notes.reserve(SEQUENCE_HEADROOM);
+        
+        self.sequence_len = notes.len();
+        self.sequence_capacity = notes.capacity();
+        self.send(Message::Sequence(notes));
+    }
+    
+    /// adds a note to the player's sequence, without interrupting anything
+    /// it's already playing. the note isn't heard until it next comes round.
+    pub fn add_note(&mut self, instrument: usize, note: song::Note) {
+        if self.sequence_len >= self.sequence_capacity {
+            let room = Vec::with_capacity(self.sequence_capacity * 2 + SEQUENCE_HEADROOM);
+            self.sequence_capacity = room.capacity();
+            self.send(Message::MakeRoom(room));
+        }
+        
+        self.sequence_len += 1;
+        self.send(Message::AddNote(instrument, note));
+    }
+    
+    pub fn remove_note(&mut self, instrument: usize, note: song::Note) {
+        self.sequence_len = self.sequence_len.saturating_sub(1);
+        self.send(Message::RemoveNote(instrument, note));
+    }
+    
+    /// starts the player after clicking out `beats` beats of the current
+    /// tempo, to give whoever's playing along time to get ready.
+    pub fn count_in(&mut self, beats: f64) {
+        self.paused = false;
+        self.send(Message::CountIn(beats));
+    }
+    
+    /// starts playing a note live on an instrument, until `note_off` is
+    /// called with the same pitch.
+    pub fn note_on(&mut self, instrument: usize, pitch: u32, velocity: f32) {
+        self.send(Message::NoteOn { instrument, pitch, velocity });
+    }
+    
+    pub fn note_off(&mut self, instrument: usize, pitch: u32) {
+        self.send(Message::NoteOff { instrument, pitch });
+    }
+    
+    pub fn flush_notes(&mut self) {
+        self.sequence_len = 0;
+        self.send(Message::FlushNotes);
+    }
+    
+    /// resets the player to a default state, as `Player::reset` does.
+    pub fn reset(&mut self) {
+        self.tempo = song::TempoMap::constant(60.0);
+        self.volume = 1.0;
+        self.mute = false;
+        self.paused = true;
+        self.mixer = Mixer::default();
+        self.loop_region = None;
+        self.sequence_len = 0;
+        self.send(Message::Reset);
+    }
+}
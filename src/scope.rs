@@ -0,0 +1,86 @@
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// a ring buffer holding the most recent samples of a signal, for drawing
+/// scopes and meters. one thread writes to it while any number of others read
+/// from it, and none of them ever wait for each other. once it's full, each
+/// new sample replaces the oldest one.
+pub struct Scope {
+    /// the bits of each sample, so that they can be stored atomically.
+    samples: Box<[AtomicU32]>,
+    
+    /// the total number of samples which have ever been written.
+    written: AtomicUsize,
+}
+
+impl Scope {
+    /// makes an empty scope which remembers the last `len` samples.
+    pub fn new(len: usize) -> Scope {
+        Scope {
+            samples: (0..len.max(1)).map(|_| AtomicU32::new(0)).collect(),
+            written: AtomicUsize::new(0),
+        }
+    }
+    
+    /// adds a sample to the scope. only one thread should write to a scope.
+    pub fn push(&self, sample: f32) {
+        let n = self.written.load(Ordering::Relaxed);
+        self.samples[n % self.samples.len()].store(sample.to_bits(), Ordering::Relaxed);
+        self.written.store(n + 1, Ordering::Release);
+    }
+    
+    /// fills `buf` with the most recent samples, oldest first. if fewer have
+    /// been written than will fit, the start of `buf` is filled with silence.
+    /// reading never holds up the writer, so if `buf` is nearly as long as
+    /// the scope, the oldest few samples might already have been replaced.
+    pub fn read(&self, buf: &mut [f32]) {
+        let n = self.written.load(Ordering::Acquire);
+        let len = self.samples.len();
+        
+        for (i, s) in buf.iter_mut().rev().enumerate() {
+            *s = if i < n && i < len {
+                f32::from_bits(self.samples[(n - i - 1) % len].load(Ordering::Relaxed))
+            } else {
+                0.0
+            };
+        }
+    }
+    
+    /// finds the loudest of the last `count` samples, e.g. for a level meter.
+    pub fn peak(&self, count: usize) -> f32 {
+        let mut buf = vec![0.0; count.min(self.samples.len())];
+        self.read(&mut buf);
+        buf.iter().fold(0.0, |peak, s| s.abs().max(peak))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn keeps_the_latest_samples() {
+        let scope = Scope::new(4);
+        let mut buf = [1.0; 3];
+        
+        scope.read(&mut buf);
+        assert_eq!(buf, [0.0, 0.0, 0.0]);
+        
+        scope.push(1.0);
+        scope.push(2.0);
+        scope.read(&mut buf);
+        assert_eq!(buf, [0.0, 1.0, 2.0]);
+        
+        for s in 3..=6 {
+            scope.push(s as f32);
+        }
+        
+        scope.read(&mut buf);
+        assert_eq!(buf, [4.0, 5.0, 6.0]);
+        
+        // asking for more than the scope holds pads the start with silence.
+        let mut long = [1.0; 6];
+        scope.read(&mut long);
+        assert_eq!(long, [0.0, 0.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(scope.peak(2), 6.0);
+    }
+}
@@ -1,16 +1,43 @@
 use serde::{Serialize, Deserialize};
+use serde_json::{json, Map, Value};
+use std::fmt;
+use std::io::{Read, Write};
 use std::sync::mpsc;
 
+use crate::synth;
+use crate::synth::effect::{EffectSpec, MAX_DELAY_LENGTH};
+use crate::synth::modulation::{Destination, Source};
+use crate::mixer::Mixer;
+
+/// the version of the .crz format written by this version of cancrizans.
+/// files without a version field are taken to be version 1.
+pub const FORMAT_VERSION: u32 = 10;
+
+/// the migrations used to upgrade old .crz documents. `MIGRATIONS[i]` upgrades
+/// a document from version `i + 1` to version `i + 2`, so there should always
+/// be exactly `FORMAT_VERSION - 1` of them.
+const MIGRATIONS: [fn(&mut Map<String, Value>); FORMAT_VERSION as usize - 1] = [
+    migrate_v1_to_v2,
+    migrate_v2_to_v3,
+    migrate_v3_to_v4,
+    migrate_v4_to_v5,
+    migrate_v5_to_v6,
+    migrate_v6_to_v7,
+    migrate_v7_to_v8,
+    migrate_v8_to_v9,
+    migrate_v9_to_v10,
+];
+
 pub const BEAT_DIVISIONS: u32 = 96;
 pub const NUM_PARTS: usize = 4;
-pub const C0: f32 = 16.35159783;
+pub const C0: f32 = 16.351598;
 
 pub const NOTE_NAMES: [&str; 12] = [
     "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
 ];
 
 /// a particular point of time, quantized as a moment in a song.
-#[derive(Copy, Clone, Serialize, Deserialize)]
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Time {
     pub beat: u32,
     pub division: u32,
@@ -21,6 +48,11 @@ impl Time {
         Time { beat, division }
     }
     
+    /// builds a time from a number of divisions since the start of the song.
+    pub fn from_divs(divisions: u32) -> Time {
+        Time::new(divisions / BEAT_DIVISIONS, divisions % BEAT_DIVISIONS)
+    }
+    
     pub fn add(&self, divisions: u32) -> Time {
         Time {
             beat: self.beat + divisions / BEAT_DIVISIONS,
@@ -35,10 +67,95 @@ impl Time {
     pub fn as_divs(&self) -> u32 {
         self.beat * BEAT_DIVISIONS + self.division
     }
+    
+    /// gets the time as a (fractional) number of beats since the start of the song.
+    pub fn as_beats(&self) -> f64 {
+        self.beat as f64 + self.division as f64 / BEAT_DIVISIONS as f64
+    }
 }
 
-/// an individual note in a song.
+/// a change of tempo at some point in a song.
 #[derive(Copy, Clone, Serialize, Deserialize)]
+pub struct TempoChange {
+    /// the time at which the song reaches the new tempo.
+    pub time: Time,
+    
+    /// the new tempo, in beats per minute.
+    pub bpm: f64,
+    
+    /// if set, the tempo changes gradually from the previous tempo, arriving
+    /// at `bpm` at `time`, rather than jumping there all at once.
+    #[serde(default)]
+    pub ramp: bool,
+}
+
+/// a time signature, such as 6/8.
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Meter {
+    /// the number of units in a bar.
+    pub numerator: u32,
+    
+    /// the length of each unit, as a fraction of a whole note (e.g. 8 for
+    /// eighth notes.) this should be a power of two, no more than 64.
+    pub denominator: u32,
+}
+
+/// a change of time signature at some point in a song. a new bar always
+/// begins at the change, even if the previous one hasn't finished.
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub struct MeterChange {
+    /// the time at which the first bar in the new meter begins.
+    pub time: Time,
+    
+    /// the new time signature.
+    pub meter: Meter,
+}
+
+/// a stretch of a song during which the meter stays the same.
+#[derive(Copy, Clone)]
+struct MeterSection {
+    /// the division at which the section begins.
+    start: u32,
+    
+    /// the number of bars before the start of the section.
+    bar: u32,
+    
+    meter: Meter,
+}
+
+/// works out which bar each point in a song falls in, taking all of the
+/// song's changes of time signature into account.
+#[derive(Clone)]
+pub struct MeterMap {
+    sections: Vec<MeterSection>,
+}
+
+/// one stretch of a tempo map, over which the tempo is either constant or
+/// changes linearly with each beat.
+#[derive(Copy, Clone)]
+struct TempoSegment {
+    /// the beat at which the segment begins.
+    beat: f64,
+    
+    /// the time, in seconds, at which the segment begins.
+    seconds: f64,
+    
+    /// the tempo, in beats per second, at the start of the segment.
+    bps: f64,
+    
+    /// how much the tempo (in beats per second) changes with each beat.
+    slope: f64,
+}
+
+/// converts between positions in a song, measured in beats, and real times
+/// measured in seconds, taking all of the song's tempo changes into account.
+#[derive(Clone)]
+pub struct TempoMap {
+    segments: Vec<TempoSegment>,
+}
+
+/// an individual note in a song.
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Note {
     /// the pitch of the note. C0 is represented as 0, and each successive
     /// pitch goes up by one semitone.
@@ -56,19 +173,57 @@ pub struct Note {
     pub velocity: f32
 }
 
+/// an error encountered while loading a song from a .crz file.
+#[derive(Debug)]
+pub enum LoadError {
+    /// the file isn't valid JSON, or doesn't describe a song.
+    Json(serde_json::Error),
+    
+    /// the top level of the file isn't a JSON object.
+    NotAnObject,
+    
+    /// the file's version field isn't a positive integer.
+    BadVersion(Value),
+    
+    /// the file was written by a newer version of cancrizans, and so can't be
+    /// read safely.
+    TooNew(u32),
+}
+
 /// a song, consisting of NUM_PARTS parts, each of which are a list of
 /// notes to be played.
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Song {
-    /// the beats-per-minute of the song.
+    /// the beats-per-minute at the start of the song.
     pub bpm: u32,
     
-    /// the amount of beats in a bar (e.g. 4 for a 4/4 piece, or 3 for a 3/4.) a
-    /// beat is always a quarter note.
-    pub beats_per_bar: u32,
+    /// the time signature at the start of the song. whatever the meter, each
+    /// beat in a `Time` is a quarter note.
+    pub meter: Meter,
+    
+    /// changes of time signature after the start of the song, which don't
+    /// need to be in any particular order.
+    #[serde(default)]
+    pub meter_changes: Vec<MeterChange>,
+    
+    /// changes of tempo after the start of the song, which don't need to be
+    /// in any particular order.
+    #[serde(default)]
+    pub tempo_changes: Vec<TempoChange>,
     
     /// the parts making up the song, each represented as a vector of notes.
     pub parts: Vec<Vec<Note>>,
+    
+    /// the instruments used to play each part. this is only kept up to date
+    /// when the song is saved, since the live instruments belong to the
+    /// player. older songs may not have any instruments.
+    #[serde(default)]
+    pub instruments: Vec<synth::InstrumentSpec>,
+    
+    /// the gain, pan, mute and solo of each part's instrument. like the
+    /// instruments, this is only kept up to date when the song is saved.
+    #[serde(default)]
+    pub mixer: Mixer,
 }
 
 impl Note {
@@ -78,22 +233,22 @@ impl Note {
     
     /// get the real frequency of the note (as opposed to its pitch) in Hz.
     pub fn freq(&self) -> f32 {
-        C0 * (2.0 as f32).powf((self.pitch as f32) / 12.0)
+        C0 * 2.0f32.powf((self.pitch as f32) / 12.0)
     }
     
     /// get the time (in seconds) at which the note should start to play.
-    pub fn start_time(&self, bps: f64) -> f64 {
-        ((self.start.beat as f64) + (self.start.division as f64) / (BEAT_DIVISIONS as f64)) / bps
+    pub fn start_time(&self, tempo: &TempoMap) -> f64 {
+        tempo.seconds_at(self.start.as_beats())
     }
     
     /// get the time (in seconds) at which the note should finish playing.
-    pub fn end_time(&self, bps: f64) -> f64 {
-        self.start_time(bps) + self.real_duration(bps)
+    pub fn end_time(&self, tempo: &TempoMap) -> f64 {
+        tempo.seconds_at(self.start.as_beats() + self.duration as f64 / BEAT_DIVISIONS as f64)
     }
     
     /// get the duration (in seconds) of the note.
-    pub fn real_duration(&self, bps: f64) -> f64 {
-        ((self.duration as f64) / BEAT_DIVISIONS as f64) / bps
+    pub fn real_duration(&self, tempo: &TempoMap) -> f64 {
+        self.end_time(tempo) - self.start_time(tempo)
     }
     
     /// check if two notes overlap.
@@ -103,7 +258,7 @@ impl Note {
         let s2 = note.start.as_divs();
         let e2 = s2 + note.duration;
         
-        (s1 < e2 && e1 > s2) || (s2 < e1 && e2 > s1)
+        s1 < e2 && s2 < e1
     }
     
     /// check if the note contains a particular time.
@@ -123,7 +278,7 @@ impl Note {
 }
 
 impl Song {
-    pub fn new(num_parts: usize, bpm: u32, beats_per_bar: u32) -> Song {
+    pub fn new(num_parts: usize, bpm: u32, meter: Meter) -> Song {
         let mut parts = Vec::new();
         for _ in 0..num_parts {
             parts.push(Vec::new());
@@ -131,8 +286,12 @@ impl Song {
         
         Song {
             bpm,
-            beats_per_bar,
+            meter,
+            meter_changes: Vec::new(),
+            tempo_changes: Vec::new(),
             parts,
+            instruments: Vec::new(),
+            mixer: Mixer::default(),
         }
     }
     
@@ -142,33 +301,725 @@ impl Song {
     }
     
     /// calculates the total duration of the song, in seconds.
-    pub fn duration(&self, bps: f64) -> f64 {
-        self.parts.iter().filter_map(|p| p.last().map(|n| n.end_time(bps))).reduce(f64::max).unwrap_or(0.0)
+    pub fn duration(&self) -> f64 {
+        let tempo = self.tempo_map();
+        self.parts.iter().flatten().map(|n| n.end_time(&tempo)).reduce(f64::max).unwrap_or(0.0)
+    }
+    
+    /// builds the song's tempo map, which is used to work out when things
+    /// happen in real time.
+    pub fn tempo_map(&self) -> TempoMap {
+        TempoMap::new(self.bpm as f64, &self.tempo_changes)
+    }
+    
+    /// builds the song's meter map, which is used to work out where each bar is.
+    pub fn meter_map(&self) -> MeterMap {
+        MeterMap::new(self.meter, &self.meter_changes)
+    }
+    
+    /// reads a song from a .crz document, upgrading it from older versions of
+    /// the format if necessary.
+    pub fn load<R: Read>(reader: R) -> Result<Song, LoadError> {
+        let doc = serde_json::from_reader(reader).map_err(LoadError::Json)?;
+        Song::from_value(doc)
+    }
+    
+    /// builds a song from an already-parsed .crz document, upgrading it from
+    /// older versions of the format if necessary.
+    pub fn from_value(mut doc: Value) -> Result<Song, LoadError> {
+        let obj = doc.as_object_mut().ok_or(LoadError::NotAnObject)?;
+        
+        let version = match obj.remove("version") {
+            None => 1,
+            Some(v) => match v.as_u64() {
+                Some(n) if n >= 1 && n <= u32::MAX as u64 => n as u32,
+                _ => return Err(LoadError::BadVersion(v)),
+            },
+        };
+        
+        if version > FORMAT_VERSION {
+            return Err(LoadError::TooNew(version));
+        }
+        
+        for migrate in &MIGRATIONS[version as usize - 1..] {
+            migrate(obj);
+        }
+        
+        serde_json::from_value(doc).map_err(LoadError::Json)
     }
     
-    /// gets the song's BPS, or beats per second.
-    pub fn get_bps(&self) -> f64 {
-        self.bpm as f64 / 60.0
+    /// writes the song as a .crz document, tagged with the current format version.
+    pub fn save<W: Write>(&self, writer: W) -> serde_json::Result<()> {
+        #[derive(Serialize)]
+        struct Versioned<'a> {
+            version: u32,
+            
+            #[serde(flatten)]
+            song: &'a Song,
+        }
+        
+        serde_json::to_writer_pretty(writer, &Versioned {
+            version: FORMAT_VERSION,
+            song: self,
+        })
+    }
+    
+    /// checks that the song is well-formed, returning a description of each
+    /// problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        
+        // times are counted in divisions from the start, which only go so far.
+        let fits = |time: Time, duration: u32| {
+            time.beat as u64 * BEAT_DIVISIONS as u64 + time.division as u64 + duration as u64 <= u32::MAX as u64
+        };
+        
+        if self.parts.len() != NUM_PARTS {
+            problems.push(format!("has {} parts, rather than {}", self.parts.len(), NUM_PARTS));
+        }
+        
+        if self.bpm == 0 {
+            problems.push(String::from("bpm must be greater than zero"));
+        }
+        
+        for (i, change) in self.tempo_changes.iter().enumerate() {
+            if !(change.bpm > 0.0 && change.bpm.is_finite()) {
+                problems.push(format!("tempo change {}: bpm must be greater than zero", i + 1));
+            }
+            
+            if change.time.division >= BEAT_DIVISIONS {
+                problems.push(format!("tempo change {}: division is out of range", i + 1));
+            }
+            
+            if !fits(change.time, 0) {
+                problems.push(format!("tempo change {}: is too late", i + 1));
+            }
+        }
+        
+        if let Some(problem) = self.meter.problem() {
+            problems.push(format!("time signature: {}", problem));
+        }
+        
+        for (i, change) in self.meter_changes.iter().enumerate() {
+            if let Some(problem) = change.meter.problem() {
+                problems.push(format!("time signature change {}: {}", i + 1, problem));
+            }
+            
+            if change.time.division >= BEAT_DIVISIONS {
+                problems.push(format!("time signature change {}: division is out of range", i + 1));
+            }
+            
+            if !fits(change.time, 0) {
+                problems.push(format!("time signature change {}: is too late", i + 1));
+            }
+        }
+        
+        for (p, part) in self.parts.iter().enumerate() {
+            for (n, note) in part.iter().enumerate() {
+                let mut problem = |what: &str| problems.push(format!("part {}, note {}: {}", p + 1, n + 1, what));
+                
+                if note.duration == 0 {
+                    problem("duration is zero");
+                }
+                
+                if note.start.division >= BEAT_DIVISIONS {
+                    problem("division is out of range");
+                }
+                
+                if !(0.0..=1.0).contains(&note.velocity) {
+                    problem("velocity is outside of 0-1");
+                }
+                
+                if !fits(note.start, note.duration) {
+                    problem("ends too late");
+                }
+            }
+        }
+        
+        for (i, instr) in self.instruments.iter().enumerate() {
+            if instr.num_voices == 0 {
+                problems.push(format!("instrument {}: has no voices", i + 1));
+            }
+            
+            if instr.operators.len() > synth::MAX_OPERATORS {
+                problems.push(format!("instrument {}: has more than {} operators", i + 1, synth::MAX_OPERATORS));
+            }
+            
+            for (e, effect) in instr.effects.iter().enumerate() {
+                match effect {
+                    EffectSpec::Echo { length, .. } | EffectSpec::Delay { length, .. }
+                        if !(1..=MAX_DELAY_LENGTH).contains(length) => {
+                        problems.push(format!("instrument {}, effect {}: length must be from 1 to {} samples",
+                            i + 1, e + 1, MAX_DELAY_LENGTH));
+                    },
+                    _ => {},
+                }
+            }
+            
+            for (r, route) in instr.routes.iter().enumerate().filter(|(_, r)| r.is_active()) {
+                let mut problem = |what: &str| problems.push(format!("instrument {}, route {}: {}", i + 1, r + 1, what));
+                
+                match route.source {
+                    Source::Lfo(l) if l >= synth::lfo::MAX_LFOS => problem("source LFO doesn't exist"),
+                    Source::Envelope(o) if o >= instr.operators.len() => problem("source operator doesn't exist"),
+                    _ => {},
+                }
+                
+                match route.destination {
+                    Destination::Effect { effect, .. } if effect >= instr.effects.len() => problem("destination effect doesn't exist"),
+                    Destination::Effect { .. } => {},
+                    d if d.index() >= instr.operators.len() => problem("destination operator doesn't exist"),
+                    _ => {},
+                }
+            }
+        }
+        
+        problems
     }
     
     /// sends all of the notes in a song to a player's note channel.
     pub fn sequence(&self, chan: mpsc::Sender<(usize, Note)>) {
+        // play the notes in the order in which they begin.
+        self.notes_in_order().into_iter().for_each(|note| {
+            chan.send(note)
+                .expect("could not send note")
+        });
+    }
+    
+    /// gets every note in the song, along with the part it belongs to, in
+    /// the order in which they start.
+    pub fn notes_in_order(&self) -> Vec<(usize, Note)> {
+        let tempo = &self.tempo_map();
+        
         // collect all notes from all parts into one vector, and note their
         // start times.
         let mut notes = self.parts
             .iter()
             .enumerate()
-            .map(|(i, part)| part.iter().map(move |n| (i, n, n.start_time(self.get_bps()))))
-            .flatten()
+            .flat_map(|(i, part)| part.iter().map(move |n| (i, n, n.start_time(tempo))))
             .collect::<Vec<(usize, &Note, f64)>>();
         
         // sort all of the notes by their start time.
         notes.sort_by(|(_, _, a), (_, _, b)| a.partial_cmp(b).unwrap());
         
-        // play the notes in the order in which they begin.
-        notes.iter().for_each(|(i, note, _)| {
-            chan.send((*i, **note))
-                .expect("could not send note")
+        notes.into_iter().map(|(i, note, _)| (i, *note)).collect()
+    }
+}
+
+impl Meter {
+    pub fn new(numerator: u32, denominator: u32) -> Meter {
+        Meter { numerator, denominator }
+    }
+    
+    /// gets the length of one unit of the meter (e.g. an eighth note in 6/8)
+    /// in divisions.
+    pub fn unit_divs(&self) -> u32 {
+        (BEAT_DIVISIONS * 4 / self.denominator.max(1)).max(1)
+    }
+    
+    /// gets the length of a whole bar in divisions.
+    pub fn bar_divs(&self) -> u32 {
+        self.unit_divs() * self.numerator.max(1)
+    }
+    
+    /// describes what's wrong with the time signature, if anything.
+    fn problem(&self) -> Option<&'static str> {
+        if self.numerator == 0 {
+            Some("numerator must be greater than zero")
+        } else if !self.denominator.is_power_of_two() || self.denominator > 64 {
+            Some("denominator must be a power of two, no more than 64")
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Meter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator)
+    }
+}
+
+impl MeterMap {
+    /// builds a meter map for a song which starts in `meter`, and then
+    /// changes time signature as described by `changes`.
+    pub fn new(meter: Meter, changes: &[MeterChange]) -> MeterMap {
+        let mut changes = changes.to_vec();
+        changes.sort_by_key(|c| c.time.as_divs());
+        
+        let mut sections = vec![MeterSection { start: 0, bar: 0, meter }];
+        
+        for change in changes {
+            let start = change.time.as_divs();
+            let last = sections.last_mut().unwrap();
+            
+            if start == last.start {
+                last.meter = change.meter;
+            } else {
+                // a bar which is cut short by the change still counts.
+                let bar_divs = last.meter.bar_divs();
+                let bar = last.bar + (start - last.start).div_ceil(bar_divs);
+                sections.push(MeterSection { start, bar, meter: change.meter });
+            }
+        }
+        
+        MeterMap { sections }
+    }
+    
+    /// gets the time signature in use at the given time.
+    pub fn meter_at(&self, time: Time) -> Meter {
+        self.section_at(time).meter
+    }
+    
+    /// finds the bar containing the given time, counting from zero, along
+    /// with how many divisions into that bar it is.
+    pub fn position(&self, time: Time) -> (u32, u32) {
+        let section = self.section_at(time);
+        let offset = time.as_divs() - section.start;
+        let bar_divs = section.meter.bar_divs();
+        
+        (section.bar + offset / bar_divs, offset % bar_divs)
+    }
+    
+    fn section_at(&self, time: Time) -> &MeterSection {
+        let i = self.sections.partition_point(|s| s.start <= time.as_divs());
+        &self.sections[i - 1]
+    }
+}
+
+impl TempoMap {
+    /// builds a tempo map for a song which starts at `bpm` beats per minute,
+    /// and then changes tempo as described by `changes`.
+    pub fn new(bpm: f64, changes: &[TempoChange]) -> TempoMap {
+        let mut changes = changes.to_vec();
+        changes.sort_by_key(|c| c.time.as_divs());
+        
+        let mut segments = vec![TempoSegment { beat: 0.0, seconds: 0.0, bps: bpm / 60.0, slope: 0.0 }];
+        
+        for change in changes {
+            let beat = change.time.as_beats();
+            let bps = change.bpm / 60.0;
+            let last = segments.last_mut().unwrap();
+            
+            if change.ramp && beat > last.beat {
+                last.slope = (bps - last.bps) / (beat - last.beat);
+            }
+            
+            let seconds = last.seconds_at(beat);
+            
+            if beat <= last.beat {
+                last.bps = bps;
+            } else {
+                segments.push(TempoSegment { beat, seconds, bps, slope: 0.0 });
+            }
+        }
+        
+        TempoMap { segments }
+    }
+    
+    /// builds a tempo map which stays at `bpm` beats per minute forever.
+    pub fn constant(bpm: f64) -> TempoMap {
+        TempoMap::new(bpm, &[])
+    }
+    
+    /// changes the map to stay at `bpm` beats per minute forever, without
+    /// allocating or freeing any memory.
+    pub fn make_constant(&mut self, bpm: f64) {
+        self.segments.truncate(1);
+        self.segments[0] = TempoSegment { beat: 0.0, seconds: 0.0, bps: bpm / 60.0, slope: 0.0 };
+    }
+    
+    /// gets the time, in seconds, at which the given beat is reached.
+    pub fn seconds_at(&self, beat: f64) -> f64 {
+        self.segment_by(|s| s.beat <= beat).seconds_at(beat)
+    }
+    
+    /// gets the (fractional) beat which is reached after the given number of seconds.
+    pub fn beats_at(&self, seconds: f64) -> f64 {
+        self.segment_by(|s| s.seconds <= seconds).beats_at(seconds)
+    }
+    
+    /// gets the tempo, in beats per minute, at the given beat.
+    pub fn bpm_at(&self, beat: f64) -> f64 {
+        let seg = self.segment_by(|s| s.beat <= beat);
+        (seg.bps + seg.slope * (beat - seg.beat).max(0.0)) * 60.0
+    }
+    
+    /// finds the last segment satisfying `starts_before`, or the first if none of them do.
+    fn segment_by<F: Fn(&TempoSegment) -> bool>(&self, starts_before: F) -> &TempoSegment {
+        let i = self.segments.partition_point(starts_before);
+        &self.segments[i.max(1) - 1]
+    }
+}
+
+impl TempoSegment {
+    fn seconds_at(&self, beat: f64) -> f64 {
+        let beats = beat - self.beat;
+        
+        if self.slope == 0.0 || beats < 0.0 {
+            self.seconds + beats / self.bps
+        } else {
+            // the tempo is bps + slope * beats, so the time taken is the
+            // integral of 1 / (bps + slope * beats) over the beats.
+            self.seconds + (1.0 + self.slope * beats / self.bps).ln() / self.slope
+        }
+    }
+    
+    fn beats_at(&self, seconds: f64) -> f64 {
+        let elapsed = seconds - self.seconds;
+        
+        if self.slope == 0.0 || elapsed < 0.0 {
+            self.beat + elapsed * self.bps
+        } else {
+            self.beat + self.bps * (self.slope * elapsed).exp_m1() / self.slope
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Json(err) => write!(f, "invalid song file: {}", err),
+            LoadError::NotAnObject => write!(f, "invalid song file: expected a JSON object"),
+            LoadError::BadVersion(v) => write!(f, "invalid song file: bad format version {}", v),
+            LoadError::TooNew(v) => write!(f,
+                "song file is format version {}, but only versions up to {} are supported. try a newer version of cancrizans",
+                v, FORMAT_VERSION),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// version 2 added instruments to songs. older songs just don't have any.
+fn migrate_v1_to_v2(doc: &mut Map<String, Value>) {
+    doc.entry("instruments").or_insert_with(|| Value::Array(Vec::new()));
+}
+
+/// version 3 added tempo changes. older songs keep the same tempo throughout.
+fn migrate_v2_to_v3(doc: &mut Map<String, Value>) {
+    doc.entry("tempo_changes").or_insert_with(|| Value::Array(Vec::new()));
+}
+
+/// version 4 replaced the number of beats in a bar with a full time
+/// signature. older songs were always counted in quarter notes.
+fn migrate_v3_to_v4(doc: &mut Map<String, Value>) {
+    if let Some(beats_per_bar) = doc.remove("beats_per_bar") {
+        doc.insert(String::from("meter"), json!({ "numerator": beats_per_bar, "denominator": 4 }));
+    }
+    
+    doc.entry("meter_changes").or_insert_with(|| Value::Array(Vec::new()));
+}
+
+/// version 5 made songs stereo, adding the mixer and the width of echoes and
+/// reverbs. older songs play every part as it is, and their effects are
+/// given the widest sound. some older instruments were panned by themselves,
+/// and their pan is moved into the mixer.
+fn migrate_v4_to_v5(doc: &mut Map<String, Value>) {
+    let mut pans = Vec::new();
+    
+    if let Some(Value::Array(instruments)) = doc.get_mut("instruments") {
+        for instr in instruments.iter_mut() {
+            let instr = match instr.as_object_mut() {
+                Some(instr) => instr,
+                None => {
+                    pans.push(None);
+                    continue;
+                },
+            };
+            
+            pans.push(instr.remove("pan").and_then(|p| p.as_f64()));
+            
+            if let Some(Value::Array(effects)) = instr.get_mut("effects") {
+                for effect in effects.iter_mut().filter_map(Value::as_object_mut) {
+                    if matches!(effect.get("type").and_then(Value::as_str), Some("Echo" | "Reverb")) {
+                        effect.entry("width").or_insert(json!(1.0));
+                    }
+                }
+            }
+        }
+    }
+    
+    let channels: Vec<Value> = if pans.iter().any(Option::is_some) {
+        pans.into_iter()
+            .map(|pan| json!({ "gain": 1.0, "pan": pan.unwrap_or(0.0), "mute": false, "solo": false }))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    
+    doc.entry("mixer").or_insert_with(|| json!({ "channels": channels }));
+}
+
+/// version 6 added voice stealing policies. older instruments always stole
+/// the oldest note.
+fn migrate_v5_to_v6(doc: &mut Map<String, Value>) {
+    if let Some(Value::Array(instruments)) = doc.get_mut("instruments") {
+        for instr in instruments.iter_mut().filter_map(Value::as_object_mut) {
+            instr.entry("stealing").or_insert_with(|| json!("Oldest"));
+        }
+    }
+}
+
+/// version 7 added mono instruments. every older instrument is polyphonic.
+fn migrate_v6_to_v7(doc: &mut Map<String, Value>) {
+    if let Some(Value::Array(instruments)) = doc.get_mut("instruments") {
+        for instr in instruments.iter_mut().filter_map(Value::as_object_mut) {
+            instr.entry("mono").or_insert_with(|| json!({ "enabled": false, "legato": false, "glide": 0.0 }));
+        }
+    }
+}
+
+/// version 8 added LFOs. older instruments get two which don't move any of
+/// their operators.
+fn migrate_v7_to_v8(doc: &mut Map<String, Value>) {
+    let lfo = json!({ "shape": "Sine", "rate": 5.0, "sync": false, "delay": 0.0, "fade": 0.0, "key_sync": false });
+    let depths = json!({ "pitch": 0.0, "amplitude": 0.0, "send": 0.0, "receive": 0.0 });
+    
+    if let Some(Value::Array(instruments)) = doc.get_mut("instruments") {
+        for instr in instruments.iter_mut().filter_map(Value::as_object_mut) {
+            instr.entry("lfos").or_insert_with(|| json!([lfo, lfo]));
+            
+            if let Some(Value::Array(operators)) = instr.get_mut("operators") {
+                for op in operators.iter_mut().filter_map(Value::as_object_mut) {
+                    op.entry("lfos").or_insert_with(|| json!([depths, depths]));
+                }
+            }
+        }
+    }
+}
+
+/// version 9 added the modulation matrix. older instruments get an empty one.
+fn migrate_v8_to_v9(doc: &mut Map<String, Value>) {
+    let route = json!({ "source": "Velocity", "destination": { "Transpose": 0 }, "amount": 0.0 });
+    
+    if let Some(Value::Array(instruments)) = doc.get_mut("instruments") {
+        for instr in instruments.iter_mut().filter_map(Value::as_object_mut) {
+            instr.entry("routes").or_insert_with(|| Value::Array(vec![route.clone(); 8]));
+        }
+    }
+}
+
+/// version 10 added band-limited waves. older operators keep their sharp
+/// corners, so that they sound the same as they did.
+fn migrate_v9_to_v10(doc: &mut Map<String, Value>) {
+    if let Some(Value::Array(instruments)) = doc.get_mut("instruments") {
+        for instr in instruments.iter_mut().filter_map(Value::as_object_mut) {
+            if let Some(Value::Array(operators)) = instr.get_mut("operators") {
+                for op in operators.iter_mut().filter_map(Value::as_object_mut) {
+                    op.entry("band_limited").or_insert(json!(false));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mixer::Channel;
+    
+    const TEST_SONG: &str = include_str!("../songs/test.crz");
+    
+    #[test]
+    fn loads_test_song() {
+        let song = Song::load(TEST_SONG.as_bytes()).unwrap();
+        
+        assert_eq!(song.bpm, 60);
+        assert!(song.meter == Meter::new(4, 4));
+        assert_eq!(song.parts.iter().map(|p| p.len()).collect::<Vec<_>>(), vec![32, 0, 0, 0]);
+        assert!(song.instruments.is_empty());
+    }
+    
+    #[test]
+    fn migrates_v1() {
+        let v1 = json!({
+            "version": 1,
+            "bpm": 120,
+            "beats_per_bar": 3,
+            "parts": [[{ "pitch": 60, "start": { "beat": 1, "division": 48 }, "duration": 96, "velocity": 0.5 }]],
+        });
+        
+        let song = Song::from_value(v1).unwrap();
+        
+        assert_eq!(song.bpm, 120);
+        assert!(song.meter == Meter::new(3, 4));
+        assert!(song.meter_changes.is_empty());
+        assert_eq!(song.parts[0][0].start.division, 48);
+        assert!(song.instruments.is_empty());
+        assert!(song.tempo_changes.is_empty());
+        assert!(song.mixer.channels.is_empty());
+    }
+    
+    #[test]
+    fn widens_v4_effects() {
+        let v4 = json!({
+            "version": 4,
+            "bpm": 60,
+            "meter": { "numerator": 4, "denominator": 4 },
+            "parts": [[]],
+            "instruments": [{
+                "num_voices": 2,
+                "operators": [],
+                "effects": [
+                    { "type": "Echo", "length": 100, "amount": 0.5, "feedback": 0.25 },
+                    { "type": "Reverb", "mix": 0.5, "gain": 1.0, "in_gain": [1.0, 1.0, 1.0, 1.0], "out_gain": [1.0, 1.0, 1.0, 1.0] },
+                ],
+            }],
+        });
+        
+        let song = Song::from_value(v4).unwrap();
+        assert!(song.mixer.channels.is_empty());
+        assert!(matches!(song.instruments[0].effects[0], synth::effect::EffectSpec::Echo { width, .. } if width == 1.0));
+        assert!(matches!(song.instruments[0].effects[1], synth::effect::EffectSpec::Reverb { width, .. } if width == 1.0));
+    }
+    
+    #[test]
+    fn moves_v4_instrument_pans_into_the_mixer() {
+        let instrument = |pan: Option<f64>| {
+            let mut instr = json!({ "num_voices": 2, "operators": [], "effects": [] });
+            
+            if let Some(pan) = pan {
+                instr["pan"] = json!(pan);
+            }
+            
+            instr
+        };
+        
+        let v4 = json!({
+            "version": 4,
+            "bpm": 60,
+            "meter": { "numerator": 4, "denominator": 4 },
+            "parts": [[], [], []],
+            "instruments": [instrument(Some(-0.5)), instrument(None), instrument(Some(0.75))],
+        });
+        
+        let song = Song::from_value(v4).unwrap();
+        assert_eq!(song.mixer.channels.len(), 3);
+        assert!(song.mixer.channel(0) == Channel { pan: -0.5, ..Channel::default() });
+        assert!(song.mixer.channel(1) == Channel::default());
+        assert!(song.mixer.channel(2) == Channel { pan: 0.75, ..Channel::default() });
+    }
+    
+    #[test]
+    fn keeps_old_operators_sharp() {
+        let mut doc = json!({ "instruments": [{ "operators": [{ "wave": "Square" }] }] });
+        migrate_v9_to_v10(doc.as_object_mut().unwrap());
+        assert_eq!(doc["instruments"][0]["operators"][0]["band_limited"], json!(false));
+    }
+    
+    #[test]
+    fn migrates_v5_instruments() {
+        let v5 = json!({
+            "version": 5,
+            "bpm": 60,
+            "meter": { "numerator": 4, "denominator": 4 },
+            "parts": [[]],
+            "instruments": [{ "num_voices": 2, "operators": [], "effects": [] }],
         });
+        
+        let song = Song::from_value(v5).unwrap();
+        assert_eq!(song.instruments[0].num_voices, 2);
+        assert!(song.instruments[0].stealing == synth::StealPolicy::Oldest);
+        assert!(song.instruments[0].mono == synth::Mono::default());
+        assert!(song.instruments[0].lfos == [synth::lfo::Lfo::new(); synth::lfo::MAX_LFOS]);
+        assert!(song.instruments[0].routes.iter().all(|r| !r.is_active()));
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn finds_problems_which_would_stop_a_song_playing() {
+        let mut song = Song::new(NUM_PARTS, 60, Meter::new(4, 4));
+        song.add_note(0, Note::new(40, 0, 0, 24, 1.0));
+        assert!(song.validate().is_empty());
+        
+        song.add_note(1, Note::new(40, u32::MAX / BEAT_DIVISIONS, 0, BEAT_DIVISIONS, 1.0));
+        song.instruments.push(synth::Instrument::new(1).spec());
+        song.instruments[0].effects.push(EffectSpec::Delay { length: 0, ratio: 0.5 });
+        song.parts.pop();
+        
+        assert_eq!(song.validate(), [
+            String::from("has 3 parts, rather than 4"),
+            String::from("part 2, note 1: ends too late"),
+            format!("instrument 1, effect 1: length must be from 1 to {} samples", MAX_DELAY_LENGTH),
+        ]);
+    }
+    
+    #[test]
+    fn round_trips() {
+        let mut song = Song::new(2, 90, Meter::new(6, 8));
+        song.add_note(1, Note::new(40, 2, 0, 24, 0.75));
+        song.mixer.channel_mut(1).solo = true;
+        song.mixer.channel_mut(1).pan = -0.25;
+        
+        let mut buf = Vec::new();
+        song.save(&mut buf).unwrap();
+        
+        let doc: Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(doc["version"], json!(FORMAT_VERSION));
+        
+        let loaded = Song::load(&buf[..]).unwrap();
+        assert_eq!(loaded.bpm, 90);
+        assert!(loaded.meter == Meter::new(6, 8));
+        assert_eq!(loaded.parts[1][0].pitch, 40);
+        assert_eq!(loaded.parts[1][0].duration, 24);
+        assert!(loaded.mixer.channel(0) == Channel::default());
+        assert!(loaded.mixer.channel(1) == Channel { gain: 1.0, pan: -0.25, mute: false, solo: true });
+    }
+    
+    #[test]
+    fn follows_tempo_changes() {
+        let tempo = TempoMap::new(60.0, &[
+            TempoChange { time: Time::new(8, 0), bpm: 60.0, ramp: false },
+            TempoChange { time: Time::new(4, 0), bpm: 120.0, ramp: true },
+            TempoChange { time: Time::new(6, 0), bpm: 30.0, ramp: false },
+        ]);
+        
+        // from 60bpm to 120bpm over four beats, then two beats at 120bpm, two
+        // at 30bpm, and back to 60bpm.
+        let ramp = 4.0 * 2.0f64.ln();
+        
+        assert!((tempo.seconds_at(2.0) - 4.0 * 1.5f64.ln()).abs() < 1e-9);
+        assert!((tempo.seconds_at(4.0) - ramp).abs() < 1e-9);
+        assert!((tempo.seconds_at(7.0) - (ramp + 1.0 + 2.0)).abs() < 1e-9);
+        assert!((tempo.seconds_at(9.0) - (ramp + 1.0 + 4.0 + 1.0)).abs() < 1e-9);
+        assert!((tempo.bpm_at(3.0) - 105.0).abs() < 1e-9);
+        assert!((tempo.bpm_at(5.0) - 120.0).abs() < 1e-9);
+        
+        for &beat in &[0.0, 0.5, 3.9, 4.0, 5.5, 6.25, 100.0] {
+            assert!((tempo.beats_at(tempo.seconds_at(beat)) - beat).abs() < 1e-9);
+        }
+    }
+    
+    #[test]
+    fn counts_bars_across_meter_changes() {
+        let meters = MeterMap::new(Meter::new(6, 8), &[
+            MeterChange { time: Time::new(7, 0), meter: Meter::new(7, 8) },
+            MeterChange { time: Time::new(12, 48), meter: Meter::new(4, 4) },
+        ]);
+        
+        // bars of 6/8 last three beats, so the third one is cut short at beat 7.
+        assert_eq!(meters.position(Time::new(0, 0)), (0, 0));
+        assert_eq!(meters.position(Time::new(4, 48)), (1, 144));
+        assert_eq!(meters.position(Time::new(6, 95)), (2, 95));
+        assert_eq!(meters.position(Time::new(7, 0)), (3, 0));
+        
+        // bars of 7/8 last three and a half beats.
+        assert_eq!(meters.position(Time::new(10, 48)), (4, 0));
+        assert_eq!(meters.position(Time::new(12, 48)), (5, 0));
+        assert_eq!(meters.position(Time::new(17, 0)), (6, 48));
+        assert!(meters.meter_at(Time::new(12, 47)) == Meter::new(7, 8));
+    }
+    
+    #[test]
+    fn rejects_newer_versions() {
+        let doc = json!({ "version": FORMAT_VERSION + 1, "bpm": 60 });
+        assert!(matches!(Song::from_value(doc), Err(LoadError::TooNew(v)) if v == FORMAT_VERSION + 1));
+    }
+    
+    #[test]
+    fn rejects_bad_versions() {
+        assert!(matches!(Song::from_value(json!({ "version": 0 })), Err(LoadError::BadVersion(_))));
+        assert!(matches!(Song::from_value(json!({ "version": "two" })), Err(LoadError::BadVersion(_))));
+        assert!(matches!(Song::from_value(json!([])), Err(LoadError::NotAnObject)));
+    }
+}
@@ -1,38 +1,199 @@
 use std::f64::consts::PI;
 
-/// an effect, used to process a signal sample-by-sample.
+use serde::{Serialize, Deserialize};
+
+use super::Stereo;
+
+/// the longest an echo or delay can be, in samples.
+pub const MAX_DELAY_LENGTH: usize = 1 << 20;
+
+/// the most an echo's feedback can be, however it's modulated. any more, and
+/// the echoes would take too long to die away, or never would.
+const MAX_FEEDBACK: f32 = 0.95;
+
+/// an effect, used to process a stereo signal sample-by-sample.
 pub trait Effect : Send {
-    /// processes a single audio sample, and updates the internal state.
-    fn process(&mut self, sample: f32) -> f32;
+    /// processes a single stereo sample, and updates the internal state.
+    fn process(&mut self, sample: Stereo) -> Stereo;
     
     /// resets an effect's internal state.
     fn reset(&mut self);
+    
+    /// makes a boxed copy of the effect, including its internal state.
+    fn clone_box(&self) -> Box<dyn Effect>;
+    
+    /// describes the effect's parameters (but not its internal state) in a
+    /// form which can be stored in a song file.
+    fn spec(&self) -> EffectSpec;
+    
+    /// gets the names of the parameters which the modulation matrix can move.
+    fn params(&self) -> &'static [&'static str] {
+        &[]
+    }
+    
+    /// moves one of the parameters listed by `params` by `by` from where it's
+    /// set, until it's moved again.
+    fn modulate(&mut self, _param: usize, _by: f32) {}
+}
+
+/// a serialisable description of an effect and its parameters, from which
+/// the effect can be rebuilt.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum EffectSpec {
+    Echo {
+        length: usize,
+        amount: f32,
+        feedback: f32,
+        
+        width: f32,
+    },
+    
+    EQ {
+        biquads: Vec<Biquad>,
+    },
+    
+    Biquad(Biquad),
+    
+    Reverb {
+        mix: f32,
+        gain: f32,
+        in_gain: [f32; 4],
+        out_gain: [f32; 4],
+        
+        width: f32,
+    },
+    
+    Delay {
+        length: usize,
+        ratio: f32,
+    },
+}
+
+impl EffectSpec {
+    /// constructs a new effect, with a fresh internal state, from the spec.
+    pub fn build(&self) -> Box<dyn Effect> {
+        match self {
+            EffectSpec::Echo { length, amount, feedback, width } => {
+                let mut echo = Echo::new(*length, *amount);
+                echo.feedback = *feedback;
+                echo.width = *width;
+                Box::new(echo)
+            },
+            EffectSpec::EQ { biquads } => {
+                let mut eq = EQ { biquads: biquads.clone() };
+                eq.reset();
+                Box::new(eq)
+            },
+            EffectSpec::Biquad(bq) => {
+                let mut bq = *bq;
+                bq.reset();
+                Box::new(bq)
+            },
+            EffectSpec::Reverb { mix, gain, in_gain, out_gain, width } => {
+                let mut reverb = Reverb::new(*mix, *gain);
+                reverb.in_gain = *in_gain;
+                reverb.out_gain = *out_gain;
+                reverb.width = *width;
+                Box::new(reverb)
+            },
+            EffectSpec::Delay { length, ratio } => Box::new(Delay::new(*length, *ratio)),
+        }
+    }
+}
+
+impl Clone for Box<dyn Effect> {
+    fn clone(&self) -> Box<dyn Effect> {
+        self.clone_box()
+    }
 }
 
+/// an echo, with a delay line for each channel. the wider it is, the more
+/// each echo bounces across to the other side (a "ping-pong" echo.)
+#[derive(Clone)]
 pub struct Echo {
     pub amount: f32,
-    delay: Delay,
+    
+    /// how much of each echo is fed back into the next one.
+    pub feedback: f32,
+    
+    /// from 0.0, where each channel echoes by itself, to 1.0, where every
+    /// echo comes from the opposite side to the last.
+    pub width: f32,
+    
+    /// how far the amount, feedback and width are moved by modulation.
+    offsets: [f32; 3],
+    
+    lines: [DelayLine; 2],
 }
 
 impl Echo {
     pub fn new(length: usize, amount: f32) -> Echo {
         Echo {
             amount,
-            delay: Delay::new(length, amount),
+            feedback: amount,
+            width: 1.0,
+            offsets: [0.0; 3],
+            lines: [DelayLine::new(length, 0.0), DelayLine::new(length, 0.0)],
         }
     }
 }
 
 impl Effect for Echo {
-    fn process(&mut self, sample: f32) -> f32 {
-        self.delay.push(sample * self.amount) + sample
+    fn process(&mut self, sample: Stereo) -> Stereo {
+        let [left, right] = sample;
+        let echo = [self.lines[0].peek(), self.lines[1].peek()];
+        
+        let amount = (self.amount + self.offsets[0]).max(0.0);
+        let feedback = (self.feedback + self.offsets[1]).clamp(0.0, MAX_FEEDBACK);
+        let width = (self.width + self.offsets[2]).clamp(0.0, 1.0);
+        
+        // a wide echo sends everything into the left line, so that it can
+        // bounce to the right and back again from there.
+        let mid = (left + right) / 2.0;
+        let input = [
+            left + (mid - left) * width,
+            right * (1.0 - width),
+        ];
+        
+        for i in 0..2 {
+            let (own, other) = (echo[i], echo[1 - i]);
+            let fed_back = own + (other - own) * width;
+            self.lines[i].push(input[i] * amount + fed_back * feedback);
+        }
+        
+        [left + echo[0], right + echo[1]]
     }
 
     fn reset(&mut self) {
-        self.delay.reset();
+        self.lines.iter_mut().for_each(|l| l.reset());
+    }
+    
+    fn clone_box(&self) -> Box<dyn Effect> {
+        Box::new(self.clone())
+    }
+    
+    fn spec(&self) -> EffectSpec {
+        EffectSpec::Echo {
+            length: self.lines[0].len(),
+            amount: self.amount,
+            feedback: self.feedback,
+            width: self.width,
+        }
+    }
+    
+    fn params(&self) -> &'static [&'static str] {
+        &["amount", "feedback", "width"]
+    }
+    
+    fn modulate(&mut self, param: usize, by: f32) {
+        if let Some(offset) = self.offsets.get_mut(param) {
+            *offset = by;
+        }
     }
 }
 
+#[derive(Clone)]
 pub struct EQ {
     pub biquads: Vec<Biquad>,
 }
@@ -45,8 +206,14 @@ impl EQ {
     }
 }
 
+impl Default for EQ {
+    fn default() -> EQ {
+        EQ::new()
+    }
+}
+
 impl Effect for EQ {
-    fn process(&mut self, sample: f32) -> f32 {
+    fn process(&mut self, sample: Stereo) -> Stereo {
         self.biquads
             .iter_mut()
             .fold(sample, |s, eff| eff.process(s))
@@ -55,16 +222,31 @@ impl Effect for EQ {
     fn reset(&mut self) {
         self.biquads.iter_mut().for_each(|b| b.reset());
     }
+    
+    fn clone_box(&self) -> Box<dyn Effect> {
+        Box::new(self.clone())
+    }
+    
+    fn spec(&self) -> EffectSpec {
+        EffectSpec::EQ {
+            biquads: self.biquads.clone(),
+        }
+    }
 }
 
 /// a biquad filter, able to take the form of many LTI filters including
-/// the filters required for EQ (low-pass, high-pass, etc.)
-#[derive(Clone, Copy)]
+/// the filters required for EQ (low-pass, high-pass, etc.) each channel is
+/// filtered separately.
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct Biquad {
     pub a: [f64; 3],
     pub b: [f64; 3],
-    x: [f64; 3],
-    y: [f64; 3],
+    
+    #[serde(skip)]
+    x: [[f64; 3]; 2],
+    
+    #[serde(skip)]
+    y: [[f64; 3]; 2],
 }
 
 impl Biquad {
@@ -72,8 +254,8 @@ impl Biquad {
         Biquad {
             a: [1.0, a1 / a0, a2 / a0],
             b: [b0 / a0, b1 / a0, b2 / a0],
-            x: [0.0, 0.0, 0.0],
-            y: [0.0, 0.0, 0.0],
+            x: [[0.0; 3]; 2],
+            y: [[0.0; 3]; 2],
         }
     }
     
@@ -130,39 +312,67 @@ impl Biquad {
             1.0 - alpha * sqrt_gain,
         )
     }
-}
-
-impl Effect for Biquad {
-    fn process(&mut self, sample: f32) -> f32 {
-        self.x[2] = self.x[1];
-        self.x[1] = self.x[0];
-        self.x[0] = sample as f64;
+    
+    /// filters the next sample of one channel.
+    fn filter(&mut self, channel: usize, sample: f32) -> f32 {
+        let x = &mut self.x[channel];
+        let y = &mut self.y[channel];
+        
+        x[2] = x[1];
+        x[1] = x[0];
+        x[0] = sample as f64;
         
         let y0
-            = self.b[0] * self.x[0]
-            + self.b[1] * self.x[1]
-            + self.b[2] * self.x[2]
-            - self.a[1] * self.y[0]
-            - self.a[2] * self.y[1];
+            = self.b[0] * x[0]
+            + self.b[1] * x[1]
+            + self.b[2] * x[2]
+            - self.a[1] * y[0]
+            - self.a[2] * y[1];
         
-        self.y[2] = self.y[1];
-        self.y[1] = self.y[0];
-        self.y[0] = y0;
+        y[2] = y[1];
+        y[1] = y[0];
+        y[0] = y0;
         
         y0 as f32
     }
+}
+
+impl Effect for Biquad {
+    fn process(&mut self, sample: Stereo) -> Stereo {
+        [self.filter(0, sample[0]), self.filter(1, sample[1])]
+    }
 
     fn reset(&mut self) {
-        self.x = [0.0, 0.0, 0.0];
-        self.y = [0.0, 0.0, 0.0];
+        self.x = [[0.0; 3]; 2];
+        self.y = [[0.0; 3]; 2];
+    }
+    
+    fn clone_box(&self) -> Box<dyn Effect> {
+        Box::new(*self)
+    }
+    
+    fn spec(&self) -> EffectSpec {
+        EffectSpec::Biquad(*self)
     }
 }
 
+/// a reverb built from a feedback delay network. the left channel feeds the
+/// first and third delay lines and the right channel feeds the others, and
+/// the two sides are heard from different combinations of them, which makes
+/// the reverb sound wide.
+#[derive(Clone)]
 pub struct Reverb {
     fdn: FeedbackDelayNetwork,
     pub in_gain: [f32; 4],
     pub out_gain: [f32; 4],
     pub mix: f32,
+    
+    /// from 0.0, where both channels hear the same reverb, to 1.0, where
+    /// they're as different as they can be.
+    pub width: f32,
+    
+    /// how far the mix and width are moved by modulation.
+    offsets: [f32; 2],
 }
 
 impl Reverb {
@@ -181,34 +391,73 @@ impl Reverb {
             in_gain: [ 0.4, 0.3, 0.2, 0.2 ],
             out_gain: [ 0.5, 0.5, 0.3, 0.1 ],
             mix,
+            width: 1.0,
+            offsets: [0.0; 2],
         }
     }
 }
 
 impl Effect for Reverb {
-    fn process(&mut self, sample: f32) -> f32 {
+    fn process(&mut self, sample: Stereo) -> Stereo {
+        let [left, right] = sample;
+        
         self.fdn.run([
-            sample * self.in_gain[0],
-            sample * self.in_gain[1],
-            sample * self.in_gain[2],
-            sample * self.in_gain[3],
+            left * self.in_gain[0],
+            right * self.in_gain[1],
+            left * self.in_gain[2],
+            right * self.in_gain[3],
         ]);
         
-        let out: f32 = (0..4)
-            .map(|i| self.fdn.output[i] * self.out_gain[i])
-            .sum();
+        let out: [f32; 4] = std::array::from_fn(|i| self.fdn.output[i] * self.out_gain[i]);
+        let mix = (self.mix + self.offsets[0]).clamp(0.0, 1.0);
+        let width = (self.width + self.offsets[1]).clamp(0.0, 1.0);
+        
+        // the right channel hears the second and fourth lines the other way
+        // up, so the two sides are different but still add up to the same
+        // thing as a narrow reverb would.
+        let shared = out[0] + out[2];
+        let split = out[1] + out[3];
+        let wet = [shared + split, shared + split * (1.0 - 2.0 * width)];
         
-        self.mix * out + (1.0 - self.mix) * sample
+        [
+            mix * wet[0] + (1.0 - mix) * left,
+            mix * wet[1] + (1.0 - mix) * right,
+        ]
     }
 
     fn reset(&mut self) {
         self.fdn.reset();
     }
+    
+    fn clone_box(&self) -> Box<dyn Effect> {
+        Box::new(self.clone())
+    }
+    
+    fn spec(&self) -> EffectSpec {
+        EffectSpec::Reverb {
+            mix: self.mix,
+            gain: self.fdn.feedback_gain[0],
+            in_gain: self.in_gain,
+            out_gain: self.out_gain,
+            width: self.width,
+        }
+    }
+    
+    fn params(&self) -> &'static [&'static str] {
+        &["mix", "width"]
+    }
+    
+    fn modulate(&mut self, param: usize, by: f32) {
+        if let Some(offset) = self.offsets.get_mut(param) {
+            *offset = by;
+        }
+    }
 }
 
 /// a four-channel feedback-delay-network. four inputs are given at each frame,
 /// and are delayed by different amounts. feedback from these delays is taken and
 /// added to the inputs before feeding into the delay lines.
+#[derive(Clone)]
 struct FeedbackDelayNetwork {
     /// the final gain of the four feedback lines, before adding with the new samples.
     feedback_gain: [f32; 4],
@@ -220,7 +469,7 @@ struct FeedbackDelayNetwork {
     feedback_filter: [Biquad; 4],
     
     /// the four delay lines.
-    delays: [Delay; 4],
+    delays: [DelayLine; 4],
     
     /// the latest output from each FDN channel.
     output: [f32; 4],
@@ -233,10 +482,10 @@ impl FeedbackDelayNetwork {
             feedback_matrix: FeedbackDelayNetwork::hadamard(),
             feedback_filter: [Biquad::lowpass(10000.0, 0.517, 1.0 / 44100.0); 4],
             delays: [
-                Delay::new(l0, 0.0),
-                Delay::new(l1, 0.0),
-                Delay::new(l2, 0.0),
-                Delay::new(l3, 0.0),
+                DelayLine::new(l0, 0.0),
+                DelayLine::new(l1, 0.0),
+                DelayLine::new(l2, 0.0),
+                DelayLine::new(l3, 0.0),
             ],
             output: [0.0, 0.0, 0.0, 0.0],
         }
@@ -249,15 +498,15 @@ impl FeedbackDelayNetwork {
             self.output[i] = self.delays[i].peek();
         }
         
-        for i in 0..4 {
-            fb[i] =
+        for (i, f) in fb.iter_mut().enumerate() {
+            *f =
                 ( self.feedback_matrix[i][0] * self.output[0]
                 + self.feedback_matrix[i][1] * self.output[1]
                 + self.feedback_matrix[i][2] * self.output[2]
                 + self.feedback_matrix[i][3] * self.output[3] )
                 * self.feedback_gain[i];
             
-            fb[i] = self.feedback_filter[i].process(fb[i]);
+            *f = self.feedback_filter[i].filter(0, *f);
         }
         
         for i in 0..4 {
@@ -281,7 +530,8 @@ impl FeedbackDelayNetwork {
 }
 
 /// a delay line, used for numerous effects
-struct Delay {
+#[derive(Clone)]
+struct DelayLine {
     /// the internal state of the delay line, implemented as a circular buffer.
     /// the buffer grows backwards, so line[head] is the next element and line[head+1]
     /// is the one after that.
@@ -295,10 +545,12 @@ struct Delay {
     ratio: f32,
 }
 
-impl Delay {
+impl DelayLine {
+    /// makes a delay line of `length` samples. it's always at least one
+    /// sample long, since an empty line would have nowhere to put anything.
     fn new(length: usize, ratio: f32) -> Self {
-        Delay {
-            line: std::iter::repeat(Default::default()).take(length).collect(),
+        DelayLine {
+            line: vec![0.0; length.clamp(1, MAX_DELAY_LENGTH)],
             head: 0,
             ratio,
         }
@@ -318,15 +570,99 @@ impl Delay {
     fn len(&self) -> usize {
         self.line.len()
     }
+    
+    fn reset(&mut self) {
+        self.line.iter_mut().for_each(|x| *x = 0.0);
+        self.head = 0;
+    }
+}
+
+/// a plain delay, with a separate line for each channel.
+#[derive(Clone)]
+struct Delay {
+    lines: [DelayLine; 2],
+}
+
+impl Delay {
+    fn new(length: usize, ratio: f32) -> Self {
+        Delay {
+            lines: [DelayLine::new(length, ratio), DelayLine::new(length, ratio)],
+        }
+    }
 }
 
 impl Effect for Delay {
-    fn process(&mut self, sample: f32) -> f32 {
-        self.push(sample)
+    fn process(&mut self, sample: Stereo) -> Stereo {
+        [self.lines[0].push(sample[0]), self.lines[1].push(sample[1])]
     }
 
     fn reset(&mut self) {
-        self.line.iter_mut().for_each(|x| *x = 0.0);
-        self.head = 0;
+        self.lines.iter_mut().for_each(|l| l.reset());
+    }
+    
+    fn clone_box(&self) -> Box<dyn Effect> {
+        Box::new(self.clone())
+    }
+    
+    fn spec(&self) -> EffectSpec {
+        EffectSpec::Delay {
+            length: self.lines[0].len(),
+            ratio: self.lines[0].ratio,
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// feeds a short burst of sound into the middle of an effect, and finds
+    /// the biggest difference between its two sides afterwards.
+    fn spread(effect: &mut dyn Effect) -> f32 {
+        (0..20000)
+            .map(|i| effect.process(if i < 100 { [1.0, 1.0] } else { [0.0, 0.0] }))
+            .map(|[left, right]| (left - right).abs())
+            .fold(0.0, f32::max)
+    }
+    
+    #[test]
+    fn wide_effects_differ_between_sides() {
+        let mut reverb = Reverb::new(0.5, 0.8);
+        let mut echo = Echo::new(2000, 0.5);
+        assert!(spread(&mut reverb) > 0.01);
+        assert!(spread(&mut echo) > 0.01);
+        
+        reverb.width = 0.0;
+        echo.width = 0.0;
+        reverb.reset();
+        echo.reset();
+        assert_eq!(spread(&mut reverb), 0.0);
+        assert_eq!(spread(&mut echo), 0.0);
+    }
+    
+    #[test]
+    fn empty_delays_are_built_with_room() {
+        for spec in [
+            EffectSpec::Echo { length: 0, amount: 0.5, feedback: 0.5, width: 1.0 },
+            EffectSpec::Delay { length: 0, ratio: 0.5 },
+        ] {
+            let mut effect = spec.build();
+            effect.process([1.0, 1.0]);
+            effect.process([0.0, 0.0]);
+        }
+    }
+    
+    #[test]
+    fn modulated_echoes_die_away() {
+        let mut echo = Echo::new(100, 0.5);
+        echo.modulate(1, 10.0);
+        
+        let tail = (0..20000)
+            .map(|i| echo.process(if i < 100 { [1.0, 1.0] } else { [0.0, 0.0] }))
+            .skip(19000)
+            .map(|[left, right]| left.abs().max(right.abs()))
+            .fold(0.0, f32::max);
+        
+        assert!(tail < 0.01);
+    }
+}
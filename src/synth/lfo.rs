@@ -0,0 +1,178 @@
+use std::f32::consts::PI;
+
+use serde::{Serialize, Deserialize};
+
+use super::WaveType;
+
+/// how many LFOs each instrument has.
+pub const MAX_LFOS: usize = 2;
+
+/// a low-frequency oscillator, which slowly moves the pitch, amplitude and
+/// connection levels of its instrument's operators, e.g. for vibrato or
+/// tremolo. how far it moves each operator is set by the operator's `Depths`.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Lfo {
+    pub shape: WaveType,
+    
+    /// how many cycles the LFO goes through each second, or each beat if it's
+    /// synced to the tempo.
+    pub rate: f32,
+    pub sync: bool,
+    
+    /// how long the LFO waits after a note starts before fading in, in seconds.
+    pub delay: f32,
+    
+    /// how long the LFO takes to fade in once the delay is over, in seconds.
+    pub fade: f32,
+    
+    /// whether the LFO starts from the beginning of its cycle with each note,
+    /// rather than running freely, so that every note is at the same point.
+    pub key_sync: bool,
+}
+
+/// how far an operator is moved by one of its instrument's LFOs.
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Depths {
+    /// how far the pitch moves either way, in semitones.
+    pub pitch: f32,
+    
+    /// how far the amplitude dips at the bottom of each cycle, from 0 to 1.
+    pub amplitude: f32,
+    
+    /// how far the operator's send and receive levels move either way, as a
+    /// fraction of themselves.
+    pub send: f32,
+    pub receive: f32,
+}
+
+/// what an operator's pitch, amplitude, sends and receives are multiplied by
+/// at some moment, going by the LFOs' outputs then.
+pub struct Factors {
+    pub freq: f32,
+    pub amplitude: f32,
+    pub send: f32,
+    pub receive: f32,
+}
+
+/// where each of an instrument's free-running LFOs is up to, in cycles, and
+/// how many cycles per second each one is going at.
+#[derive(Clone, Copy)]
+pub struct Clock {
+    pub phases: [f64; MAX_LFOS],
+    pub rates: [f64; MAX_LFOS],
+}
+
+impl Lfo {
+    pub fn new() -> Lfo {
+        Lfo {
+            shape: WaveType::Sine,
+            rate: 5.0,
+            sync: false,
+            delay: 0.0,
+            fade: 0.0,
+            key_sync: false,
+        }
+    }
+    
+    /// gets how many cycles the LFO goes through each second, at a tempo of
+    /// `bps` beats per second.
+    pub fn frequency(&self, bps: f64) -> f64 {
+        if self.sync {
+            self.rate as f64 * bps
+        } else {
+            self.rate as f64
+        }
+    }
+    
+    /// gets the LFO's output, from -1 to 1, at a phase (in cycles) of a note
+    /// which started `age` seconds ago. every shape but the square starts at
+    /// 0 and rises; the square starts at the top, and drops half way through.
+    pub fn value(&self, phase: f64, age: f64) -> f32 {
+        let p = phase.rem_euclid(1.0) as f32;
+        
+        let v = match self.shape {
+            WaveType::Sine => f32::sin(2.0 * PI * p),
+            WaveType::Square => if p < 0.5 { 1.0 } else { -1.0 },
+            WaveType::Triangle => 1.0 - 4.0 * f32::abs((p + 0.25).fract() - 0.5),
+            WaveType::Sawtooth => 2.0 * (p + 0.5).fract() - 1.0,
+        };
+        
+        v * self.level(age)
+    }
+    
+    /// gets how far the LFO has faded in, from 0 to 1, `age` seconds after a
+    /// note started.
+    fn level(&self, age: f64) -> f32 {
+        let age = age as f32 - self.delay;
+        
+        if age < 0.0 {
+            0.0
+        } else if self.fade <= 0.0 {
+            1.0
+        } else {
+            (age / self.fade).min(1.0)
+        }
+    }
+}
+
+impl Default for Lfo {
+    fn default() -> Lfo {
+        Lfo::new()
+    }
+}
+
+impl Factors {
+    /// the factors which leave an operator as it is.
+    pub const NONE: Factors = Factors { freq: 1.0, amplitude: 1.0, send: 1.0, receive: 1.0 };
+    
+    /// adds up what each LFO does to an operator, given how far it moves the
+    /// operator and what it's outputting.
+    pub fn new(depths: &[Depths; MAX_LFOS], values: &[f32; MAX_LFOS]) -> Factors {
+        let mut pitch = 0.0;
+        let mut amplitude = 1.0;
+        let mut send = 1.0;
+        let mut receive = 1.0;
+        
+        for (d, v) in depths.iter().zip(values) {
+            pitch += d.pitch * v;
+            amplitude *= 1.0 - d.amplitude * (1.0 - v) / 2.0;
+            send += d.send * v;
+            receive += d.receive * v;
+        }
+        
+        Factors {
+            freq: if pitch == 0.0 { 1.0 } else { 2.0f32.powf(pitch / 12.0) },
+            amplitude,
+            send: send.max(0.0),
+            receive: receive.max(0.0),
+        }
+    }
+}
+
+impl Clock {
+    /// moves every LFO on by `dt` seconds.
+    pub fn advance(&mut self, dt: f64) {
+        for (phase, rate) in self.phases.iter_mut().zip(self.rates) {
+            *phase = (*phase + rate * dt).rem_euclid(1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn fades_in_after_the_delay() {
+        let lfo = Lfo { shape: WaveType::Square, delay: 0.5, fade: 1.0, ..Lfo::new() };
+        
+        assert_eq!(lfo.value(0.1, 0.25), 0.0);
+        assert_eq!(lfo.value(0.1, 1.0), 0.5);
+        assert_eq!(lfo.value(0.6, 2.0), -1.0);
+        
+        // a tremolo of depth 1 goes all the way down to silence.
+        let depths = [Depths { amplitude: 1.0, ..Depths::default() }, Depths::default()];
+        assert_eq!(Factors::new(&depths, &[-1.0, 0.0]).amplitude, 0.0);
+        assert_eq!(Factors::new(&depths, &[1.0, 0.0]).amplitude, 1.0);
+    }
+}
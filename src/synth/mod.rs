@@ -1,14 +1,54 @@
 pub mod effect;
+pub mod lfo;
+pub mod modulation;
 
+use std::collections::VecDeque;
 use std::f32::consts::PI;
 
+use serde::{Serialize, Deserialize};
+
 use crate::song;
 
+use lfo::{Lfo, MAX_LFOS};
+use modulation::{Controls, Route, Sources, Targets, MAX_ROUTES};
+
 pub const NUM_CHANNELS: usize = 6;
 pub const MAX_OPERATORS: usize = 8;
 pub const HOLD_BUFFER_SIZE: usize = 256;
+pub const MAX_VOICES: usize = 32;
 
-#[derive(Clone, Copy)]
+/// how long a voice's note takes to fade out when the voice is stolen for
+/// another note, in seconds. it fades out in the voice's tail, so the new note
+/// doesn't wait for it, but it still shouldn't hang around for long.
+const STEAL_FADE: f32 = 0.005;
+
+/// how many notes held down at once a mono instrument keeps track of. past
+/// this, the one held the longest is forgotten.
+const MAX_HELD: usize = 16;
+
+/// how many notes can be scheduled on an instrument ahead of time, on top
+/// of the ones its voices are already playing.
+const MAX_SCHEDULED: usize = 256;
+
+/// how many events an instrument's queue has room for: a note-off for every
+/// note being played or held, and a note-on and a note-off for every note
+/// scheduled ahead of time. the queue never grows past this, so that it
+/// never allocates on the audio thread.
+const QUEUE_CAPACITY: usize = MAX_VOICES + MAX_HELD + 2 * MAX_SCHEDULED;
+
+/// how close, as a fraction of a sample, a note's start time has to be to a
+/// sample for it to start on that sample. this only makes up for rounding.
+const ONSET_TOLERANCE: f64 = 1.0e-3;
+
+/// a single sample of stereo audio, as `[left, right]`.
+pub type Stereo = [f32; 2];
+
+/// mixes a stereo sample down to mono.
+pub fn downmix(sample: Stereo) -> f32 {
+    (sample[0] + sample[1]) / 2.0
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 /// the type of wave for a synth to play. the default and most
 /// common is a sine wave, but others are available.
 pub enum WaveType {
@@ -36,9 +76,127 @@ impl WaveType {
             WaveType::Sawtooth => 3,
         }
     }
+    
+    /// gets the wave's value at a phase of `t` cycles, straight from its
+    /// shape. the sharp corners alias at high pitches.
+    pub fn sample(self, t: f64) -> f32 {
+        match self {
+            WaveType::Sine => -f64::cos(2.0 * std::f64::consts::PI * t) as f32,
+            WaveType::Square => 2.0 * ((2.0 * t) as u32 % 2) as f32 - 1.0,
+            WaveType::Triangle => 1.0 - 2.0 * f32::abs(2.0 * (t - t.floor()) as f32 - 1.0),
+            WaveType::Sawtooth => (t - t.floor()) as f32,
+        }
+    }
+    
+    /// gets the wave's value at a phase of `t` cycles, going up by `inc`
+    /// cycles each sample. the corners are smoothed over the samples either
+    /// side of them (with PolyBLEP for jumps, and PolyBLAMP for bends), which
+    /// takes away most of the aliasing.
+    pub fn sample_band_limited(self, t: f64, inc: f64) -> f32 {
+        if self == WaveType::Sine || inc <= 0.0 {
+            return self.sample(t);
+        }
+        
+        let inc = inc.min(0.5);
+        let p = t.rem_euclid(1.0);
+        let half = (p + 0.5).fract();
+        
+        (match self {
+            WaveType::Square => (if p < 0.5 { -1.0 } else { 1.0 }) + poly_blep(half, inc) - poly_blep(p, inc),
+            WaveType::Triangle => 1.0 - 2.0 * f64::abs(2.0 * p - 1.0) + 4.0 * inc * (poly_blamp(p, inc) - poly_blamp(half, inc)),
+            _ => p - 0.5 * poly_blep(p, inc),
+        }) as f32
+    }
+}
+
+/// the difference between a band-limited jump from -1 to 1 at a phase of 0
+/// and a sharp one, `p` cycles afterwards.
+fn poly_blep(p: f64, inc: f64) -> f64 {
+    if p < inc {
+        let x = p / inc;
+        2.0 * x - x * x - 1.0
+    } else if p > 1.0 - inc {
+        let x = (p - 1.0) / inc;
+        x * x + 2.0 * x + 1.0
+    } else {
+        0.0
+    }
 }
 
-#[derive(Clone, Copy, PartialEq)]
+/// the difference between a band-limited bend (where the slope goes up by 2
+/// per sample) at a phase of 0 and a sharp one, `p` cycles afterwards.
+fn poly_blamp(p: f64, inc: f64) -> f64 {
+    if p < inc {
+        let x = p / inc - 1.0;
+        -x * x * x / 3.0
+    } else if p > 1.0 - inc {
+        let x = (p - 1.0) / inc + 1.0;
+        x * x * x / 3.0
+    } else {
+        0.0
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+/// which voice an instrument gives up for a new note when all of them are
+/// busy. voices which have already been let go of are always taken first.
+pub enum StealPolicy {
+    /// takes the voice whose note started longest ago.
+    #[default]
+    Oldest,
+    
+    /// takes the voice which is currently quietest.
+    Quietest,
+    
+    /// takes the voice playing the lowest note.
+    Lowest,
+    
+    /// takes the voice playing the highest note.
+    Highest,
+    
+    /// never takes a voice whose note is still held, dropping the new note
+    /// instead.
+    Never,
+}
+
+impl StealPolicy {
+    pub fn from(i: u32) -> StealPolicy {
+        match i % 5 {
+            0 => Self::Oldest,
+            1 => Self::Quietest,
+            2 => Self::Lowest,
+            3 => Self::Highest,
+            _ => Self::Never,
+        }
+    }
+    
+    pub fn to_u32(self) -> u32 {
+        match self {
+            StealPolicy::Oldest => 0,
+            StealPolicy::Quietest => 1,
+            StealPolicy::Lowest => 2,
+            StealPolicy::Highest => 3,
+            StealPolicy::Never => 4,
+        }
+    }
+}
+
+/// whether an instrument plays only one note at a time, and if so, how it
+/// moves from one note to the next while they overlap.
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Mono {
+    pub enabled: bool,
+    
+    /// whether a note played while another is held carries on through the
+    /// same envelopes, rather than starting them again.
+    pub legato: bool,
+    
+    /// how long the pitch takes to slide from one held note to the next, in
+    /// seconds.
+    pub glide: f32,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 /// the way in which a receipt from a channel should be computed.
 /// normally, the channel is simply added to the phase (after being
 /// multiplied by the delta time), but for modulation, it is also
@@ -50,7 +208,9 @@ pub enum ReceiveKind {
     /// frequency modulation.
     Modulate,
     
-    /// vibrato. functionally identical to normal.
+    /// vibrato. functionally identical to normal, and kept so that songs
+    /// which use it still load and sound the same. the instrument's LFOs are
+    /// what actually give vibrato.
     Vibrato,
 }
 
@@ -81,22 +241,45 @@ struct PlayedNote {
     freq: f32,
     velocity: f32,
     start: f64,
-    duration: f64,
+    
+    /// identifies the note, so that its note-off can find it again even if
+    /// another note of the same pitch has started since. notes played live
+    /// have no id, and are let go of by pitch.
+    id: Option<u64>,
+}
+
+/// something which happens to an instrument's notes at a particular time.
+#[derive(Clone)]
+enum NoteEvent {
+    On(PlayedNote),
+    
+    /// lets go of the note with the given pitch and id.
+    Off {
+        pitch: u32,
+        id: Option<u64>,
+    },
+}
+
+/// finds the frame of a block starting at `time` on which something happening
+/// at `start` should take effect, i.e. the first frame at or after `start`.
+/// the result is negative if `start` has already passed.
+pub fn onset_frame(start: f64, time: f64, dt: f64) -> f64 {
+    ((start - time) / dt - ONSET_TOLERANCE).ceil()
 }
 
 impl PlayedNote {
-    fn from(note: song::Note, bps: f64) -> PlayedNote {
+    fn new(pitch: u32, velocity: f32, start: f64, id: Option<u64>) -> PlayedNote {
         PlayedNote {
-            pitch: note.pitch,
-            freq: note.freq(),
-            velocity: note.velocity,
-            start: note.start_time(bps),
-            duration: note.real_duration(bps),
+            pitch,
+            freq: song::C0 * 2.0f32.powf(pitch as f32 / 12.0),
+            velocity,
+            start,
+            id,
         }
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Connection {
     pub kind: ReceiveKind,
     pub receive: f32,
@@ -109,23 +292,125 @@ pub struct Voice {
     channels: [f32; NUM_CHANNELS],
     channels_back: [f32; NUM_CHANNELS],
     phases: [f32; MAX_OPERATORS],
+    
+    /// how far through its wave each operator is, in cycles. these carry on
+    /// from note to note, so the sound stays smooth when the pitch changes.
+    carriers: [f64; MAX_OPERATORS],
     note: PlayedNote,
+    
+    /// whether the voice has been given a note since it was made or silenced.
+    /// pitch 0 is a real note, so it can't stand in for this.
+    sounding: bool,
+    
+    /// the frequency being played, which only differs from the note's while
+    /// gliding towards it.
+    freq: f32,
+    
+    /// where each LFO is up to, in cycles, for the ones which start again
+    /// with every note.
+    lfo_phases: [f64; MAX_LFOS],
+    
+    /// how fast the frequency is gliding, as the natural logarithm of how
+    /// much it's multiplied by each second, or zero once it's arrived.
+    glide: f32,
+    
+    /// when the note was let go of, in seconds, or infinity while it's held.
+    released: f64,
+    
+    /// where each operator is up to in its envelope.
+    envelopes: [EnvelopeState; MAX_OPERATORS],
+    
+    /// the value of every modulation source as of the voice's last frame.
+    sources: Sources,
+    
+    /// how far the modulation matrix is stretching each operator's release,
+    /// for when the note is let go of.
+    stretch: [f32; MAX_OPERATORS],
 }
 
 /// collects together a number of identical synths, and allocates
 /// notes between them. each voice acts upon the same hold buffer.
+#[derive(Clone)]
 pub struct Instrument {
+    /// there are always MAX_VOICES voices, so that the polyphony can change
+    /// while playing, but only the first `num_voices` are given new notes.
     voices: Vec<Voice>,
+    num_voices: usize,
+    
+    /// where each voice's old note fades out when the voice is stolen, while
+    /// the voice itself starts the new one.
+    tails: Vec<Voice>,
+    pub stealing: StealPolicy,
+    pub mono: Mono,
+    pub lfos: [Lfo; MAX_LFOS],
+    pub routes: [Route; MAX_ROUTES],
+    
+    /// the live controllers, which aren't saved with the instrument.
+    pub controls: Controls,
     pub operators: Vec<Operator>,
     pub effects: Vec<Box<dyn effect::Effect>>,
     
-    pub hold_buf: [f32; HOLD_BUFFER_SIZE],
-    hold_buf_back: [f32; HOLD_BUFFER_SIZE],
+    pub hold_buf: [Stereo; HOLD_BUFFER_SIZE],
+    hold_buf_back: [Stereo; HOLD_BUFFER_SIZE],
+    
+    /// the voices are mixed together here, in mono, before the effects.
+    dry_buf: [f32; HOLD_BUFFER_SIZE],
     hold_index: usize,
+    hold_len: usize,
+    
+    /// note-ons and note-offs which haven't happened yet, along with the
+    /// times they happen at, in order.
+    queue: EventQueue,
+    
+    /// the id to give the next note which is scheduled.
+    next_id: u64,
+    
+    /// the notes held down on a mono instrument, most recent last, so that
+    /// letting go of one can go back to the one before. only the first
+    /// `num_held` are really held; there's a fixed number of them so that
+    /// holding another never allocates.
+    held: [PlayedNote; MAX_HELD],
+    num_held: usize,
+    
+    /// where each free-running LFO is up to, in cycles.
+    lfo_phases: [f64; MAX_LFOS],
+    
+    /// the tempo, in beats per second, which synced LFOs follow.
+    bps: f64,
+}
+
+/// an instrument's queue of note events. it's made with room for
+/// QUEUE_CAPACITY events, and so is every copy of it, so that it can be
+/// filled on the audio thread without ever growing.
+struct EventQueue(VecDeque<(f64, NoteEvent)>);
+
+/// what an instrument's voices need to know about it to play a block.
+struct Patch<'a> {
+    ops: &'a [Operator],
+    
+    /// the LFOs, if any operator or route uses them.
+    lfos: Option<&'a [Lfo; MAX_LFOS]>,
+    
+    /// the modulation matrix, if any of its routes do anything.
+    routes: Option<&'a [Route; MAX_ROUTES]>,
+    controls: Controls,
+}
+
+/// a serialisable description of an instrument: its operators and effects,
+/// and how many voices it has, but none of its playback state.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct InstrumentSpec {
+    pub num_voices: usize,
+    pub stealing: StealPolicy,
+    pub mono: Mono,
+    pub lfos: [Lfo; MAX_LFOS],
+    pub routes: [Route; MAX_ROUTES],
+    pub operators: Vec<Operator>,
+    pub effects: Vec<effect::EffectSpec>,
 }
 
 /// an FM operator, which sends to and receives from channels, and outputs a frequency.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Operator {
     pub connections: [Connection; NUM_CHANNELS],
     
@@ -140,10 +425,17 @@ pub struct Operator {
     
     /// the envelope which the operator's output amplitude follows.
     pub envelope: Envelope,
+    
+    /// how far each of the instrument's LFOs moves the operator.
+    pub lfos: [lfo::Depths; MAX_LFOS],
+    
+    /// whether square, triangle and sawtooth waves are band-limited, so they
+    /// don't alias at high pitches.
+    pub band_limited: bool,
 }
 
 /// a parameterised ADSR envelope.
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Envelope {
     pub attack: f32,
     pub decay: f32,
@@ -151,10 +443,53 @@ pub struct Envelope {
     pub release: f32,
 }
 
+/// the stages which a note goes through in an envelope.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Idle,
+}
+
+/// how far a note has got through an envelope. the envelope only says how
+/// long each stage lasts, so the state keeps track of the level itself, and
+/// every stage carries on from wherever the last one left off.
+#[derive(Clone, Copy)]
+pub struct EnvelopeState {
+    pub stage: Stage,
+    pub level: f32,
+    
+    /// how far the level falls each second during the release.
+    release_rate: f32,
+}
+
+/// replaces the first few instruments with ones built from the given specs,
+/// adding new instruments if there are more specs than instruments.
+pub fn load_instruments(instruments: &mut Vec<Instrument>, specs: &[InstrumentSpec]) {
+    for (i, spec) in specs.iter().enumerate() {
+        let instr = Instrument::from_spec(spec);
+        
+        if i < instruments.len() {
+            instruments[i] = instr;
+        } else {
+            instruments.push(instr);
+        }
+    }
+}
+
 impl Instrument {
     pub fn new(num_voices: usize) -> Instrument {
         Instrument {
-            voices: vec![Voice::new(); num_voices],
+            voices: vec![Voice::new(); MAX_VOICES],
+            num_voices: num_voices.clamp(1, MAX_VOICES),
+            tails: vec![Voice::new(); MAX_VOICES],
+            stealing: StealPolicy::Oldest,
+            mono: Mono::default(),
+            lfos: [Lfo::new(); MAX_LFOS],
+            routes: [Route::new(); MAX_ROUTES],
+            controls: Controls::default(),
             operators: Vec::new(),
             effects: vec![
                 // Box::new(effect::Reverb::new(0.8, 0.95)),
@@ -163,43 +498,245 @@ impl Instrument {
                 // Box::new(effect::Biquad::peak(440.0, 1.0 / SQRT_2, 3.0, 1.0 / 44100.0))
             ],
             
-            hold_buf: [0.0; HOLD_BUFFER_SIZE],
-            hold_buf_back: [0.0; HOLD_BUFFER_SIZE],
+            hold_buf: [[0.0; 2]; HOLD_BUFFER_SIZE],
+            hold_buf_back: [[0.0; 2]; HOLD_BUFFER_SIZE],
+            dry_buf: [0.0; HOLD_BUFFER_SIZE],
             hold_index: HOLD_BUFFER_SIZE,
+            hold_len: HOLD_BUFFER_SIZE,
+            queue: EventQueue::new(),
+            next_id: 0,
+            held: std::array::from_fn(|_| PlayedNote::new(0, 0.0, 0.0, None)),
+            num_held: 0,
+            lfo_phases: [0.0; MAX_LFOS],
+            bps: 1.0,
+        }
+    }
+    
+    /// constructs a new instrument from a spec, with fresh voices and effects.
+    pub fn from_spec(spec: &InstrumentSpec) -> Instrument {
+        let mut instr = Instrument::new(spec.num_voices);
+        instr.stealing = spec.stealing;
+        instr.mono = spec.mono;
+        instr.lfos = spec.lfos;
+        instr.routes = spec.routes;
+        instr.operators = spec.operators.clone();
+        instr.effects = spec.effects.iter().map(|e| e.build()).collect();
+        instr
+    }
+    
+    /// describes the instrument's sound design in a serialisable form.
+    pub fn spec(&self) -> InstrumentSpec {
+        InstrumentSpec {
+            num_voices: self.num_voices,
+            stealing: self.stealing,
+            mono: self.mono,
+            lfos: self.lfos,
+            routes: self.routes,
+            operators: self.operators.clone(),
+            effects: self.effects.iter().map(|e| e.spec()).collect(),
+        }
+    }
+    
+    /// queues a note to be played. it will start on the first sample at or
+    /// after its start time, as long as it's scheduled before that sample is
+    /// rendered. notes which should already have started begin straight away.
+    /// its note-off is queued at the same time, for when the note ends.
+    /// if the queue is full, the note is dropped.
+    pub fn schedule(&mut self, note: song::Note, tempo: &song::TempoMap) {
+        if !self.queue.has_room(2) {
+            return;
+        }
+        
+        let id = Some(self.next_id);
+        self.next_id += 1;
+        
+        let start = note.start_time(tempo);
+        self.push(start, NoteEvent::On(PlayedNote::new(note.pitch, note.velocity, start, id)));
+        self.push(note.end_time(tempo), NoteEvent::Off { pitch: note.pitch, id });
+    }
+    
+    /// starts playing a note at `time`, which is held until `note_off` is
+    /// called with the same pitch, e.g. for playing the instrument live. if
+    /// the queue is full, or would be without room for the note-off, the
+    /// note is dropped.
+    pub fn note_on(&mut self, pitch: u32, velocity: f32, time: f64) {
+        if self.queue.has_room(2) {
+            self.push(time, NoteEvent::On(PlayedNote::new(pitch, velocity, time, None)));
+        }
+    }
+    
+    /// lets go of any notes of the given pitch which are being played live
+    /// at `time`, after which they fade out over their release.
+    pub fn note_off(&mut self, pitch: u32, time: f64) {
+        self.push(time, NoteEvent::Off { pitch, id: None });
+    }
+    
+    /// adds an event to the queue, after any others at the same time, as
+    /// long as there's room for it.
+    fn push(&mut self, time: f64, event: NoteEvent) {
+        if self.queue.has_room(1) {
+            let index = self.queue.partition_point(|(t, _)| *t <= time);
+            self.queue.insert(index, (time, event));
         }
     }
     
-    /// places a note into the "best" voice and, if it is to be played at
-    /// the current time, starts to play it.
-    pub fn schedule(&mut self, note: song::Note, bps: f64) {        
-        let mut best_finish = f64::MAX;
-        let mut best_index = 0;
+    /// whether any of the instrument's notes are waiting to start, or can
+    /// still be heard.
+    pub fn is_sounding(&self) -> bool {
+        let len = self.operators.len();
+        !self.queue.is_empty() || self.voices.iter().chain(&self.tails).any(|v| !v.is_silent(len))
+    }
+    
+    /// forgets about every note which has been scheduled but not yet started.
+    /// notes which have already started will still be let go of.
+    pub fn clear_queue(&mut self) {
+        let (voices, held) = (&self.voices, &self.held[..self.num_held]);
         
-        for (n, voice) in self.voices.iter().enumerate() {
-            let finish = voice.note.start + voice.note.duration;
+        self.queue.retain(|(_, event)| match event {
+            NoteEvent::Off { pitch, id } => {
+                voices.iter().any(|v| v.is_holding(*pitch, *id))
+                    || held.iter().any(|n| n.pitch == *pitch && n.id == *id)
+            },
+            NoteEvent::On(_) => false,
+        });
+    }
+    
+    /// moves every queued event to a different time, e.g. to the same beat
+    /// after a change of tempo. `f` should never move one event past another.
+    pub fn retime<F>(&mut self, f: F) where F: Fn(f64) -> f64 {
+        for (time, event) in self.queue.iter_mut() {
+            *time = f(*time);
             
-            if voice.note.pitch == note.pitch {
-                best_index = n;
-                break;
+            if let NoteEvent::On(note) = event {
+                note.start = f(note.start);
             }
+        }
+    }
+    
+    /// sets the tempo which synced LFOs follow, in beats per minute.
+    pub fn set_bpm(&mut self, bpm: f64) {
+        self.bps = bpm / 60.0;
+    }
+    
+    /// gets how many notes the instrument can play at once.
+    pub fn polyphony(&self) -> usize {
+        self.num_voices
+    }
+    
+    /// changes how many notes the instrument can play at once, up to
+    /// MAX_VOICES. any notes in the voices which are taken away carry on
+    /// until they're let go of and have faded out, but no new ones start.
+    pub fn set_polyphony(&mut self, num_voices: usize) {
+        self.num_voices = num_voices.clamp(1, MAX_VOICES);
+    }
+    
+    /// places a note into the "best" voice, which starts to play it. a voice
+    /// already playing the same pitch is best, then any which are silent, and
+    /// otherwise one is stolen. a stolen voice's note is moved into its tail
+    /// to fade out quickly, so that it doesn't click, and the new note starts
+    /// straight away.
+    fn start(&mut self, note: PlayedNote) {
+        if self.mono.enabled {
+            self.start_mono(note);
+            return;
+        }
+        
+        let len = self.operators.len();
+        let voices = &self.voices[..self.num_voices];
+        
+        let free = voices.iter().position(|v| v.note.pitch == note.pitch)
+            .or_else(|| voices.iter().position(|v| v.is_silent(len)));
+        
+        if let Some(i) = free.or_else(|| self.victim()) {
+            let voice = &mut self.voices[i];
             
-            if finish < best_finish {
-                best_finish = finish;
-                best_index = n;
+            if voice.note.pitch != note.pitch && !voice.is_silent(len) {
+                self.tails[i].clone_from(voice);
+                self.tails[i].fade(STEAL_FADE);
+                voice.silence();
             }
+            
+            voice.note_on(note, &self.operators);
         }
+    }
+    
+    /// plays a note on a mono instrument, which only ever uses its first
+    /// voice. if another note is still held, the voice moves on from it
+    /// rather than starting afresh.
+    fn start_mono(&mut self, note: PlayedNote) {
+        let voice = &mut self.voices[0];
         
-        self.voices[best_index].note = PlayedNote::from(note, bps);
+        if voice.is_held() {
+            voice.glide(note.clone(), self.mono, &self.operators);
+        } else {
+            voice.note_on(note.clone(), &self.operators);
+        }
+        
+        if self.num_held == MAX_HELD {
+            self.held.rotate_left(1);
+            self.num_held -= 1;
+        }
+        
+        self.held[self.num_held] = note;
+        self.num_held += 1;
     }
     
-    /// flushes all notes from the instrument's voices.
+    /// chooses which voice to steal for a new note, following the stealing
+    /// policy. gives `None` if every voice is held and the policy won't
+    /// steal any of them.
+    fn victim(&self) -> Option<usize> {
+        let len = self.operators.len();
+        
+        let rank = |v: &Voice| (v.is_held(), match self.stealing {
+            StealPolicy::Oldest | StealPolicy::Never => v.note.start,
+            StealPolicy::Quietest => v.level(len) as f64,
+            StealPolicy::Lowest => v.note.pitch as f64,
+            StealPolicy::Highest => -(v.note.pitch as f64),
+        });
+        
+        (0..self.num_voices)
+            .filter(|&i| self.stealing != StealPolicy::Never || !self.voices[i].is_held())
+            .min_by(|&a, &b| {
+                rank(&self.voices[a]).partial_cmp(&rank(&self.voices[b])).unwrap_or(std::cmp::Ordering::Equal)
+            })
+    }
+    
+    /// lets go of the note with a given pitch and id, if a voice is playing it.
+    fn release(&mut self, pitch: u32, id: Option<u64>, time: f64) {
+        let mut kept = 0;
+        
+        for i in 0..self.num_held {
+            if self.held[i].pitch != pitch || self.held[i].id != id {
+                self.held.swap(kept, i);
+                kept += 1;
+            }
+        }
+        
+        self.num_held = kept;
+        
+        // on a mono instrument, the last note which is still held takes over
+        // again, as though it had just been played.
+        if self.mono.enabled && self.voices[0].is_holding(pitch, id) {
+            if let Some(note) = self.held[..self.num_held].last() {
+                self.voices[0].glide(note.clone(), self.mono, &self.operators);
+                return;
+            }
+        }
+        
+        for voice in self.voices.iter_mut().filter(|v| v.is_holding(pitch, id)) {
+            voice.note_off(time, &self.operators);
+        }
+    }
+    
+    /// flushes all notes from the instrument's voices and its queue, and
+    /// throws away anything rendered in advance.
     pub fn flush(&mut self) {
-        for voice in self.voices.iter_mut() {
-            voice.note.freq = 0.0;
-            voice.note.pitch = 0;
-            voice.note.duration = 0.0;
-            voice.note.start = -0.0;
-            voice.note.velocity = 0.0;
+        self.queue.clear();
+        self.num_held = 0;
+        self.hold_index = self.hold_len;
+        
+        for voice in self.voices.iter_mut().chain(self.tails.iter_mut()) {
+            voice.silence();
         }
         
         for effect in self.effects.iter_mut() {
@@ -207,15 +744,35 @@ impl Instrument {
         }
     }
     
+    /// moves every note being played by `by` seconds, so that the playhead
+    /// can jump by the same amount without the output changing at all.
+    pub fn shift(&mut self, by: f64) {
+        for voice in self.voices.iter_mut().chain(self.tails.iter_mut()) {
+            voice.shift(by);
+        }
+        
+        self.retime(|time| time + by);
+    }
+    
+    /// gets the longest release of any of the instrument's operators, i.e. how
+    /// long a note can be heard for after it finishes.
+    pub fn release_time(&self) -> f32 {
+        self.operators.iter().map(|op| op.envelope.release).fold(0.0, f32::max)
+    }
+    
     pub fn add_operator(&mut self, op: Operator) {
         if self.operators.len() < MAX_OPERATORS {
             self.operators.push(op);
         }
     }
     
-    pub fn next_output(&mut self, time: f64, dt: f64) -> f32 {
-        if self.hold_index >= HOLD_BUFFER_SIZE {
-            self.fill_hold_buffer(time, dt);
+    /// gets the next sample of the instrument's output, which is played at
+    /// `time`. samples are rendered in blocks of up to `block` samples at a
+    /// time (and never more than HOLD_BUFFER_SIZE), so every note starting
+    /// within the next `block` samples should already be scheduled.
+    pub fn next_output(&mut self, time: f64, dt: f64, block: usize) -> Stereo {
+        if self.hold_index >= self.hold_len {
+            self.fill_hold_buffer(time, dt, block.clamp(1, HOLD_BUFFER_SIZE));
         }
         
         let out = self.hold_buf[self.hold_index];
@@ -224,23 +781,94 @@ impl Instrument {
         out
     }
     
-    fn fill_hold_buffer(&mut self, time: f64, dt: f64) {
-        for i in 0..HOLD_BUFFER_SIZE {
-            self.hold_buf_back[i] = 0.0;
+    fn fill_hold_buffer(&mut self, time: f64, dt: f64, len: usize) {
+        for i in 0..len {
+            self.dry_buf[i] = 0.0;
         }
         
-        for voice in &mut self.voices {
-            voice.fill_hold_buffer(time, dt, &mut self.hold_buf_back, &self.operators);
+        // the block is split up wherever a note starts, so that each one
+        // starts on exactly the right sample.
+        let mut frame = 0;
+        
+        while frame < len {
+            while let Some(&(at, _)) = self.queue.front() {
+                if onset_frame(at, time, dt) > frame as f64 {
+                    break;
+                }
+                
+                // times are moved onto the sample to make up for any rounding.
+                let now = at.min(time + frame as f64 * dt);
+                
+                match self.queue.pop_front().unwrap().1 {
+                    NoteEvent::On(mut note) => {
+                        note.start = now;
+                        self.start(note);
+                    },
+                    NoteEvent::Off { pitch, id } => self.release(pitch, id, now),
+                }
+            }
+            
+            let end = match self.queue.front() {
+                Some(&(at, _)) => (onset_frame(at, time, dt) as usize).clamp(frame + 1, len),
+                None => len,
+            };
+            
+            let patch = Patch::new(&self.operators, &self.lfos, &self.routes, self.controls);
+            let mut clock = lfo::Clock {
+                phases: self.lfo_phases,
+                rates: self.lfos.map(|l| l.frequency(self.bps)),
+            };
+            
+            // voices which have been taken away, and tails, are only played
+            // until they've faded out.
+            let len = self.operators.len();
+            
+            for (i, voice) in self.voices.iter_mut().enumerate() {
+                if i < self.num_voices || !voice.is_silent(len) {
+                    voice.fill_hold_buffer(time + frame as f64 * dt, dt, &mut self.dry_buf[frame..end], &patch, clock);
+                }
+            }
+            
+            for tail in self.tails.iter_mut().filter(|t| !t.is_silent(len)) {
+                tail.fill_hold_buffer(time + frame as f64 * dt, dt, &mut self.dry_buf[frame..end], &patch, clock);
+            }
+            
+            clock.advance((end - frame) as f64 * dt);
+            self.lfo_phases = clock.phases;
+            frame = end;
         }
         
-        for i in 0..HOLD_BUFFER_SIZE {
+        self.modulate_effects();
+        
+        for i in 0..len {
             self.hold_buf_back[i] = self.effects
                 .iter_mut()
-                .fold(self.hold_buf_back[i], |s, eff| eff.process(s));
+                .fold([self.dry_buf[i]; 2], |s, eff| eff.process(s));
         }
         
         self.swap_buffers();
         self.hold_index = 0;
+        self.hold_len = len;
+    }
+    
+    /// moves the effects' parameters by the routes to them. effects are shared
+    /// by every voice, so they follow the note which started most recently.
+    fn modulate_effects(&mut self) {
+        let len = self.operators.len();
+        let latest = self.voices.iter()
+            .filter(|v| !v.is_silent(len))
+            .max_by(|a, b| a.note.start.partial_cmp(&b.note.start).unwrap_or(std::cmp::Ordering::Equal));
+        
+        let sources = match latest {
+            Some(voice) => voice.sources,
+            None => Sources { controls: self.controls, ..Sources::default() },
+        };
+        
+        for (i, effect) in self.effects.iter_mut().enumerate() {
+            for param in 0..effect.params().len() {
+                effect.modulate(param, modulation::effect_offset(&self.routes, &sources, i, param));
+            }
+        }
     }
     
     fn swap_buffers(&mut self) {
@@ -248,43 +876,226 @@ impl Instrument {
     }
 }
 
+impl EventQueue {
+    fn new() -> EventQueue {
+        EventQueue(VecDeque::with_capacity(QUEUE_CAPACITY))
+    }
+    
+    /// whether `events` more events can be added without growing the queue.
+    fn has_room(&self, events: usize) -> bool {
+        self.0.len() + events <= QUEUE_CAPACITY
+    }
+}
+
+impl Clone for EventQueue {
+    fn clone(&self) -> EventQueue {
+        let mut queue = EventQueue::new();
+        queue.0.extend(self.0.iter().cloned());
+        queue
+    }
+}
+
+impl std::ops::Deref for EventQueue {
+    type Target = VecDeque<(f64, NoteEvent)>;
+    
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for EventQueue {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl<'a> Patch<'a> {
+    /// gathers up what an instrument's voices need to play. the LFOs and the
+    /// modulation matrix are only worked out if they'd change anything.
+    fn new(ops: &'a [Operator], lfos: &'a [Lfo; MAX_LFOS], routes: &'a [Route; MAX_ROUTES], controls: Controls) -> Patch<'a> {
+        let lfos_used = ops.iter().any(|op| op.lfos.iter().any(|d| *d != lfo::Depths::default()))
+            || routes.iter().any(|r| r.is_active() && matches!(r.source, modulation::Source::Lfo(_)));
+        
+        Patch {
+            ops,
+            lfos: lfos_used.then_some(lfos),
+            routes: routes.iter().any(|r| r.is_active()).then_some(routes),
+            controls,
+        }
+    }
+}
+
 impl Voice {
     pub fn new() -> Voice {
         Voice {
             channels: [0.0; NUM_CHANNELS],
             channels_back: [0.0; NUM_CHANNELS],
             phases: [0.0; MAX_OPERATORS],
-            note: PlayedNote {
-                pitch: 0,
-                freq: 0.0,
-                duration: 0.0,
-                velocity: 0.0,
-                start: 0.0,
+            carriers: [0.0; MAX_OPERATORS],
+            note: PlayedNote::new(0, 0.0, 0.0, None),
+            sounding: false,
+            freq: 0.0,
+            lfo_phases: [0.0; MAX_LFOS],
+            glide: 0.0,
+            released: f64::INFINITY,
+            envelopes: [EnvelopeState::new(); MAX_OPERATORS],
+            sources: Sources::default(),
+            stretch: [1.0; MAX_OPERATORS],
+        }
+    }
+    
+    /// stops the voice from playing anything, straight away.
+    fn silence(&mut self) {
+        self.note = PlayedNote::new(0, 0.0, 0.0, None);
+        self.sounding = false;
+        self.released = f64::INFINITY;
+        self.envelopes = [EnvelopeState::new(); MAX_OPERATORS];
+        self.glide = 0.0;
+        self.channels = [0.0; NUM_CHANNELS];
+        self.channels_back = [0.0; NUM_CHANNELS];
+    }
+    
+    /// fades the voice's note out over `time` seconds, from wherever each
+    /// envelope is.
+    fn fade(&mut self, time: f32) {
+        for env in self.envelopes.iter_mut() {
+            env.fade(time);
+        }
+    }
+    
+    /// starts playing a note. each operator's envelope starts its attack from
+    /// wherever it was, so that a voice can be reused without clicking.
+    fn note_on(&mut self, note: PlayedNote, ops: &[Operator]) {
+        self.freq = note.freq;
+        self.glide = 0.0;
+        self.note = note;
+        self.sounding = true;
+        self.released = f64::INFINITY;
+        self.lfo_phases = [0.0; MAX_LFOS];
+        
+        for (env, op) in self.envelopes.iter_mut().zip(ops) {
+            env.trigger(&op.envelope);
+        }
+    }
+    
+    /// moves on to another note without letting go of the last one, sliding
+    /// from the current pitch over the glide time. unless the instrument is
+    /// legato, the envelopes and LFOs start again from wherever they are.
+    fn glide(&mut self, mut note: PlayedNote, mono: Mono, ops: &[Operator]) {
+        if mono.glide > 0.0 && self.freq > 0.0 {
+            self.glide = (note.freq / self.freq).ln() / mono.glide;
+        } else {
+            self.freq = note.freq;
+            self.glide = 0.0;
+        }
+        
+        if mono.legato {
+            note.start = self.note.start;
+        } else {
+            self.lfo_phases = [0.0; MAX_LFOS];
+            
+            for (env, op) in self.envelopes.iter_mut().zip(ops) {
+                env.trigger(&op.envelope);
             }
         }
+        
+        self.note = note;
+        self.sounding = true;
+        self.released = f64::INFINITY;
+    }
+    
+    /// lets go of the voice's note at `time`, starting each envelope's release
+    /// from its current level.
+    fn note_off(&mut self, time: f64, ops: &[Operator]) {
+        self.released = time;
+        
+        for ((env, op), stretch) in self.envelopes.iter_mut().zip(ops).zip(self.stretch) {
+            env.release(&op.envelope.stretched([1.0, 1.0, stretch]));
+        }
+    }
+    
+    /// whether the voice's note is still held down.
+    fn is_held(&self) -> bool {
+        self.sounding && self.released.is_infinite()
+    }
+    
+    /// whether the voice is holding down a particular note.
+    fn is_holding(&self, pitch: u32, id: Option<u64>) -> bool {
+        self.note.pitch == pitch && self.note.id == id && self.is_held()
+    }
+    
+    /// whether all of the voice's envelopes (for the first `num_ops`
+    /// operators) have finished, so it can't be heard.
+    fn is_silent(&self, num_ops: usize) -> bool {
+        !self.sounding || self.envelopes[..num_ops.min(MAX_OPERATORS)].iter().all(|e| e.stage == Stage::Idle)
+    }
+    
+    /// gets how loud the voice is at the moment, going by its loudest operator.
+    fn level(&self, num_ops: usize) -> f32 {
+        self.envelopes[..num_ops.min(MAX_OPERATORS)].iter().fold(0.0, |l, e| e.level.max(l)) * self.note.velocity
     }
     
     #[inline]
-    fn fill_hold_buffer(&mut self, time: f64, dt: f64,
-        buf: &mut [f32; HOLD_BUFFER_SIZE], ops: &Vec<Operator>) {
+    fn fill_hold_buffer(&mut self, time: f64, dt: f64, buf: &mut [f32], patch: &Patch, mut clock: lfo::Clock) {
         let mut t = time;
         
-        for frame in 0..HOLD_BUFFER_SIZE {
-            self.frame(t, dt, ops);
-            buf[frame] += self.channels[0];
+        for s in buf.iter_mut() {
+            self.frame(t, dt, patch, &clock);
+            *s += self.channels[0];
+            
+            if patch.lfos.is_some() {
+                clock.advance(dt);
+            }
+            
             t += dt;
         }
     }
         
     #[inline]
-    fn frame(&mut self, time: f64, dt: f64, ops: &Vec<Operator>) {
-        for (i, op) in ops.iter().enumerate() {
+    fn frame(&mut self, time: f64, dt: f64, patch: &Patch, clock: &lfo::Clock) {
+        if self.glide != 0.0 {
+            self.freq *= (self.glide * dt as f32).exp();
+            
+            // the glide stops once it reaches the note, from either side.
+            if (self.freq - self.note.freq) * self.glide >= 0.0 {
+                self.freq = self.note.freq;
+                self.glide = 0.0;
+            }
+        }
+        
+        let values = patch.lfos.map(|lfos| self.lfo_values(time, dt, lfos, clock));
+        
+        let targets = patch.routes.map(|routes| {
+            self.sources = Sources {
+                velocity: self.note.velocity,
+                key: (self.note.pitch as f32 - modulation::MIDDLE_C as f32) / 12.0,
+                lfos: values.unwrap_or_default(),
+                envelopes: self.envelopes.map(|e| e.level),
+                controls: patch.controls,
+            };
+            
+            Targets::new(routes, &self.sources)
+        });
+        
+        for (i, op) in patch.ops.iter().enumerate() {
+            let m = values.map_or(lfo::Factors::NONE, |v| lfo::Factors::new(&op.lfos, &v));
+            
+            // what the modulation matrix multiplies the pitch, the envelope's
+            // times and the sends by.
+            let (transpose, stretch, send) = match &targets {
+                Some(t) if t.transpose[i] != 0.0 => (2.0f32.powf(t.transpose[i] / 12.0), [t.attack[i], t.decay[i], t.release[i]], t.send[i]),
+                Some(t) => (1.0, [t.attack[i], t.decay[i], t.release[i]], t.send[i]),
+                None => (1.0, [1.0; 3], 1.0),
+            };
+            
+            self.stretch[i] = stretch[2];
+            
             for (i, conn) in op.connections.iter().enumerate() {
                 let modulation = match conn.kind {
                     ReceiveKind::Normal | ReceiveKind::Vibrato
-                        => self.channels[i] * conn.receive * dt as f32,
+                        => self.channels[i] * conn.receive * m.receive * dt as f32,
                     ReceiveKind::Modulate
-                        => self.channels[i] * conn.receive * dt as f32 * self.note.freq,
+                        => self.channels[i] * conn.receive * m.receive * dt as f32 * self.freq,
                 };
                 
                 self.phases[i] += modulation;
@@ -294,43 +1105,71 @@ impl Voice {
                 self.phases[i] -= 2.0 * PI;
             }
             
-            // if the pitch is 0, the note hasn't been set yet.
-            let sample = if self.note.pitch > 0 {
-                let env = op.envelope.evaluate((time - self.note.start) as f32, self.note.duration as f32);
-                let vel = env * self.note.velocity;
+            let sample = if self.sounding {
+                let env = self.envelopes[i].next(&op.envelope.stretched(stretch), dt as f32);
+                let vel = env * self.note.velocity * m.amplitude;
                 
                 let f = if op.fixed {
-                    op.transpose
+                    op.transpose * m.freq * transpose
                 } else {
-                    self.note.freq * op.transpose
+                    self.freq * op.transpose * m.freq * transpose
                 } as f64;
                 
-                let t = f * time + self.phases[i] as f64;
+                let t = self.carriers[i] + self.phases[i] as f64;
+                self.carriers[i] = (self.carriers[i] + f * dt).rem_euclid(1.0);
                 
-                vel * match op.wave {
-                    WaveType::Sine => -f64::cos(2.0 * std::f64::consts::PI * t) as f32,
-                    WaveType::Square => 2.0 * ((2.0 * t) as u32 % 2) as f32 - 1.0,
-                    WaveType::Triangle => 1.0 - 2.0 * f32::abs(2.0 * (t - t.floor()) as f32 - 1.0),
-                    WaveType::Sawtooth => (t - t.floor()) as f32,
+                if op.band_limited {
+                    vel * op.wave.sample_band_limited(t, f * dt)
+                } else {
+                    vel * op.wave.sample(t)
                 }
             } else {
                 0.0
             };
             
             for (i, conn) in op.connections.iter().enumerate() {
-                self.channels_back[i] += conn.send * sample;
+                self.channels_back[i] += conn.send * m.send * send * sample;
             }
         }
         
         self.swap_buffers();
     }
     
+    /// moves the voice's note by `by` seconds. the operators keep track of
+    /// their own phases, so the waveforms carry on smoothly regardless.
+    fn shift(&mut self, by: f64) {
+        self.note.start += by;
+        self.released += by;
+    }
+    
+    /// gets the output of each of the instrument's LFOs for this voice at
+    /// `time`, and moves the ones which restart with each note on by `dt`.
+    fn lfo_values(&mut self, time: f64, dt: f64, lfos: &[Lfo; MAX_LFOS], clock: &lfo::Clock) -> [f32; MAX_LFOS] {
+        let age = time - self.note.start;
+        let values = std::array::from_fn(|k| {
+            let phase = if lfos[k].key_sync { self.lfo_phases[k] } else { clock.phases[k] };
+            lfos[k].value(phase, age)
+        });
+        
+        for (phase, rate) in self.lfo_phases.iter_mut().zip(clock.rates) {
+            *phase = (*phase + rate * dt).rem_euclid(1.0);
+        }
+        
+        values
+    }
+    
     fn swap_buffers(&mut self) {
         std::mem::swap(&mut self.channels, &mut self.channels_back);
         self.channels_back = [0.0; NUM_CHANNELS];
     }
 }
 
+impl Default for Voice {
+    fn default() -> Voice {
+        Voice::new()
+    }
+}
+
 impl Operator {
     pub fn new(wave: WaveType, fixed: bool, transpose: f32) -> Operator {
         Operator {
@@ -342,7 +1181,9 @@ impl Operator {
             fixed,
             wave,
             transpose,
-            envelope: Envelope { attack: 0.0, decay: 0.0, sustain: 1.0, release: 0.0 }
+            envelope: Envelope { attack: 0.0, decay: 0.0, sustain: 1.0, release: 0.0 },
+            lfos: [lfo::Depths::default(); MAX_LFOS],
+            band_limited: true,
         }
     }
     
@@ -351,6 +1192,11 @@ impl Operator {
         self
     }
     
+    pub fn band_limited(&mut self, band_limited: bool) -> &mut Operator {
+        self.band_limited = band_limited;
+        self
+    }
+    
     pub fn send(&mut self, channel: usize, level: f32) -> &mut Operator {
         self.connections[channel].send = level;
         self.connections[channel].kind = ReceiveKind::Normal;
@@ -377,35 +1223,417 @@ impl Operator {
 }
 
 impl Envelope {
-    pub fn evaluate(&self, t: f32, hold_time: f32) -> f32 {
-        if t < 0.0 || t >= hold_time + self.release {
-            return 0.0;
+    /// gets a copy of the envelope with its attack, decay and release times
+    /// multiplied by the given amounts.
+    pub fn stretched(&self, by: [f32; 3]) -> Envelope {
+        Envelope {
+            attack: self.attack * by[0],
+            decay: self.decay * by[1],
+            sustain: self.sustain,
+            release: self.release * by[2],
         }
-        
-        if self.attack < 0.0 {
-            return 1.0;
+    }
+}
+
+impl EnvelopeState {
+    /// a state which is silent, and isn't in the middle of a note.
+    pub fn new() -> EnvelopeState {
+        EnvelopeState {
+            stage: Stage::Idle,
+            level: 0.0,
+            release_rate: 0.0,
+        }
+    }
+    
+    /// starts a new note, attacking from the current level.
+    pub fn trigger(&mut self, env: &Envelope) {
+        self.stage = Stage::Attack;
+        self.skip_empty_stages(env);
+    }
+    
+    /// lets go of the note, so that the level falls from wherever it is now
+    /// down to nothing over the envelope's release.
+    pub fn release(&mut self, env: &Envelope) {
+        self.fade(env.release);
+    }
+    
+    /// makes the level fall from wherever it is now down to nothing over
+    /// `time` seconds.
+    pub fn fade(&mut self, time: f32) {
+        if self.stage == Stage::Idle {
+            return;
         }
         
-        if hold_time < self.attack + self.decay && t >= hold_time {
-            let rf = 1.0 - (t - hold_time) / self.release;
-            
-            if hold_time < self.attack {
-                return (hold_time / self.attack) * rf;
-            } else {
-                return (1.0 - ((1.0 - self.sustain) * (hold_time - self.attack)) / self.decay) * rf;
-            }
+        if time <= 0.0 {
+            self.level = 0.0;
+            self.stage = Stage::Idle;
         } else {
-            if t < self.attack {
-                return t / self.attack;
-            } else if t < self.attack + self.decay {
-                return 1.0 - ((1.0 - self.sustain) * (t - self.attack)) / self.decay;
-            } else if t < hold_time {
-                return self.sustain;
-            } else if t < hold_time + self.release {
-                return self.sustain * (1.0 - (t - hold_time) / self.release);
+            self.stage = Stage::Release;
+            self.release_rate = self.level / time;
+        }
+    }
+    
+    /// gets the current level, and then moves on by `dt` seconds.
+    pub fn next(&mut self, env: &Envelope, dt: f32) -> f32 {
+        let level = self.level;
+        
+        match self.stage {
+            Stage::Attack => self.level += dt / env.attack,
+            Stage::Decay => self.level -= (1.0 - env.sustain) * dt / env.decay,
+            Stage::Sustain => self.level = env.sustain,
+            Stage::Release => self.level -= self.release_rate * dt,
+            Stage::Idle => {},
+        }
+        
+        self.skip_empty_stages(env);
+        level
+    }
+    
+    /// moves on to the next stage for as long as the current one is already
+    /// finished, e.g. straight past an attack of length zero.
+    fn skip_empty_stages(&mut self, env: &Envelope) {
+        loop {
+            match self.stage {
+                Stage::Attack if env.attack <= 0.0 || self.level >= 1.0 => {
+                    self.level = 1.0;
+                    self.stage = Stage::Decay;
+                },
+                
+                // the decay is finished once the level reaches the sustain,
+                // from whichever side it's approaching.
+                Stage::Decay if env.decay <= 0.0 || (self.level - env.sustain) * (1.0 - env.sustain) <= 0.0 => {
+                    self.level = env.sustain;
+                    self.stage = Stage::Sustain;
+                },
+                Stage::Release if self.level <= 0.0 => {
+                    self.level = 0.0;
+                    self.stage = Stage::Idle;
+                },
+                _ => break,
             }
+        }
+    }
+}
+
+impl Default for EnvelopeState {
+    fn default() -> EnvelopeState {
+        EnvelopeState::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::modulation::{Destination, Source};
+    
+    const DT: f64 = 1.0 / 8000.0;
+    
+    /// renders an instrument from `from` to `to` seconds, and finds the
+    /// loudest sample.
+    fn peak(instr: &mut Instrument, from: f64, to: f64) -> f32 {
+        let start = (from / DT).round() as usize;
+        let end = (to / DT).round() as usize;
+        
+        (start..end)
+            .map(|i| instr.next_output(i as f64 * DT, DT, HOLD_BUFFER_SIZE)[0].abs())
+            .fold(0.0, f32::max)
+    }
+    
+    /// plays a wave at 1234Hz for two seconds, and gets the fraction of the
+    /// second second's energy which isn't at DC or one of the harmonics below
+    /// the Nyquist frequency, i.e. which has aliased.
+    fn aliasing(wave: WaveType, band_limited: bool) -> f64 {
+        const N: usize = 8000;
+        const FREQ: usize = 1234;
+        
+        let mut instr = Instrument::new(1);
+        Operator::new(wave, true, FREQ as f32).band_limited(band_limited).send(0, 1.0).add(&mut instr);
+        instr.note_on(48, 1.0, 0.0);
+        
+        let samples: Vec<f64> = (0..2 * N)
+            .map(|i| instr.next_output(i as f64 * DT, DT, HOLD_BUFFER_SIZE)[0] as f64)
+            .skip(N)
+            .collect();
+        
+        // with a whole number of cycles in the window, each harmonic (aliased
+        // or not) lands exactly on one bin of the DFT.
+        let bin = |k: usize| {
+            let (re, im) = samples.iter().enumerate().fold((0.0, 0.0), |(re, im), (n, x)| {
+                let w = 2.0 * std::f64::consts::PI * (k * n % N) as f64 / N as f64;
+                (re + x * w.cos(), im - x * w.sin())
+            });
             
-            0.0
+            re * re + im * im
+        };
+        
+        let total = N as f64 * samples.iter().map(|x| x * x).sum::<f64>();
+        let harmonics = bin(0) + 2.0 * (1..=N / 2 / FREQ).map(|h| bin(h * FREQ)).sum::<f64>();
+        
+        (total - harmonics) / total
+    }
+    
+    #[test]
+    fn releases_from_wherever_the_envelope_is() {
+        let env = Envelope { attack: 1.0, decay: 0.0, sustain: 1.0, release: 0.1 };
+        let mut state = EnvelopeState::new();
+        state.trigger(&env);
+        
+        for _ in 0..250 {
+            state.next(&env, 0.001);
         }
+        
+        // letting go half way through the attack fades out from there, rather
+        // than jumping to the sustain level first.
+        let level = state.level;
+        state.release(&env);
+        assert!((level - 0.25).abs() < 1e-3);
+        assert_eq!(state.next(&env, 0.05), level);
+        assert!((state.next(&env, 0.05) - level / 2.0).abs() < 1e-3);
+        assert_eq!(state.next(&env, 0.01), 0.0);
+        assert_eq!(state.stage, Stage::Idle);
+    }
+    
+    #[test]
+    fn steals_voices_by_policy() {
+        let cases = [
+            (StealPolicy::Oldest, [48, 52]),
+            (StealPolicy::Quietest, [52, 55]),
+            (StealPolicy::Lowest, [52, 55]),
+            (StealPolicy::Highest, [48, 52]),
+            (StealPolicy::Never, [48, 55]),
+        ];
+        
+        for (policy, expected) in cases {
+            let mut instr = Instrument::new(2);
+            instr.stealing = policy;
+            Operator::new(WaveType::Sine, false, 1.0).send(0, 1.0).add(&mut instr);
+            
+            instr.note_on(55, 1.0, 0.0);
+            instr.note_on(48, 0.2, 0.1);
+            instr.note_on(52, 1.0, 0.2);
+            peak(&mut instr, 0.0, 0.3);
+            
+            let mut pitches: Vec<u32> = instr.voices[..2].iter().map(|v| v.note.pitch).collect();
+            pitches.sort();
+            assert_eq!(pitches, expected);
+        }
+        
+        // a voice which has been let go of is taken before any held ones.
+        let mut instr = Instrument::new(2);
+        instr.stealing = StealPolicy::Never;
+        Operator::new(WaveType::Sine, false, 1.0).env(0.0, 0.0, 1.0, 1.0).send(0, 1.0).add(&mut instr);
+        
+        instr.note_on(48, 1.0, 0.0);
+        instr.note_on(55, 1.0, 0.0);
+        instr.note_off(55, 0.1);
+        instr.note_on(52, 1.0, 0.2);
+        peak(&mut instr, 0.0, 0.3);
+        
+        let mut pitches: Vec<u32> = instr.voices[..2].iter().map(|v| v.note.pitch).collect();
+        pitches.sort();
+        assert_eq!(pitches, [48, 52]);
+    }
+    
+    #[test]
+    fn voices_taken_away_finish_their_notes() {
+        let mut instr = Instrument::new(2);
+        Operator::new(WaveType::Sine, true, 203.3).env(0.0, 0.0, 1.0, 0.1).send(0, 1.0).add(&mut instr);
+        
+        instr.note_on(48, 1.0, 0.0);
+        instr.note_on(55, 1.0, 0.0);
+        peak(&mut instr, 0.0, 0.096);
+        instr.set_polyphony(1);
+        assert_eq!(instr.polyphony(), 1);
+        
+        // the second voice carries on with its note, and fades out as usual
+        // once it's let go of.
+        instr.note_off(55, 0.192);
+        assert!(peak(&mut instr, 0.096, 0.192) > 1.5);
+        assert!(instr.voices[1].is_holding(55, None));
+        
+        peak(&mut instr, 0.192, 0.224);
+        assert_eq!(instr.voices[1].envelopes[0].stage, Stage::Release);
+        
+        // once it's quiet, it isn't given any more notes.
+        instr.note_on(60, 1.0, 0.32);
+        peak(&mut instr, 0.224, 0.352);
+        assert!(instr.voices[1].is_silent(1));
+        assert!(instr.voices[0].is_holding(60, None));
+    }
+    
+    #[test]
+    fn the_lowest_note_can_be_played() {
+        let mut instr = Instrument::new(1);
+        Operator::new(WaveType::Sine, true, 203.3).env(0.0, 0.0, 1.0, 0.1).send(0, 1.0).add(&mut instr);
+        
+        instr.note_on(0, 1.0, 0.0);
+        assert!(peak(&mut instr, 0.0, 0.05) > 0.5);
+        assert!(instr.voices[0].is_holding(0, None));
+        
+        instr.note_off(0, 0.05);
+        peak(&mut instr, 0.05, 0.2);
+        assert!(instr.voices[0].is_silent(1));
+    }
+    
+    #[test]
+    fn mono_instruments_glide_between_held_notes() {
+        let mut instr = Instrument::new(4);
+        instr.mono = Mono { enabled: true, legato: true, glide: 0.1 };
+        Operator::new(WaveType::Sine, false, 1.0).env(0.2, 0.0, 1.0, 0.0).send(0, 1.0).add(&mut instr);
+        
+        instr.note_on(48, 1.0, 0.0);
+        instr.note_on(60, 1.0, 0.5);
+        peak(&mut instr, 0.0, 0.55);
+        
+        // the second note takes over the first one's voice, sliding up an
+        // octave without starting the envelope again.
+        let voice = &instr.voices[0];
+        assert!(voice.freq > voice.note.freq / 2.0 && voice.freq < voice.note.freq);
+        assert_eq!(voice.envelopes[0].stage, Stage::Sustain);
+        assert!(instr.voices[1..].iter().all(|v| v.is_silent(1)));
+        
+        peak(&mut instr, 0.55, 0.704);
+        assert_eq!(instr.voices[0].freq, instr.voices[0].note.freq);
+        
+        // letting go of it goes back to the note still held.
+        instr.note_off(60, 0.704);
+        peak(&mut instr, 0.704, 0.96);
+        assert!(instr.voices[0].is_holding(48, None));
+        assert_eq!(instr.voices[0].freq, instr.voices[0].note.freq);
+        
+        instr.note_off(48, 0.96);
+        assert!(peak(&mut instr, 0.96, 1.1) < 1e-6);
+        assert!(!instr.is_sounding());
+    }
+    
+    #[test]
+    fn the_queue_never_grows() {
+        let mut instr = Instrument::new(4);
+        Operator::new(WaveType::Sine, true, 203.3).send(0, 1.0).add(&mut instr);
+        
+        // copies of the instrument (like the ones sent to the player) have
+        // just as much room, and once it's full, more notes are dropped.
+        let mut instr = instr.clone();
+        let capacity = instr.queue.capacity();
+        assert!(capacity >= QUEUE_CAPACITY);
+        
+        let tempo = song::TempoMap::constant(60.0);
+        
+        for i in 0..QUEUE_CAPACITY as u32 {
+            instr.schedule(song::Note::new(48, i, 0, 24, 1.0), &tempo);
+        }
+        
+        instr.note_off(48, 0.0);
+        assert_eq!(instr.queue.len(), QUEUE_CAPACITY);
+        assert_eq!(instr.queue.capacity(), capacity);
+        
+        // the notes which did fit still play.
+        assert!(peak(&mut instr, 0.0, 0.1) > 0.5);
+    }
+    
+    #[test]
+    fn mono_instruments_forget_the_oldest_held_notes() {
+        let mut instr = Instrument::new(1);
+        instr.mono = Mono { enabled: true, legato: false, glide: 0.0 };
+        Operator::new(WaveType::Sine, false, 1.0).send(0, 1.0).add(&mut instr);
+        
+        for pitch in 48..49 + MAX_HELD as u32 {
+            instr.note_on(pitch, 1.0, 0.0);
+        }
+        
+        for pitch in 50..49 + MAX_HELD as u32 {
+            instr.note_off(pitch, 0.0);
+        }
+        
+        peak(&mut instr, 0.0, 0.032);
+        assert!(instr.voices[0].is_holding(49, None));
+        
+        // the first note was forgotten, so letting go of the second one is
+        // the end of it.
+        instr.note_off(49, 0.032);
+        peak(&mut instr, 0.032, 0.064);
+        assert!(!instr.is_sounding());
+    }
+    
+    #[test]
+    fn lfos_restart_with_each_note_when_key_synced() {
+        let mut instr = Instrument::new(1);
+        instr.set_bpm(240.0);
+        instr.lfos[0] = Lfo { shape: WaveType::Square, rate: 0.5, sync: true, key_sync: true, ..Lfo::new() };
+        
+        let mut op = Operator::new(WaveType::Sine, true, 203.3);
+        op.send(0, 1.0).lfos[0].amplitude = 1.0;
+        op.add(&mut instr);
+        
+        // half a cycle a beat is two a second, so the tremolo cuts out for
+        // the second quarter of each second.
+        instr.note_on(48, 1.0, 0.0);
+        assert!(peak(&mut instr, 0.0, 0.24) > 0.5);
+        peak(&mut instr, 0.24, 0.26);
+        assert!(peak(&mut instr, 0.26, 0.49) < 1e-6);
+        
+        // a free-running LFO would be half way through its cycle here.
+        instr.note_off(48, 0.768);
+        instr.note_on(50, 1.0, 0.768);
+        assert!(peak(&mut instr, 0.49, 0.768) > 0.5);
+        assert!(peak(&mut instr, 0.768, 1.0) > 0.5);
+        peak(&mut instr, 1.0, 1.03);
+        assert!(peak(&mut instr, 1.03, 1.25) < 1e-6);
+    }
+    
+    #[test]
+    fn band_limited_waves_barely_alias() {
+        for wave in [WaveType::Square, WaveType::Triangle, WaveType::Sawtooth] {
+            let naive = aliasing(wave, false);
+            let band_limited = aliasing(wave, true);
+            assert!(band_limited * 20.0 < naive, "{} vs {}", band_limited, naive);
+        }
+        
+        assert!(aliasing(WaveType::Sine, true) < 1e-6);
+    }
+    
+    #[test]
+    fn the_mod_wheel_moves_routed_sends() {
+        let mut instr = Instrument::new(1);
+        instr.routes[0] = Route { source: Source::ModWheel, destination: Destination::Send(0), amount: -1.0 };
+        Operator::new(WaveType::Sine, true, 203.3).send(0, 1.0).add(&mut instr);
+        
+        // turning the wheel all the way up takes the operator's send down to
+        // nothing, even in the middle of a note.
+        instr.note_on(48, 1.0, 0.0);
+        assert!(peak(&mut instr, 0.0, 0.512) > 0.5);
+        instr.controls.mod_wheel = 1.0;
+        peak(&mut instr, 0.512, 0.544);
+        assert!(peak(&mut instr, 0.544, 1.0) < 1e-6);
+        
+        instr.controls.mod_wheel = 0.5;
+        peak(&mut instr, 1.0, 1.024);
+        let half = peak(&mut instr, 1.024, 1.5);
+        assert!(half > 0.2 && half < 0.8);
+    }
+    
+    #[test]
+    fn notes_are_let_go_of_by_their_own_note_off() {
+        let mut instr = Instrument::new(4);
+        Operator::new(WaveType::Sine, true, 203.3).send(0, 1.0).add(&mut instr);
+        
+        // the second note takes over the first one's voice, but the first
+        // one's note-off doesn't cut it short.
+        let tempo = song::TempoMap::constant(60.0);
+        instr.schedule(song::Note::new(48, 0, 0, 96, 1.0), &tempo);
+        instr.schedule(song::Note::new(48, 0, 48, 144, 1.0), &tempo);
+        
+        assert!(peak(&mut instr, 0.0, 1.9) > 0.5);
+        assert!(peak(&mut instr, 1.9, 2.1) > 0.5);
+        assert!(peak(&mut instr, 2.1, 2.5) < 1e-6);
+        
+        // live notes are held until they're let go of.
+        instr.note_on(50, 1.0, 2.5);
+        assert!(peak(&mut instr, 2.5, 4.0) > 0.5);
+        assert!(instr.is_sounding());
+        
+        instr.note_off(50, 4.0);
+        assert!(peak(&mut instr, 4.0, 4.5) < 1e-6);
+        assert!(!instr.is_sounding());
     }
 }
@@ -0,0 +1,286 @@
+use serde::{Serialize, Deserialize};
+
+use super::MAX_OPERATORS;
+use super::lfo::MAX_LFOS;
+
+/// how many routes each instrument's modulation matrix has.
+pub const MAX_ROUTES: usize = 8;
+
+/// the pitch of middle C, which `Source::Key` is measured from.
+pub const MIDDLE_C: u32 = 48;
+
+/// how many semitones a route of amount 1 moves an operator's pitch by.
+const TRANSPOSE_RANGE: f32 = 24.0;
+
+/// how many octaves (i.e. doublings) a route of amount 1 stretches an
+/// envelope time by.
+const TIME_RANGE: f32 = 4.0;
+
+/// something which can move a parameter.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Source {
+    /// how hard the note was played, from 0 to 1.
+    Velocity,
+    
+    /// how far the note is above middle C, in octaves.
+    Key,
+    
+    /// one of the instrument's LFOs, from -1 to 1.
+    Lfo(usize),
+    
+    /// the level of one of the operators' envelopes, from 0 to 1.
+    Envelope(usize),
+    
+    /// the live mod wheel, from 0 to 1.
+    ModWheel,
+    
+    /// how hard the keys are being pressed down live, from 0 to 1.
+    Aftertouch,
+}
+
+/// a parameter which can be moved by a source.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Destination {
+    /// an operator's pitch, up to TRANSPOSE_RANGE semitones either way.
+    Transpose(usize),
+    
+    /// the times of an operator's envelope, each stretched or squashed by
+    /// up to TIME_RANGE octaves.
+    Attack(usize),
+    Decay(usize),
+    Release(usize),
+    
+    /// an operator's send levels, moved as a fraction of themselves.
+    Send(usize),
+    
+    /// one of an effect's parameters, as listed by `Effect::params`.
+    Effect {
+        effect: usize,
+        param: usize,
+    },
+}
+
+/// a connection in the modulation matrix, moving a destination by a source
+/// multiplied by the amount. a route with an amount of 0 does nothing.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    pub source: Source,
+    pub destination: Destination,
+    
+    /// from -1 to 1, as a fraction of the destination's range.
+    pub amount: f32,
+}
+
+/// the values of an instrument's live controllers, which aren't part of
+/// any particular note.
+#[derive(Clone, Copy, PartialEq, Default)]
+pub struct Controls {
+    pub mod_wheel: f32,
+    pub aftertouch: f32,
+}
+
+/// the value of every source for one voice at some moment.
+#[derive(Clone, Copy, Default)]
+pub struct Sources {
+    pub velocity: f32,
+    pub key: f32,
+    pub lfos: [f32; MAX_LFOS],
+    pub envelopes: [f32; MAX_OPERATORS],
+    pub controls: Controls,
+}
+
+/// how far the modulation matrix moves each operator at some moment.
+pub struct Targets {
+    /// in semitones.
+    pub transpose: [f32; MAX_OPERATORS],
+    
+    /// what each envelope time is multiplied by.
+    pub attack: [f32; MAX_OPERATORS],
+    pub decay: [f32; MAX_OPERATORS],
+    pub release: [f32; MAX_OPERATORS],
+    
+    /// what each operator's send levels are multiplied by.
+    pub send: [f32; MAX_OPERATORS],
+}
+
+impl Source {
+    /// gets the kind of source as a number, for choosing in the GUI, where
+    /// the index of an LFO or operator is chosen separately.
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Source::Velocity => 0,
+            Source::Key => 1,
+            Source::Lfo(_) => 2,
+            Source::Envelope(_) => 3,
+            Source::ModWheel => 4,
+            Source::Aftertouch => 5,
+        }
+    }
+    
+    /// makes a source of the given kind, which uses `index` if it needs an
+    /// LFO or operator.
+    pub fn from(i: u32, index: usize) -> Source {
+        match i % 6 {
+            0 => Source::Velocity,
+            1 => Source::Key,
+            2 => Source::Lfo(index.min(MAX_LFOS - 1)),
+            3 => Source::Envelope(index.min(MAX_OPERATORS - 1)),
+            4 => Source::ModWheel,
+            _ => Source::Aftertouch,
+        }
+    }
+    
+    /// gets the LFO or operator which the source follows, if any.
+    pub fn index(self) -> Option<usize> {
+        match self {
+            Source::Lfo(i) | Source::Envelope(i) => Some(i),
+            _ => None,
+        }
+    }
+}
+
+impl Destination {
+    /// gets the kind of destination as a number, for choosing in the GUI.
+    pub fn to_u32(self) -> u32 {
+        match self {
+            Destination::Transpose(_) => 0,
+            Destination::Attack(_) => 1,
+            Destination::Decay(_) => 2,
+            Destination::Release(_) => 3,
+            Destination::Send(_) => 4,
+            Destination::Effect { .. } => 5,
+        }
+    }
+    
+    /// makes a destination of the given kind, on the operator or effect
+    /// numbered `index`, and the effect parameter `param` if it needs one.
+    pub fn from(i: u32, index: usize, param: usize) -> Destination {
+        match i % 6 {
+            0 => Destination::Transpose(index.min(MAX_OPERATORS - 1)),
+            1 => Destination::Attack(index.min(MAX_OPERATORS - 1)),
+            2 => Destination::Decay(index.min(MAX_OPERATORS - 1)),
+            3 => Destination::Release(index.min(MAX_OPERATORS - 1)),
+            4 => Destination::Send(index.min(MAX_OPERATORS - 1)),
+            _ => Destination::Effect { effect: index, param },
+        }
+    }
+    
+    /// gets the operator or effect which the destination is on.
+    pub fn index(self) -> usize {
+        match self {
+            Destination::Transpose(i)
+                | Destination::Attack(i)
+                | Destination::Decay(i)
+                | Destination::Release(i)
+                | Destination::Send(i)
+                | Destination::Effect { effect: i, .. } => i,
+        }
+    }
+}
+
+impl Route {
+    pub fn new() -> Route {
+        Route {
+            source: Source::Velocity,
+            destination: Destination::Transpose(0),
+            amount: 0.0,
+        }
+    }
+    
+    /// whether the route moves anything at all.
+    pub fn is_active(&self) -> bool {
+        self.amount != 0.0
+    }
+}
+
+impl Default for Route {
+    fn default() -> Route {
+        Route::new()
+    }
+}
+
+impl Sources {
+    /// gets the value of a source. LFOs and operators which don't exist give 0.
+    pub fn get(&self, source: Source) -> f32 {
+        match source {
+            Source::Velocity => self.velocity,
+            Source::Key => self.key,
+            Source::Lfo(i) => self.lfos.get(i).copied().unwrap_or(0.0),
+            Source::Envelope(i) => self.envelopes.get(i).copied().unwrap_or(0.0),
+            Source::ModWheel => self.controls.mod_wheel,
+            Source::Aftertouch => self.controls.aftertouch,
+        }
+    }
+}
+
+impl Targets {
+    /// works out where every route leaves the operators, given the sources.
+    /// routes to effects are left for the instrument to deal with.
+    pub fn new(routes: &[Route; MAX_ROUTES], sources: &Sources) -> Targets {
+        let mut transpose = [0.0; MAX_OPERATORS];
+        let mut times = [[0.0; MAX_OPERATORS]; 3];
+        let mut send = [1.0; MAX_OPERATORS];
+        
+        for route in routes.iter().filter(|r| r.is_active()) {
+            let by = route.amount * sources.get(route.source);
+            let i = route.destination.index();
+            
+            match route.destination {
+                Destination::Effect { .. } => {},
+                _ if i >= MAX_OPERATORS => {},
+                Destination::Transpose(_) => transpose[i] += by * TRANSPOSE_RANGE,
+                Destination::Attack(_) => times[0][i] += by * TIME_RANGE,
+                Destination::Decay(_) => times[1][i] += by * TIME_RANGE,
+                Destination::Release(_) => times[2][i] += by * TIME_RANGE,
+                Destination::Send(_) => send[i] += by,
+            }
+        }
+        
+        let [attack, decay, release] = times.map(|t| t.map(|octaves| 2.0f32.powf(octaves)));
+        
+        Targets {
+            transpose,
+            attack,
+            decay,
+            release,
+            send: send.map(|s| s.max(0.0)),
+        }
+    }
+}
+
+/// adds up how far the routes to one parameter of an effect move it.
+pub fn effect_offset(routes: &[Route; MAX_ROUTES], sources: &Sources, effect: usize, param: usize) -> f32 {
+    routes.iter()
+        .filter(|r| r.is_active() && r.destination == Destination::Effect { effect, param })
+        .map(|r| r.amount * sources.get(r.source))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn adds_up_routes_to_the_same_destination() {
+        let mut routes = [Route::new(); MAX_ROUTES];
+        routes[0] = Route { source: Source::Velocity, destination: Destination::Transpose(1), amount: 0.5 };
+        routes[1] = Route { source: Source::Lfo(1), destination: Destination::Transpose(1), amount: 0.25 };
+        routes[2] = Route { source: Source::ModWheel, destination: Destination::Release(0), amount: -0.25 };
+        routes[3] = Route { source: Source::Key, destination: Destination::Effect { effect: 0, param: 1 }, amount: 1.0 };
+        
+        let sources = Sources {
+            velocity: 1.0,
+            key: -0.5,
+            lfos: [0.0, -1.0],
+            controls: Controls { mod_wheel: 1.0, aftertouch: 0.0 },
+            ..Sources::default()
+        };
+        
+        let targets = Targets::new(&routes, &sources);
+        assert_eq!(targets.transpose[1], 6.0);
+        assert_eq!(targets.release[0], 0.5);
+        assert_eq!(targets.attack[0], 1.0);
+        assert_eq!(effect_offset(&routes, &sources, 0, 1), -0.5);
+        assert_eq!(effect_offset(&routes, &sources, 0, 0), 0.0);
+    }
+}
@@ -9,6 +9,21 @@ pub const REAL_HEIGHT: u32 = SCREEN_HEIGHT * SCREEN_SCALE;
 pub const SPECTRUM_WIDTH: u32 = 128;
 pub const SPECTRUM_HEIGHT: u32 = 32;
 
+/// the width of the mixer strip beside each instrument's scope.
+pub const MIXER_WIDTH: u32 = 26;
+
+/// the height of the time ruler along the top of the sequencer.
+pub const RULER_HEIGHT: u32 = 7;
+
+/// the height of the velocity lane along the bottom of the sequencer.
+pub const VELOCITY_HEIGHT: u32 = 16;
+
+/// the highest octave which the computer keyboard can play from.
+pub const MAX_OCTAVE: u32 = 7;
+
+/// the number of edits which can be undone.
+pub const HISTORY_LENGTH: usize = 256;
+
 pub const FG: Color = Color { r: 213, g: 200, b: 204, a: 255 };
 pub const FG2: Color = Color { r: 139, g: 139, b: 139, a: 255 };
 pub const PANEL_BG: Color = Color { r: 29, g: 24, b: 30, a: 255 };
@@ -21,11 +36,15 @@ pub const SEQ_NOTE: Color = Color { r: 203, g: 185, b: 191, a: 255 };
 pub const SEQ_GHOST_NOTE: Color = Color { r: 121, g: 89, b: 128, a: 255 };
 pub const SEQ_PLAYHEAD: Color = Color { r: 240, g: 44, b: 44, a: 255 };
 pub const SEQ_DIVIDER: Color = Color { r: 65, g: 57, b: 66, a: 255 };
+pub const SEQ_TEMPO: Color = Color { r: 98, g: 142, b: 161, a: 255 };
+pub const SEQ_SELECTED: Color = Color { r: 236, g: 151, b: 196, a: 255 };
+pub const SEQ_LOOP: Color = Color { r: 71, g: 52, b: 75, a: 255 };
 
 pub const CONTROL_BG: Color = Color { r: 15, g: 14, b: 15, a: 255 };
 pub const CONTROL_HOVER: Color = Color { r: 53, g: 24, b: 51, a: 255 };
 pub const CONTROL_ACTIVE: Color = Color { r: 109, g: 73, b: 106, a: 255 };
 pub const SLIDER_HANDLE: Color = Color { r: 200, g: 200, b: 200, a: 255 };
+pub const RECORD: Color = Color { r: 240, g: 44, b: 44, a: 255 };
 pub const DIM_LABEL: Color = Color { r: 72, g: 62, b: 74, a: 255 };
 
 pub const EQ_FG: Color = Color { r: 156, g: 121, b: 133, a: 255 };
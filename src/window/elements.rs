@@ -1,14 +1,16 @@
 use std::f32::consts;
-
-use std::sync::{Arc, Mutex, mpsc};
+use std::fs::OpenOptions;
+use std::path::Path;
 
 use sdl2::{mouse, keyboard};
+use sdl2::keyboard::Mod;
 use sdl2::event::Event;
 use sdl2::rect::{Rect, Point};
 use sdl2::pixels::Color;
 
-use crate::{player::*, synth};
+use crate::{history::{Edit, History}, mixer, record::{Jump, Take}, remote::Remote, synth};
 use crate::song;
+use crate::midi;
 
 use super::font;
 use super::constants::*;
@@ -57,7 +59,6 @@ pub struct Panel {
 
 pub struct Spectrum {
     pub rect: Rect,
-    pub player: Arc<Mutex<Player>>,
     pub index: usize,
     pub wave_scale: f32,
 }
@@ -67,12 +68,44 @@ pub struct Sequencer {
     pub scroll_x: f32,
     pub scroll_y: f32,
     pub num_octaves: u32,
+    pub drag: Option<SeqDrag>,
     pub drag_start: Option<Point>,
     pub drag_end: Option<Point>,
     pub temp_note: Option<song::Note>,
     pub place_dur: u32,
-    pub to_delete: Option<usize>,
-    pub on_change: Box<dyn FnMut(&mut WindowState) -> ()>,
+    pub hovered: Option<song::Note>,
+    pub loop_start: Option<song::Time>,
+    
+    /// the selected notes in the selected instrument's part.
+    pub selection: Vec<song::Note>,
+    
+    /// where the selected notes will end up, while they're being dragged.
+    pub preview: Vec<song::Note>,
+    
+    /// the new velocities of the notes in the velocity lane, while they're
+    /// being drawn in.
+    pub velocities: Vec<(song::Note, f32)>,
+}
+
+/// what a drag across the sequencer's notes is doing.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SeqDrag {
+    /// placing a new note where the mouse was pressed. moving away turns
+    /// the drag into a selection instead.
+    Place,
+    
+    /// dragging out a box, and selecting every note inside it.
+    Select,
+    
+    /// moving the selected notes in time and pitch.
+    Move,
+    
+    /// changing the length of the selected notes.
+    Resize,
+    
+    /// drawing velocities in the velocity lane. the drag's points are kept as
+    /// a sequencer x position and a screen y position.
+    Velocity,
 }
 
 pub struct Stepper {
@@ -188,9 +221,37 @@ pub struct Rectangle {
 }
 
 pub struct WindowState {
-    pub player: Arc<Mutex<Player>>,
-    pub note_channel: mpsc::Sender<(usize, song::Note)>,
+    pub player: Remote,
     pub song: song::Song,
+    pub history: History,
+    pub clipboard: Vec<song::Note>,
+    pub keymod: Mod,
+    
+    /// the octave played by the bottom row of the computer keyboard.
+    pub octave: u32,
+    
+    /// the keys on the computer keyboard which are playing notes, and the
+    /// instrument and pitch each one is playing.
+    pub held_keys: Vec<(keyboard::Keycode, usize, u32)>,
+    
+    /// whether notes played live are recorded into the song while it plays.
+    pub record: bool,
+    
+    /// whether recording keeps the notes which were already there, rather
+    /// than replacing them.
+    pub overdub: bool,
+    
+    /// whether recorded notes are snapped to the sequencer's quantisation.
+    pub record_quantize: bool,
+    
+    /// whether starting to play while recording counts in a bar first.
+    pub count_in: bool,
+    
+    /// the notes being recorded, while the song is playing and recording.
+    pub take: Option<Take>,
+    
+    /// the player's counts of seeks and loop wraps, as of the last frame.
+    pub jumps: (u64, u64),
     pub filename: Option<String>,
     pub selected_instrument: usize,
     pub mouse_x: u32,
@@ -199,6 +260,12 @@ pub struct WindowState {
     pub seq_scale_y: u32,
     pub seq_quantize: u32,
     pub current_op: usize,
+    
+    /// which of the selected instrument's LFOs is shown.
+    pub current_lfo: usize,
+    
+    /// which route in the selected instrument's modulation matrix is shown.
+    pub current_route: usize,
 }
 
 #[derive(Clone)]
@@ -227,8 +294,11 @@ impl Element for Panel {
         for child in &mut self.children {
             let mut e = event.clone();
             
+            // shortcuts reach every element, not just the one under the
+            // mouse.
             let propagate_anyway = match e.event {
                 Event::MouseButtonUp { .. } | Event::MouseMotion { .. } => true,
+                Event::KeyDown { keycode: Some(keycode), keymod, .. } => is_shortcut(keycode, keymod),
                 _ => false,
             };
             
@@ -259,10 +329,11 @@ impl Element for Rectangle {
 
 impl Element for Spectrum {
     fn render(&mut self, buf: &mut [u8], state: &WindowState) {
-        let player = self.player.lock().unwrap();
         let safe = safe_area(self.rect());
+        let scope = state.player.scope(self.index)
+            .filter(|_| self.index < state.player.instruments().len());
         
-        if self.index < player.instruments.len() {
+        if let Some(scope) = scope {
             draw_rect(buf, self.rect, WIN_BG, Some(BORDER), Some(CORNER));
             
             assert_eq!(SPECTRUM_WIDTH, safe.width());
@@ -272,7 +343,9 @@ impl Element for Spectrum {
             
             draw_rect(buf, Rect::new(safe.x, safe.y + safe.h / 2, safe.width(), 1), colour, None, None);
             
-            let samples = &player.instruments[self.index].hold_buf;
+            let mut samples = [0.0; SPECTRUM_WIDTH as usize];
+            scope.read(&mut samples);
+            
             for i in 0..SPECTRUM_WIDTH as usize {
                 let sample = ((samples[i] * self.wave_scale) as i32 + axis).clamp(safe.top(), safe.bottom());
                 
@@ -333,8 +406,223 @@ impl Sequencer {
         y / state.seq_scale_y
     }
     
-    fn draw_note(&self, buf: &mut [u8], note: &song::Note, bg: Color, state: &WindowState) {
+    /// works out where the selected notes would be if they were dragged from
+    /// `start` to `end`, keeping to the quantisation grid.
+    fn dragged(&self, start: Point, end: Point, state: &WindowState) -> Vec<song::Note> {
+        let dt = self.x_to_t(end.x.max(0) as u32, state).diff(self.x_to_t(start.x.max(0) as u32, state));
+        let dp = self.y_to_pitch(end.y.max(0) as u32, state) as i32 - self.y_to_pitch(start.y.max(0) as u32, state) as i32;
+        
+        match self.drag {
+            Some(SeqDrag::Move) => {
+                // none of the notes can go before the start of the song, or
+                // off either end of the keyboard.
+                let earliest = self.selection.iter().map(|n| n.start.as_divs()).min().unwrap_or(0) as i32;
+                let lowest = self.selection.iter().map(|n| n.pitch).min().unwrap_or(0) as i32;
+                let highest = self.selection.iter().map(|n| n.pitch).max().unwrap_or(0) as i32;
+                let dt = dt.max(-earliest);
+                let dp = dp.clamp(-lowest, (12 * self.num_octaves) as i32 - 1 - highest);
+                
+                self.selection.iter().map(|&n| song::Note {
+                    start: song::Time::from_divs((n.start.as_divs() as i32 + dt) as u32),
+                    pitch: (n.pitch as i32 + dp) as u32,
+                    ..n
+                }).collect()
+            },
+            Some(SeqDrag::Resize) => {
+                let shortest = (song::BEAT_DIVISIONS / state.seq_quantize) as i32;
+                
+                self.selection.iter().map(|&n| song::Note {
+                    duration: (n.duration as i32 + dt).max(shortest) as u32,
+                    ..n
+                }).collect()
+            },
+            _ => self.selection.clone(),
+        }
+    }
+    
+    /// finds the notes in the selected part which are at least partly inside
+    /// the box between two points.
+    fn notes_in_box(&self, a: Point, b: Point, state: &WindowState) -> Vec<song::Note> {
+        let (left, right) = (a.x.min(b.x).max(0) as u32, a.x.max(b.x).max(0) as u32);
+        let low = self.y_to_pitch(a.y.min(b.y).max(0) as u32, state);
+        let high = self.y_to_pitch(a.y.max(b.y).max(0) as u32, state);
+        
+        state.song.parts[state.selected_instrument]
+            .iter()
+            .filter(|n| n.pitch >= low && n.pitch <= high)
+            .filter(|n| self.t_to_x(n.start, state) <= right && self.t_to_x(n.start.add(n.duration), state) > left)
+            .copied()
+            .collect()
+    }
+    
+    /// acts on the sequencer's keyboard shortcuts, wherever the mouse is.
+    /// pasting puts the first of the copied notes under the mouse, or at
+    /// the playhead if the mouse is somewhere else.
+    fn handle_shortcut(&mut self, keycode: keyboard::Keycode, keymod: Mod, inside: bool, state: &mut WindowState) {
+        let part = state.selected_instrument;
+        self.selection.retain(|n| state.song.parts[part].contains(n));
+        
+        let command = is_command(keymod);
+        
+        match keycode {
+            keyboard::Keycode::Delete | keyboard::Keycode::Backspace => {
+                state.replace_notes(part, std::mem::take(&mut self.selection), Vec::new());
+            },
+            keyboard::Keycode::A if command => {
+                self.selection = state.song.parts[part].clone();
+            },
+            keyboard::Keycode::C if command => {
+                state.clipboard = self.selection.clone();
+            },
+            keyboard::Keycode::X if command => {
+                state.clipboard = self.selection.clone();
+                state.replace_notes(part, std::mem::take(&mut self.selection), Vec::new());
+            },
+            keyboard::Keycode::V if command => {
+                let x = if inside {
+                    state.mouse_x as i32 - self.rect.x + self.scroll_x as i32 - 1
+                } else {
+                    let beats = state.player.tempo().beats_at(state.player.playhead());
+                    (beats * state.seq_scale_x as f64) as i32
+                };
+                
+                let t = self.x_to_t(x.max(0) as u32, state);
+                self.selection = state.paste(part, t);
+            },
+            _ => {},
+        }
+    }
+    
+    fn end_drag(&mut self) {
+        self.drag = None;
+        self.drag_start = None;
+        self.drag_end = None;
+        self.temp_note = None;
+        self.preview.clear();
+        self.velocities.clear();
+    }
+    
+    fn ruler(&self) -> Rect {
         let safe = safe_area(self.rect);
+        Rect::new(safe.x, safe.y, safe.width(), RULER_HEIGHT)
+    }
+    
+    /// the lane along the bottom of the sequencer showing each note's velocity.
+    fn velocity_lane(&self) -> Rect {
+        let safe = safe_area(self.rect);
+        Rect::new(safe.x, safe.bottom() - VELOCITY_HEIGHT as i32, safe.width(), VELOCITY_HEIGHT)
+    }
+    
+    /// the part of the sequencer where the notes are, above the velocity lane.
+    fn grid(&self) -> Rect {
+        let safe = safe_area(self.rect);
+        Rect::new(safe.x, safe.y, safe.width(), safe.height() - VELOCITY_HEIGHT)
+    }
+    
+    /// works out the velocity for a height on the screen in the velocity lane.
+    fn lane_velocity(&self, real_y: i32) -> f32 {
+        let lane = self.velocity_lane();
+        ((lane.bottom() - 1 - real_y) as f32 / (lane.height() - 2) as f32).clamp(0.0, 1.0)
+    }
+    
+    /// sets the velocity of each note whose bar is between two points in the
+    /// velocity lane, as if a line had been drawn between them. while any
+    /// notes are selected, only they are changed.
+    fn draw_velocities(&mut self, a: Point, b: Point, state: &WindowState) {
+        let (from, to) = if a.x <= b.x { (a, b) } else { (b, a) };
+        
+        for &note in &state.song.parts[state.selected_instrument] {
+            let x = self.t_to_x(note.start, state) as i32;
+            
+            if x < from.x - 1 || x > to.x + 1 || !(self.selection.is_empty() || self.selection.contains(&note)) {
+                continue;
+            }
+            
+            let y = if from.x == to.x {
+                to.y
+            } else {
+                from.y + (to.y - from.y) * (x.clamp(from.x, to.x) - from.x) / (to.x - from.x)
+            };
+            
+            let velocity = self.lane_velocity(y);
+            
+            match self.velocities.iter_mut().find(|(n, _)| *n == note) {
+                Some(v) => v.1 = velocity,
+                None => self.velocities.push((note, velocity)),
+            }
+        }
+    }
+    
+    /// draws a bar for each note's velocity in the velocity lane.
+    fn draw_velocity_lane(&self, buf: &mut [u8], state: &WindowState) {
+        let lane = self.velocity_lane();
+        draw_rect(buf, lane, CONTROL_BG, None, None);
+        draw_rect(buf, Rect::new(lane.x, lane.y, lane.width(), 1), BORDER, None, None);
+        
+        for note in &state.song.parts[state.selected_instrument] {
+            let velocity = self.velocities.iter()
+                .find(|(n, _)| n == note)
+                .map_or(note.velocity, |(_, v)| *v);
+            
+            let x = lane.x + self.t_to_x(note.start, state) as i32 - self.scroll_x as i32;
+            let h = ((velocity * (lane.height() - 2) as f32).round() as u32).max(1);
+            let colour = if self.selection.contains(note) { SEQ_SELECTED } else { SEQ_NOTE };
+            
+            for bar in [Rect::new(x, lane.bottom() - h as i32, 1, h), Rect::new(x - 1, lane.bottom() - h as i32, 3, 1)] {
+                if let Some(bar) = clamp_rect(bar, lane) {
+                    draw_rect(buf, bar, colour, None, None);
+                }
+            }
+        }
+    }
+    
+    /// draws the time ruler, showing the bar numbers and the loop region.
+    fn draw_ruler(&self, buf: &mut [u8], state: &WindowState, meters: &song::MeterMap) {
+        let ruler = self.ruler();
+        draw_rect(buf, ruler, CONTROL_BG, None, None);
+        
+        // while the loop is being dragged out, show where it'll go.
+        let region = match self.loop_start {
+            Some(start) => {
+                let end = self.x_to_t((state.mouse_x as i32 - ruler.x).max(0) as u32 + self.scroll_x as u32, state);
+                Some(if start.as_divs() <= end.as_divs() { (start, end) } else { (end, start) })
+            },
+            None => state.player.loop_region(),
+        };
+        
+        if let Some((start, end)) = region {
+            let x = ruler.x + self.t_to_x(start, state) as i32 - self.scroll_x as i32;
+            let w = self.t_to_x(end, state) - self.t_to_x(start, state);
+            
+            if let Some(rect) = clamp_rect(Rect::new(x, ruler.y, w.max(1), ruler.height()), ruler) {
+                draw_rect(buf, rect, SEQ_LOOP, None, None);
+            }
+        }
+        
+        let mut last_bar = None;
+        
+        for real_x in 0..ruler.width() {
+            let x = real_x + self.scroll_x as u32;
+            let (bar, _) = meters.position(song::Time::from_divs(x * song::BEAT_DIVISIONS / state.seq_scale_x));
+            
+            if last_bar != Some(bar) {
+                let label = format!("{}", bar + 1);
+                let label_x = ruler.x as u32 + real_x;
+                
+                set_pixel(buf, label_x, ruler.y as u32 + 1, DIM_LABEL);
+                set_pixel(buf, label_x, ruler.y as u32 + 2, DIM_LABEL);
+                
+                if label_x + 2 + measure_text(&label) < ruler.right() as u32 {
+                    draw_text(buf, label_x + 2, ruler.y as u32 + 1, DIM_LABEL, &label);
+                }
+                
+                last_bar = Some(bar);
+            }
+        }
+    }
+    
+    fn draw_note(&self, buf: &mut [u8], note: &song::Note, bg: Color, state: &WindowState) {
+        let grid = self.grid();
         
         let mut rect = Rect::new(
             self.t_to_x(note.start, state) as i32,
@@ -343,10 +631,10 @@ impl Sequencer {
             state.seq_scale_y,
         );
         
-        rect.x = rect.x + safe.x - self.scroll_x as i32;
-        rect.y = safe.y + safe.h - (rect.y - self.scroll_y as i32);
+        rect.x = rect.x + grid.x - self.scroll_x as i32;
+        rect.y = grid.y + grid.h - (rect.y - self.scroll_y as i32);
         
-        if let Some(rect) = clamp_rect(rect, safe) {
+        if let Some(rect) = clamp_rect(rect, grid) {
             draw_rect(buf, rect, bg, None, if rect.w <= 1 { None } else { Some(TRANSPARENT) });
         }
     }
@@ -355,9 +643,22 @@ impl Sequencer {
 impl Element for Sequencer {
     fn render(&mut self, buf: &mut [u8], state: &WindowState) {
         let safe = safe_area(self.rect);
+        let grid = self.grid();
         draw_rect(buf, self.rect, SEQ_BACKGROUND[0], Some(BORDER), Some(CORNER));
         
-        for real_y in 0..safe.height() {
+        // the first unit of each bar is shaded differently, so work out which
+        // columns those are before drawing.
+        let meters = state.song.meter_map();
+        let is_first: Vec<bool> = (0..safe.width())
+            .map(|real_x| {
+                let x = real_x + self.scroll_x as u32;
+                let t = song::Time::from_divs(x * song::BEAT_DIVISIONS / state.seq_scale_x);
+                let meter = meters.meter_at(t);
+                meter.numerator > 1 && meters.position(t).1 < meter.unit_divs()
+            })
+            .collect();
+        
+        for real_y in 0..grid.height() {
             let y = real_y + self.scroll_y as u32;
             let row = (y / state.seq_scale_y) as usize;
             
@@ -369,32 +670,83 @@ impl Element for Sequencer {
                 let bg = if subdiv == state.seq_scale_x-1 {
                     SEQ_DIVIDER
                 } else {
-                    let is_first = state.song.beats_per_bar > 1 && col % state.song.beats_per_bar == 0;
-                    SEQ_BACKGROUND[12 * if is_first { 0 } else { 1 } + row % 12]
+                    SEQ_BACKGROUND[12 * if is_first[real_x as usize] { 0 } else { 1 } + row % 12]
                 };
                 
-                set_pixel(buf, real_x + safe.x as u32, safe.height() - real_y - 1 + safe.y as u32, bg);
+                set_pixel(buf, real_x + grid.x as u32, grid.height() - real_y - 1 + grid.y as u32, bg);
             }
         }
         
+        // while the selection is being dragged, it's drawn where it'll end up.
+        let dragging = matches!(self.drag, Some(SeqDrag::Move) | Some(SeqDrag::Resize));
+        
         for note in &state.song.parts[state.selected_instrument] {
-            self.draw_note(buf, note, SEQ_NOTE, state);
+            if !self.selection.contains(note) {
+                self.draw_note(buf, note, SEQ_NOTE, state);
+            } else if !dragging {
+                self.draw_note(buf, note, SEQ_SELECTED, state);
+            }
+        }
+        
+        if dragging {
+            for note in &self.preview {
+                self.draw_note(buf, note, SEQ_SELECTED, state);
+            }
         }
         
+        if let (Some(SeqDrag::Select), Some(start), Some(end)) = (self.drag, self.drag_start, self.drag_end) {
+            let x = grid.x + start.x.min(end.x) - self.scroll_x as i32;
+            let y = grid.y + grid.h - (start.y.max(end.y) - self.scroll_y as i32);
+            let w = (start.x - end.x).unsigned_abs() + 1;
+            let h = (start.y - end.y).unsigned_abs() + 1;
+            
+            for edge in [
+                Rect::new(x, y, w, 1), Rect::new(x, y + h as i32 - 1, w, 1),
+                Rect::new(x, y, 1, h), Rect::new(x + w as i32 - 1, y, 1, h),
+            ] {
+                if let Some(edge) = clamp_rect(edge, grid) {
+                    draw_rect(buf, edge, SEQ_SELECTED, None, None);
+                }
+            }
+        }
+        
+        // mark each tempo change with a line and its new tempo. ramps are
+        // shown with a '~', since they're reached gradually.
+        for change in &state.song.tempo_changes {
+            let x = safe.x + self.t_to_x(change.time, state) as i32 - self.scroll_x as i32;
+            
+            if let Some(marker) = clamp_rect(Rect::new(x, grid.y, 1, grid.height()), grid) {
+                draw_rect(buf, marker, SEQ_TEMPO, None, None);
+            }
+            
+            let label = format!("{}{}", if change.ramp { "~" } else { "" }, change.bpm.round());
+            
+            if x + 2 >= safe.x && x + 2 + (measure_text(&label) as i32) < safe.right() {
+                draw_text(buf, (x + 2) as u32, safe.y as u32 + RULER_HEIGHT + 1, SEQ_TEMPO, &label);
+            }
+        }
+        
+        self.draw_ruler(buf, state, &meters);
+        self.draw_velocity_lane(buf, state);
+        
         if safe.contains_point(Point::new(state.mouse_x as i32, state.mouse_y as i32)) {
-            if let Some(note) = self.temp_note {
+            if let (Some(SeqDrag::Velocity), Some(end)) = (self.drag, self.drag_end) {
+                draw_tooltip(buf, state.mouse_x + 4, state.mouse_y - 4, Vec::from([
+                    format!("\x06 {:.2}", self.lane_velocity(end.y)),
+                ]));
+            } else if let Some(note) = self.temp_note {
                 self.draw_note(buf, &note, SEQ_GHOST_NOTE, state);
                 
                 draw_tooltip(buf, state.mouse_x + 4, state.mouse_y - 4, Vec::from([
-                    format!("\x00 {}:{}, bar {}", note.start.beat, note.start.division, 1 + note.start.beat / state.song.beats_per_bar),
+                    format!("\x00 {}:{}, bar {}", note.start.beat, note.start.division, 1 + meters.position(note.start).0),
                     format!("\x04 {} {}", note.name(), note.octave()),
+                    format!("\x06 {:.2}", note.velocity),
                 ]));
             }
         }
         
         if let Some(playhead) = {
-            let p = state.player.lock().unwrap();
-            let cell_x = p.playhead * p.bps;
+            let cell_x = state.player.tempo().beats_at(state.player.playhead());
             let div = (cell_x.fract() * state.seq_scale_x as f64) as i32;
             let head_x = cell_x as i32 * state.seq_scale_x as i32 + div;
             
@@ -416,71 +768,229 @@ impl Element for Sequencer {
     
     fn handle(&mut self, event: InputEvent, state: &mut WindowState) {
         let safe = safe_area(self.rect);
+        let inside = safe.contains_point(Point::new(event.real_x, event.real_y));
         
-        if !safe.contains_point(Point::new(event.real_x, event.real_y)) {
+        if let Event::KeyDown { keycode: Some(keycode), keymod, .. } = event.event {
+            self.handle_shortcut(keycode, keymod, inside, state);
+            return;
+        }
+        
+        if !inside {
+            // letting go outside of the sequencer gives up on the drag.
+            if let Event::MouseButtonUp { .. } = event.event {
+                self.end_drag();
+            }
+            
             return;
         }
         
         let point = Point::new(
             event.x + self.scroll_x as i32 - 1,
-            safe.h - event.y + self.scroll_y as i32,
+            self.grid().h - event.y + self.scroll_y as i32,
         );
         
+        // clicking on the ruler seeks to that point, and dragging across it
+        // with the right button sets the loop region. a right click without
+        // a drag clears the loop.
+        let on_ruler = self.ruler().contains_point(Point::new(event.real_x, event.real_y));
+        
+        // dragging across the velocity lane draws in the velocities of the
+        // notes underneath.
+        let on_lane = self.velocity_lane().contains_point(Point::new(event.real_x, event.real_y));
+        let lane_point = Point::new(point.x, event.real_y);
+        
+        match event.event {
+            Event::MouseButtonDown { mouse_btn: mouse::MouseButton::Left, .. } if on_lane => {
+                self.drag = Some(SeqDrag::Velocity);
+                self.drag_start = Some(lane_point);
+                self.drag_end = Some(lane_point);
+                self.temp_note = None;
+                self.draw_velocities(lane_point, lane_point, state);
+                return;
+            },
+            Event::MouseMotion { .. } if self.drag == Some(SeqDrag::Velocity) => {
+                if let Some(last) = self.drag_end {
+                    self.draw_velocities(last, lane_point, state);
+                }
+                
+                self.drag_end = Some(lane_point);
+                return;
+            },
+            Event::MouseButtonUp { mouse_btn: mouse::MouseButton::Left, .. } if self.drag == Some(SeqDrag::Velocity) => {
+                let (removed, added): (Vec<song::Note>, Vec<song::Note>) = std::mem::take(&mut self.velocities)
+                    .into_iter()
+                    .filter(|(n, v)| n.velocity != *v)
+                    .map(|(n, velocity)| (n, song::Note { velocity, ..n }))
+                    .unzip();
+                
+                for note in &mut self.selection {
+                    if let Some(i) = removed.iter().position(|n| n == note) {
+                        *note = added[i];
+                    }
+                }
+                
+                state.edit(Edit::Notes { part: state.selected_instrument, removed, added });
+                self.end_drag();
+                return;
+            },
+            Event::MouseMotion { .. } if on_lane && self.drag.is_none() => {
+                self.temp_note = None;
+                self.hovered = None;
+                return;
+            },
+            _ => {},
+        }
+        
+        match event.event {
+            Event::MouseButtonDown { mouse_btn: mouse::MouseButton::Left, .. } if on_ruler => {
+                let t = self.x_to_t(point.x as u32, state);
+                let seconds = state.player.tempo().seconds_at(t.as_beats());
+                state.player.seek(seconds);
+                return;
+            },
+            Event::MouseButtonDown { mouse_btn: mouse::MouseButton::Right, .. } if on_ruler => {
+                self.loop_start = Some(self.x_to_t(point.x as u32, state));
+                return;
+            },
+            Event::MouseButtonUp { mouse_btn: mouse::MouseButton::Right, .. } => {
+                if let Some(start) = self.loop_start.take() {
+                    let end = self.x_to_t(point.x as u32, state);
+                    
+                    state.player.set_loop_region(match start.diff(end) {
+                        0 => None,
+                        d if d < 0 => Some((start, end)),
+                        _ => Some((end, start)),
+                    });
+                }
+                
+                return;
+            },
+            Event::MouseMotion { .. } if on_ruler && self.drag.is_none() => {
+                self.temp_note = None;
+                self.hovered = None;
+                return;
+            },
+            _ => {},
+        }
+        
+        // notes can be changed behind the sequencer's back (e.g. by undoing),
+        // and the selection only applies to the selected instrument.
+        let part = state.selected_instrument;
+        self.selection.retain(|n| state.song.parts[part].contains(n));
+        
         match event.event {
             Event::MouseWheel { x, y, .. } => {
                 self.scroll_x = (self.scroll_x + x as f32).max(0.0);
                 self.scroll_y = (self.scroll_y + y as f32)
-                    .clamp(0.0, (state.seq_scale_y * 12 * self.num_octaves - (self.rect.height() - 2)) as f32);
+                    .clamp(0.0, (state.seq_scale_y * 12 * self.num_octaves - (self.grid().height() - RULER_HEIGHT)) as f32);
             },
             Event::MouseButtonDown { mouse_btn: mouse::MouseButton::Left, .. } => {
-                self.drag_start = Some(point);
                 let t = self.x_to_t(point.x as u32, state);
                 let pitch = self.y_to_pitch(point.y as u32, state);
+                let shift = state.keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
                 
-                self.temp_note = Some(song::Note::new(pitch, t.beat, t.division, self.place_dur, 1.0));
+                self.drag_start = Some(point);
+                self.drag_end = Some(point);
+                self.temp_note = None;
+                
+                self.drag = match (state.find_note(part, t, pitch), shift) {
+                    // shift-clicking a note adds it to the selection, or
+                    // takes it out again.
+                    (Some(note), true) => {
+                        match self.selection.iter().position(|n| *n == note) {
+                            Some(i) => { self.selection.remove(i); },
+                            None => self.selection.push(note),
+                        }
+                        
+                        None
+                    },
+                    
+                    // grabbing a note drags the whole selection along with
+                    // it, or stretches it if the note was grabbed by its end.
+                    (Some(note), false) => {
+                        if !self.selection.contains(&note) {
+                            self.selection = vec![note];
+                        }
+                        
+                        self.hovered = Some(note);
+                        self.preview = self.selection.clone();
+                        
+                        let end = self.t_to_x(note.start.add(note.duration), state) as i32;
+                        
+                        if end - point.x <= 2 && self.t_to_x(song::Time::new(0, note.duration), state) > 3 {
+                            Some(SeqDrag::Resize)
+                        } else {
+                            Some(SeqDrag::Move)
+                        }
+                    },
+                    (None, true) => Some(SeqDrag::Select),
+                    (None, false) => {
+                        self.selection.clear();
+                        self.temp_note = Some(song::Note::new(pitch, t.beat, t.division, self.place_dur, 1.0));
+                        Some(SeqDrag::Place)
+                    },
+                };
             },
             Event::MouseMotion { mousestate, .. } => {
-                self.to_delete = None;
-                
                 let t = self.x_to_t(point.x as u32, state);
                 let pitch = self.y_to_pitch(point.y as u32, state);
                 
-                if let Some(start) = self.drag_start {
-                    let start_t = self.x_to_t(start.x as u32, state);
-                    let dur = t.diff(start_t);
-                    
-                    if mousestate.left() && dur >= (song::BEAT_DIVISIONS / state.seq_quantize) as i32 {
+                match (self.drag, self.drag_start) {
+                    // dragging away from empty space drags out a box to
+                    // select, rather than placing a note.
+                    (Some(SeqDrag::Place), Some(start)) => {
+                        if mousestate.left() && (point.x - start.x).abs() + (point.y - start.y).abs() > 2 {
+                            self.drag = Some(SeqDrag::Select);
+                            self.drag_end = Some(point);
+                            self.temp_note = None;
+                        }
+                    },
+                    (Some(_), Some(start)) => {
                         self.drag_end = Some(point);
+                        self.preview = self.dragged(start, point, state);
                         
-                        self.temp_note = self.temp_note.map(|mut n| {
-                            let diff = t.diff(n.start);
-                            if diff > 0 {
-                                n.duration = diff as u32;
-                            }
-                            n
-                        });
-                    }
-                } else if let Some((i, hovered)) = state.find_note(state.selected_instrument, t, pitch) {
-                    self.temp_note = Some(hovered);
-                    self.to_delete = Some(i);
-                } else {
-                    self.temp_note = Some(song::Note::new(pitch, t.beat, t.division, self.place_dur, 1.0));
+                        // once the notes have moved, letting go shouldn't
+                        // count as a click, even if they're put back.
+                        if self.preview != self.selection {
+                            self.hovered = None;
+                        }
+                    },
+                    _ => {
+                        self.hovered = state.find_note(part, t, pitch);
+                        self.temp_note = self.hovered
+                            .or(Some(song::Note::new(pitch, t.beat, t.division, self.place_dur, 1.0)));
+                    },
                 }
             },
             Event::MouseButtonUp { mouse_btn: mouse::MouseButton::Left, .. } => {
-                if let Some(i) = self.to_delete {
-                    state.song.parts[state.selected_instrument].remove(i);
-                    self.to_delete = None;
-                } else if let Some(note) = self.temp_note {
-                    state.add_note(state.selected_instrument, note);
-                    (self.on_change)(state);
-                    self.place_dur = note.duration;
+                match self.drag {
+                    Some(SeqDrag::Place) => if let Some(note) = self.temp_note {
+                        state.add_note(part, note);
+                        self.place_dur = note.duration;
+                    },
+                    Some(SeqDrag::Select) => if let (Some(start), Some(end)) = (self.drag_start, self.drag_end) {
+                        for note in self.notes_in_box(start, end, state) {
+                            if !self.selection.contains(&note) {
+                                self.selection.push(note);
+                            }
+                        }
+                    },
+                    Some(SeqDrag::Move) | Some(SeqDrag::Resize) => {
+                        if self.preview != self.selection {
+                            let moved = std::mem::take(&mut self.preview);
+                            state.replace_notes(part, self.selection.clone(), moved.clone());
+                            self.selection = moved;
+                        } else if let Some(note) = self.hovered.take() {
+                            // clicking a note without dragging it deletes it.
+                            state.edit(Edit::remove_note(part, note));
+                            self.selection.retain(|n| *n != note);
+                        }
+                    },
+                    _ => {},
                 }
                 
-                self.drag_start = None;
-                self.drag_end = None;
-                self.temp_note = None;
-            }
+                self.end_drag();
+            },
             _ => {},
         }
     }
@@ -971,27 +1481,283 @@ impl Element for Knob {
 }
 
 impl WindowState {
-    fn add_note(&mut self, part: usize, note: song::Note) {
-        let mut overlaps = Vec::new();
-        let part = &mut self.song.parts[part];
+    /// loads a song from a file, replacing the current song and the player's
+    /// instruments.
+    pub fn open_song(&mut self, path: String) -> Result<(), String> {
+        let is_midi = Path::new(&path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case("mid") || e.eq_ignore_ascii_case("midi"));
         
-        for (i, existing) in part.iter().enumerate() {
-            if existing.pitch == note.pitch && existing.overlap(note) {
-                overlaps.push(i);
-            }
+        if is_midi {
+            return self.import_midi(path);
         }
         
-        for (n, i) in overlaps.iter().enumerate() {
-            part.remove(i - n);
+        let file = OpenOptions::new().read(true).open(&path)
+            .map_err(|err| format!("could not open file: {}", err))?;
+        
+        let song = song::Song::load(file)
+            .map_err(|err| format!("error reading file: {}", err))?;
+        
+        // a song which doesn't make sense could crash the player.
+        let problems = song.validate();
+        
+        if !problems.is_empty() {
+            return Err(format!("invalid song:\n  {}", problems.join("\n  ")));
         }
         
-        part.push(note);
+        self.song = song;
+        self.player.set_tempo(self.song.tempo_map());
+        self.player.set_loop_region(None);
+        self.player.load_instruments(&self.song.instruments);
+        self.player.set_mixer(self.song.mixer.clone());
+        self.player.flush_notes();
+        
+        self.take = None;
+        self.history.clear();
+        self.filename = Some(path);
+        
+        Ok(())
     }
     
-    fn find_note(&mut self, part: usize, t: song::Time, pitch: u32) -> Option<(usize, song::Note)> {
-        for (i, note) in self.song.parts[part].iter().enumerate() {
+    /// replaces the current song with one imported from a MIDI file. the
+    /// imported song isn't given a filename, so that saving it doesn't
+    /// overwrite the MIDI file.
+    pub fn import_midi(&mut self, path: String) -> Result<(), String> {
+        let (song, report) = midi::import(&path, midi::PartMapping::Auto)
+            .map_err(|err| format!("could not import {}: {}", path, err))?;
+        
+        println!("{}", report);
+        
+        self.song = song;
+        self.filename = None;
+        self.take = None;
+        self.history.clear();
+        
+        self.player.set_tempo(self.song.tempo_map());
+        self.player.set_loop_region(None);
+        self.player.set_mixer(self.song.mixer.clone());
+        self.player.flush_notes();
+        
+        Ok(())
+    }
+    
+    /// plays a note on the selected instrument for a key on the computer
+    /// keyboard, or moves the keyboard up or down an octave. returns whether
+    /// the key did anything.
+    pub fn play_key(&mut self, key: keyboard::Keycode, repeat: bool) -> bool {
+        match key {
+            keyboard::Keycode::Minus => self.octave = self.octave.saturating_sub(1),
+            keyboard::Keycode::Equals => self.octave = (self.octave + 1).min(MAX_OCTAVE),
+            _ => match key_offset(key) {
+                Some(offset) => if !repeat && !self.held_keys.iter().any(|(k, ..)| *k == key) {
+                    let pitch = 12 * self.octave + offset;
+                    let beat = self.beat();
+                    self.player.note_on(self.selected_instrument, pitch, 1.0);
+                    self.held_keys.push((key, self.selected_instrument, pitch));
+                    
+                    if let Some(take) = &mut self.take {
+                        take.note_on(self.selected_instrument, pitch, 1.0, beat);
+                    }
+                },
+                None => return false,
+            },
+        }
+        
+        true
+    }
+    
+    /// lets go of the note played by a key, if it's playing one. returns
+    /// whether it was.
+    pub fn release_key(&mut self, key: keyboard::Keycode) -> bool {
+        match self.held_keys.iter().position(|(k, ..)| *k == key) {
+            Some(i) => {
+                let (_, instrument, pitch) = self.held_keys.remove(i);
+                self.note_off(instrument, pitch);
+                true
+            },
+            None => false,
+        }
+    }
+    
+    /// lets go of every note being played from the keyboard, e.g. when the
+    /// window loses focus and won't hear the keys being released.
+    pub fn release_keys(&mut self) {
+        for (_, instrument, pitch) in std::mem::take(&mut self.held_keys) {
+            self.note_off(instrument, pitch);
+        }
+    }
+    
+    /// lets go of a note being played live, finishing it off if it's being
+    /// recorded.
+    fn note_off(&mut self, instrument: usize, pitch: u32) {
+        let beat = self.beat();
+        self.player.note_off(instrument, pitch);
+        
+        if let Some(take) = &mut self.take {
+            take.note_off(instrument, pitch, beat, &mut self.song, &mut self.player);
+        }
+    }
+    
+    /// gets the beat which the player's playhead has reached.
+    fn beat(&self) -> f64 {
+        self.player.tempo().beats_at(self.player.playhead())
+    }
+    
+    /// starts a take when the song starts playing while recording, and
+    /// finishes it when either stops. in between, the take follows the
+    /// playhead. this should be called once a frame.
+    pub fn update_take(&mut self) {
+        // the jumps are looked at before the playhead, which the player
+        // always moves before counting a jump.
+        let jumps = self.player.jumps();
+        let jump = Jump::between(self.jumps, jumps);
+        self.jumps = jumps;
+        
+        let beat = self.beat();
+        
+        if !self.record || self.player.paused() {
+            self.finish_take();
+        } else if let Some(take) = &mut self.take {
+            take.advance(beat, jump, &mut self.song, &mut self.player);
+        } else {
+            let quantize = if self.record_quantize { Some(self.seq_quantize) } else { None };
+            self.take = Some(Take::new(self.selected_instrument, beat, quantize, !self.overdub));
+        }
+    }
+    
+    /// finishes the take being recorded, if there is one, so that it can be
+    /// undone.
+    fn finish_take(&mut self) {
+        if let Some(take) = self.take.take() {
+            let beat = self.beat();
+            let edit = take.finish(beat, &mut self.song, &mut self.player);
+            self.history.remember(edit);
+        }
+    }
+    
+    /// makes an edit to the song or the instruments, which can be undone.
+    pub fn edit(&mut self, edit: Edit) {
+        self.history.make(edit, &mut self.song, &mut self.player);
+    }
+    
+    pub fn undo(&mut self) {
+        self.finish_take();
+        self.history.undo(&mut self.song, &mut self.player);
+    }
+    
+    pub fn redo(&mut self) {
+        self.finish_take();
+        self.history.redo(&mut self.song, &mut self.player);
+    }
+    
+    /// changes the selected operator of the selected instrument.
+    pub fn edit_operator<F>(&mut self, f: F) where F: FnOnce(&mut synth::Operator) {
+        let (instrument, index) = (self.selected_instrument, self.current_op);
+        let from = self.player.instruments()[instrument].operators[index].clone();
+        let mut to = from.clone();
+        f(&mut to);
+        
+        self.edit(Edit::Operator { instrument, index, from: Box::new(from), to: Box::new(to) });
+    }
+    
+    /// changes the mixer settings of an instrument.
+    pub fn edit_channel<F>(&mut self, instrument: usize, f: F) where F: FnOnce(&mut mixer::Channel) {
+        let from = self.player.mixer().channel(instrument);
+        let mut to = from;
+        f(&mut to);
+        
+        self.edit(Edit::Channel { instrument, from, to });
+    }
+    
+    /// changes the number of voices of the selected instrument, and how it
+    /// steals them.
+    pub fn edit_polyphony<F>(&mut self, f: F) where F: FnOnce(&mut usize, &mut synth::StealPolicy) {
+        let instrument = self.selected_instrument;
+        let instr = &self.player.instruments()[instrument];
+        let from = (instr.polyphony(), instr.stealing);
+        let mut to = from;
+        f(&mut to.0, &mut to.1);
+        
+        self.edit(Edit::Polyphony { instrument, from, to });
+    }
+    
+    /// changes whether the selected instrument plays one note at a time, and
+    /// how it glides between them.
+    pub fn edit_mono<F>(&mut self, f: F) where F: FnOnce(&mut synth::Mono) {
+        let instrument = self.selected_instrument;
+        let from = self.player.instruments()[instrument].mono;
+        let mut to = from;
+        f(&mut to);
+        
+        self.edit(Edit::Mono { instrument, from, to });
+    }
+    
+    /// changes the selected LFO of the selected instrument.
+    pub fn edit_lfo<F>(&mut self, f: F) where F: FnOnce(&mut synth::lfo::Lfo) {
+        let (instrument, index) = (self.selected_instrument, self.current_lfo);
+        let from = self.player.instruments()[instrument].lfos[index];
+        let mut to = from;
+        f(&mut to);
+        
+        self.edit(Edit::Lfo { instrument, index, from, to });
+    }
+    
+    /// changes the selected route in the selected instrument's modulation
+    /// matrix.
+    pub fn edit_route<F>(&mut self, f: F) where F: FnOnce(&mut synth::modulation::Route) {
+        let (instrument, index) = (self.selected_instrument, self.current_route);
+        let from = self.player.instruments()[instrument].routes[index];
+        let mut to = from;
+        f(&mut to);
+        
+        self.edit(Edit::Route { instrument, index, from, to });
+    }
+    
+    /// adds a note to a part, replacing any notes of the same pitch which it
+    /// overlaps.
+    fn add_note(&mut self, part: usize, note: song::Note) {
+        self.replace_notes(part, Vec::new(), vec![note]);
+    }
+    
+    /// takes some notes out of a part and puts others in, as a single edit.
+    /// any other notes which the new ones overlap at the same pitch are taken
+    /// out too.
+    fn replace_notes(&mut self, part: usize, old: Vec<song::Note>, new: Vec<song::Note>) {
+        let overlapped: Vec<song::Note> = self.song.parts[part]
+            .iter()
+            .filter(|existing| !old.contains(existing))
+            .filter(|existing| new.iter().any(|n| existing.pitch == n.pitch && existing.overlap(*n)))
+            .copied()
+            .collect();
+        
+        let mut removed = old;
+        removed.extend(overlapped);
+        
+        self.edit(Edit::Notes { part, removed, added: new });
+    }
+    
+    /// pastes the clipboard into a part, moved so that the earliest note
+    /// starts at `at`. returns the pasted notes.
+    fn paste(&mut self, part: usize, at: song::Time) -> Vec<song::Note> {
+        let first = match self.clipboard.iter().map(|n| n.start.as_divs()).min() {
+            Some(first) => first,
+            None => return Vec::new(),
+        };
+        
+        let notes: Vec<song::Note> = self.clipboard
+            .iter()
+            .map(|&n| song::Note { start: song::Time::from_divs(n.start.as_divs() - first + at.as_divs()), ..n })
+            .collect();
+        
+        self.replace_notes(part, Vec::new(), notes.clone());
+        notes
+    }
+    
+    fn find_note(&mut self, part: usize, t: song::Time, pitch: u32) -> Option<song::Note> {
+        for note in &self.song.parts[part] {
             if note.pitch == pitch && note.contains(t) {
-                return Some((i, note.clone()))
+                return Some(*note)
             }
         }
         
@@ -999,6 +1765,41 @@ impl WindowState {
     }
 }
 
+/// finds how many semitones above the keyboard's octave a key plays, laid
+/// out like a tracker: the bottom row of letters are the white keys of one
+/// octave, with the row above as the black keys, and the top two rows are
+/// the same again an octave higher.
+fn key_offset(key: keyboard::Keycode) -> Option<u32> {
+    use keyboard::Keycode::*;
+    
+    Some(match key {
+        Z => 0, S => 1, X => 2, D => 3, C => 4, V => 5, G => 6,
+        B => 7, H => 8, N => 9, J => 10, M => 11,
+        Comma => 12, L => 13, Period => 14, Semicolon => 15, Slash => 16,
+        
+        Q => 12, Num2 => 13, W => 14, Num3 => 15, E => 16, R => 17, Num5 => 18,
+        T => 19, Num6 => 20, Y => 21, Num7 => 22, U => 23,
+        I => 24, Num9 => 25, O => 26, Num0 => 27, P => 28,
+        
+        _ => return None,
+    })
+}
+
+/// whether ctrl, or the command key on a mac, is held down.
+pub(crate) fn is_command(keymod: Mod) -> bool {
+    keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD | Mod::LGUIMOD | Mod::RGUIMOD)
+}
+
+/// whether a key press is one of the sequencer's shortcuts, which work
+/// without the mouse being over it.
+pub(crate) fn is_shortcut(keycode: keyboard::Keycode, keymod: Mod) -> bool {
+    match keycode {
+        keyboard::Keycode::Delete | keyboard::Keycode::Backspace => true,
+        keyboard::Keycode::A | keyboard::Keycode::C | keyboard::Keycode::X | keyboard::Keycode::V => is_command(keymod),
+        _ => false,
+    }
+}
+
 pub(crate) fn draw_rect(buf: &mut [u8], rect: Rect,
     bg: Color, border: Option<Color>, corner: Option<Color>) {
     if let Some(rect) = clamp_rect(rect, Rect::new(0, 0, SCREEN_WIDTH, SCREEN_HEIGHT)) {
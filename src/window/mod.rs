@@ -5,17 +5,20 @@ mod font;
 mod constants;
 mod elements;
 
-use sdl2::event::Event;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
 use sdl2::rect::{Rect, Point};
 use sdl2::render;
 
 use std::fs::OpenOptions;
 use std::path::Path;
-use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
 
 use crate::song::Song;
 use crate::synth;
-use crate::{player::Player, song};
+use crate::export;
+use crate::midi;
+use crate::{history::{Edit, History}, remote::Remote, song};
 
 use constants::*;
 use elements::*;
@@ -28,10 +31,7 @@ pub struct Window {
 }
 
 impl Window {
-    pub fn new(video: sdl2::VideoSubsystem,
-        player_mutex: Arc<Mutex<Player>>,
-        note_channel: mpsc::Sender<(usize, song::Note)>)
-    -> Result<Window, String> {
+    pub fn new(video: sdl2::VideoSubsystem, player: Remote) -> Result<Window, String> {
         let win = video
             .window("Cancrizans", REAL_WIDTH, REAL_HEIGHT)
             .allow_highdpi()
@@ -62,14 +62,26 @@ impl Window {
                 mouse_x: 0,
                 mouse_y: 0,
                 selected_instrument: 0,
-                player: player_mutex,
-                song: song::Song::new(4, 60, 4),
+                player,
+                song: song::Song::new(4, 60, song::Meter::new(4, 4)),
+                history: History::new(HISTORY_LENGTH),
+                clipboard: Vec::new(),
+                keymod: Mod::NOMOD,
+                octave: 4,
+                held_keys: Vec::new(),
+                record: false,
+                overdub: true,
+                record_quantize: true,
+                count_in: false,
+                take: None,
+                jumps: (0, 0),
                 seq_scale_x: 12,
                 seq_scale_y: 4,
                 seq_quantize: 4,
                 filename: None,
-                note_channel,
                 current_op: 0,
+                current_lfo: 0,
+                current_route: 0,
             }
         };
         
@@ -78,6 +90,12 @@ impl Window {
         Ok(win)
     }
     
+    /// loads a song file into the window, as if it had been opened from the
+    /// file dialog.
+    pub fn open(&mut self, path: String) -> Result<(), String> {
+        self.state.open_song(path)
+    }
+    
     pub fn start(&mut self, sdl: sdl2::Sdl) -> Result<(), String> {
         let mut events = sdl.event_pump()?;
         
@@ -92,6 +110,43 @@ impl Window {
                         self.state.mouse_x = x as u32 / SCREEN_SCALE;
                         self.state.mouse_y = y as u32 / SCREEN_SCALE;
                     },
+                    
+                    // ctrl+z undoes, and ctrl+shift+z redoes. the command key
+                    // works too, for macs.
+                    Event::KeyDown { keycode: Some(Keycode::Z), keymod, .. } if is_command(keymod) => {
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            self.state.redo();
+                        } else {
+                            self.state.undo();
+                        }
+                        
+                        continue;
+                    },
+                    
+                    // letting go of a control ends the edit, so that e.g.
+                    // each turn of a knob can be undone separately.
+                    Event::MouseButtonUp { .. } => self.state.history.close(),
+                    _ => {}
+                }
+                
+                // the modifier keys are remembered for mouse events, which
+                // don't say which ones are held down.
+                match e {
+                    Event::KeyDown { keymod, .. } => self.state.keymod = keymod,
+                    Event::KeyUp { keymod, .. } => {
+                        self.state.keymod = keymod;
+                        self.state.history.close();
+                    },
+                    _ => {}
+                }
+                
+                // the computer keyboard plays the selected instrument, unless
+                // ctrl is held down for a shortcut.
+                match e {
+                    Event::KeyDown { keycode: Some(key), keymod, repeat, .. }
+                        if !is_command(keymod) && self.state.play_key(key, repeat) => continue,
+                    Event::KeyUp { keycode: Some(key), .. } if self.state.release_key(key) => continue,
+                    Event::Window { win_event: WindowEvent::FocusLost, .. } => self.state.release_keys(),
                     _ => {}
                 }
                 
@@ -111,6 +166,9 @@ impl Window {
                 }
             }
             
+            self.state.update_take();
+            self.state.player.collect_garbage();
+            
             self.texture.with_lock(None, |buf: &mut [u8], _pitch: usize| {
                 self.root.render(buf, &self.state);
             })?;
@@ -147,6 +205,58 @@ impl Window {
                         format!("\x10 {}", path.file_name().and_then(|f| f.to_str()).unwrap_or("unnamed.crz"))
                     }),
                 }) as Box<dyn Element>,
+                Box::new(Button {
+                    rect: Rect::new(
+                        SCREEN_WIDTH as i32 - 138, 2,
+                        26, 7,
+                    ),
+                    kind: ButtonType::Momentary { label: String::from("export") },
+                    state: ButtonState::Off,
+                    value: false,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    background_active: CONTROL_ACTIVE,
+                    foreground: FG2,
+                    on_change: Box::new(|pressed, state| {
+                        if !pressed {
+                            let path = match nfd::open_save_dialog(Some("wav,mid"), None) {
+                                Ok(nfd::Response::Okay(f)) => Some(f),
+                                Ok(nfd::Response::OkayMultiple(fs)) => Some(fs[0].clone()),
+                                Ok(nfd::Response::Cancel) => None,
+                                Err(_) => {
+                                    eprintln!("could not open file dialog");
+                                    None
+                                },
+                            };
+                            
+                            let is_midi = path.as_ref()
+                                .and_then(|p| Path::new(p).extension())
+                                .and_then(|e| e.to_str())
+                                .is_some_and(|e| e.eq_ignore_ascii_case("mid") || e.eq_ignore_ascii_case("midi"));
+                            
+                            if is_midi {
+                                let path = path.unwrap();
+                                
+                                match midi::export(&state.song, &path) {
+                                    Ok(_) => println!("exported {}", path),
+                                    Err(err) => eprintln!("couldn't export song: {}", err),
+                                }
+                            } else if let Some(path) = path {
+                                let mut song = state.song.clone();
+                                let instruments = state.player.instruments().to_vec();
+                                song.mixer = state.player.mixer().clone();
+                                
+                                // rendering can take a while, so it's done away from the UI thread.
+                                thread::spawn(move || {
+                                    match export::export_wav(&path, &song, instruments, export::ExportSettings::default()) {
+                                        Ok(_) => println!("exported {}", path),
+                                        Err(err) => eprintln!("couldn't export song: {}", err),
+                                    }
+                                });
+                            }
+                        }
+                    }),
+                }) as Box<dyn Element>,
                 Box::new(Button {
                     rect: Rect::new(
                         SCREEN_WIDTH as i32 - 110, 2,
@@ -175,11 +285,18 @@ impl Window {
                                     }
                                 },
                             } {                                
+                                state.song.instruments = state.player
+                                    .instruments()
+                                    .iter()
+                                    .map(|instr| instr.spec())
+                                    .collect();
+                                state.song.mixer = state.player.mixer().clone();
+                                
                                 match OpenOptions::new().read(false).write(true).create(true).open(&path) {
                                     Ok(file) => {
                                         file.set_len(0).unwrap();
                                         
-                                        match serde_json::to_writer_pretty(file, &state.song) {
+                                        match state.song.save(file) {
                                             Ok(_) => state.filename = Some(path),
                                             Err(err) => eprintln!("couldn't write to file: {}", err),
                                         }
@@ -205,8 +322,10 @@ impl Window {
                     on_change: Box::new(|pressed, state| {
                         if !pressed {
                             state.filename = None;
-                            state.song = Song::new(4, 60, 4);
-                            state.player.lock().unwrap().reset();
+                            state.song = Song::new(4, 60, song::Meter::new(4, 4));
+                            state.take = None;
+                            state.history.clear();
+                            state.player.reset();
                         }
                     }),
                 }) as Box<dyn Element>,
@@ -226,18 +345,8 @@ impl Window {
                         if !pressed {
                             match nfd::open_file_dialog(None, None) {
                                 Ok(nfd::Response::Okay(f)) => {
-                                    match OpenOptions::new().read(true).open(f.clone()) {
-                                        Ok(file) => {
-                                            match serde_json::from_reader(file) {
-                                                Ok(s) => {
-                                                    state.song = s;
-                                                    state.player.lock().unwrap().bps = state.song.get_bps();
-                                                    state.filename = Some(f);
-                                                },
-                                                Err(err) => eprintln!("error reading file: {}", err),
-                                            }
-                                        },
-                                        Err(err) => eprintln!("could not open file: {}", err),
+                                    if let Err(err) = state.open_song(f) {
+                                        eprintln!("{}", err);
                                     }
                                 },
                                 Ok(nfd::Response::OkayMultiple(_fs)) => {},
@@ -254,31 +363,121 @@ impl Window {
         }) as Box<dyn Element>);
         
         root.children.push(Box::new(Panel {
-            rect: Rect::new(1, 11, (SPECTRUM_WIDTH + 2) + 4, (SPECTRUM_HEIGHT + 2) * 4 + 7),
-            children: (0..4).map(|i| {
-                Box::new(Spectrum {
-                    rect: Rect::new(
-                        3, 13 + i * (SPECTRUM_HEIGHT + 3) as i32,
-                        SPECTRUM_WIDTH + 2, SPECTRUM_HEIGHT + 2),
-                    player: self.state.player.clone(),
-                    index: i as usize,
-                    wave_scale: 12.0,
-                }) as Box<dyn Element>
-            }).collect(),
+            rect: Rect::new(1, 11, (SPECTRUM_WIDTH + MIXER_WIDTH + 2) + 4, (SPECTRUM_HEIGHT + 2) * 4 + 7),
+            children: {
+                let mut elems: Vec<Box<dyn Element>> = Vec::new();
+                
+                for i in 0..4 {
+                    let top = 13 + i as i32 * (SPECTRUM_HEIGHT + 3) as i32;
+                    let left = SPECTRUM_WIDTH as i32 + 7;
+                    
+                    elems.push(Box::new(Spectrum {
+                        rect: Rect::new(
+                            3, top,
+                            SPECTRUM_WIDTH + 2, SPECTRUM_HEIGHT + 2),
+                        index: i,
+                        wave_scale: 12.0,
+                    }));
+                    
+                    // each instrument's mixer strip sits to the right of its
+                    // scope: gain and pan along the top, then mute and solo.
+                    elems.push(Box::new(Knob {
+                        center: Point::new(left + 6, top + 9),
+                        radius: 5,
+                        border_width: 1,
+                        background: KNOB_BG,
+                        border: KNOB_BORDER,
+                        state: ButtonState::Off,
+                        min_value: 0.0,
+                        max_value: 2.0,
+                        value: DynVar::new(
+                            move |s| s.player.mixer().channel(i).gain,
+                            move |s, x| s.edit_channel(i, |c| c.gain = x),
+                        ),
+                        make_tooltip: Box::new(|x, _s| {
+                            format!("gain {}%", (x * 100.0).round())
+                        }),
+                    }));
+                    
+                    elems.push(Box::new(Knob {
+                        center: Point::new(left + 19, top + 9),
+                        radius: 5,
+                        border_width: 1,
+                        background: KNOB_BG,
+                        border: KNOB_BORDER,
+                        state: ButtonState::Off,
+                        min_value: -1.0,
+                        max_value: 1.0,
+                        value: DynVar::new(
+                            move |s| s.player.mixer().channel(i).pan,
+                            move |s, x| s.edit_channel(i, |c| c.pan = x),
+                        ),
+                        make_tooltip: Box::new(|x, _s| {
+                            let percent = (x.abs() * 100.0).round();
+                            
+                            if percent == 0.0 {
+                                String::from("pan centre")
+                            } else if x < 0.0 {
+                                format!("pan {}% L", percent)
+                            } else {
+                                format!("pan {}% R", percent)
+                            }
+                        }),
+                    }));
+                    
+                    elems.push(Box::new(Choice {
+                        rect: Rect::new(left + 1, top + 20, 11, 7),
+                        value: DynVar::new(
+                            move |s| s.player.mixer().channel(i).mute.into(),
+                            move |s, x| s.edit_channel(i, |c| c.mute = x == 1),
+                        ),
+                        num_values: 2,
+                        background: CONTROL_BG,
+                        background_hover: CONTROL_HOVER,
+                        foreground: FG,
+                        make_label: Box::new(|x| {
+                            match x {
+                                0 => String::from("\x06\x07"),
+                                _ => String::from("\x06"),
+                            }
+                        }),
+                    }));
+                    
+                    elems.push(Box::new(Choice {
+                        rect: Rect::new(left + 14, top + 20, 11, 7),
+                        value: DynVar::new(
+                            move |s| s.player.mixer().channel(i).solo.into(),
+                            move |s, x| s.edit_channel(i, |c| c.solo = x == 1),
+                        ),
+                        num_values: 2,
+                        background: CONTROL_BG,
+                        background_hover: CONTROL_HOVER,
+                        foreground: FG,
+                        make_label: Box::new(|x| {
+                            match x {
+                                0 => String::from("s"),
+                                _ => String::from("S"),
+                            }
+                        }),
+                    }));
+                }
+                
+                elems
+            },
             background: PANEL_BG,
             border: Some(BORDER),
             corner: Some(CORNER),
         }));
         
         root.children.push(Box::new(Panel {
-            rect: Rect::new((SPECTRUM_WIDTH + 2) as i32 + 6, 11,
-                SCREEN_WIDTH - (SPECTRUM_WIDTH + 2) - 7, (SPECTRUM_HEIGHT + 2) * 4 + 7),
+            rect: Rect::new((SPECTRUM_WIDTH + MIXER_WIDTH + 2) as i32 + 6, 11,
+                SCREEN_WIDTH - (SPECTRUM_WIDTH + MIXER_WIDTH + 2) - 7, (SPECTRUM_HEIGHT + 2) * 4 + 7),
             children: {
                 let mut elems: Vec<Box<dyn Element>> = Vec::new();
                 
                 elems.push(Box::new(Button {
                     rect: Rect::new(
-                        SPECTRUM_WIDTH as i32 + 11,
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 11,
                         14, 7, 7,
                     ),
                     kind: ButtonType::Momentary { label: String::from("<") },
@@ -290,8 +489,7 @@ impl Window {
                     foreground: FG2,
                     on_change: Box::new(|pressed, s| {
                         if !pressed {
-                            let p = s.player.lock().unwrap();
-                            let num_ops = p.instruments[s.selected_instrument].operators.len();
+                            let num_ops = s.player.instruments()[s.selected_instrument].operators.len();
                             s.current_op = (s.current_op + num_ops - 1) % num_ops;
                         }
                     }),
@@ -299,76 +497,596 @@ impl Window {
                 
                 elems.push(Box::new(DynamicLabel {
                     rect: Rect::new(
-                        SPECTRUM_WIDTH as i32 + 20,
-                        15, 22, 5,
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 20,
+                        15, 22, 5,
+                    ),
+                    tooltip: None,
+                    colour: FG2,
+                    get_text: Box::new(|s| {
+                        let num_ops = s.player.instruments()[s.selected_instrument].operators.len();
+                        format!("op: {}/{}", s.current_op + 1, num_ops)
+                    }),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Button {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 61,
+                        14, 7, 7,
+                    ),
+                    kind: ButtonType::Momentary { label: String::from(">") },
+                    state: ButtonState::Off,
+                    value: false,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    background_active: CONTROL_ACTIVE,
+                    foreground: FG2,
+                    on_change: Box::new(|pressed, s| {
+                        if !pressed {
+                            let num_ops = s.player.instruments()[s.selected_instrument].operators.len();
+                            s.current_op = (s.current_op + 1) % num_ops;
+                        }
+                    }),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Button {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 69,
+                        14, 7, 7,
+                    ),
+                    kind: ButtonType::Momentary { label: String::from("\x16") },
+                    state: ButtonState::Off,
+                    value: false,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    background_active: CONTROL_ACTIVE,
+                    foreground: FG2,
+                    on_change: Box::new(|_, _| {
+                        
+                    }),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Button {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 77,
+                        14, 7, 7,
+                    ),
+                    kind: ButtonType::Momentary { label: String::from("+") },
+                    state: ButtonState::Off,
+                    value: false,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    background_active: CONTROL_ACTIVE,
+                    foreground: FG2,
+                    on_change: Box::new(|_, _| {
+                        
+                    }),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Label {
+                    position: Point::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 92, 15,
+                    ),
+                    text: String::from("voices:"),
+                    tooltip: Some(String::from("how many notes can play at once")),
+                    colour: FG2,
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Stepper {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 121, 14, 15, 7,
+                    ),
+                    value: DynVar::new(
+                        |s| s.player.instruments()[s.selected_instrument].polyphony() as i32,
+                        |s, x| s.edit_polyphony(|voices, _| *voices = x as usize),
+                    ),
+                    min_value: 1,
+                    max_value: synth::MAX_VOICES as i32,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    foreground: FG,
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Label {
+                    position: Point::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 92, 24,
+                    ),
+                    text: String::from("steal:"),
+                    tooltip: Some(String::from("which note to cut off when every voice is busy")),
+                    colour: FG2,
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Choice {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 121, 23, 36, 7,
+                    ),
+                    value: DynVar::new(
+                        |s| s.player.instruments()[s.selected_instrument].stealing.to_u32(),
+                        |s, x| s.edit_polyphony(|_, stealing| *stealing = synth::StealPolicy::from(x)),
+                    ),
+                    num_values: 5,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    foreground: FG,
+                    make_label: Box::new(|x| {
+                        match x {
+                            0 => String::from("oldest"),
+                            1 => String::from("quietest"),
+                            2 => String::from("lowest"),
+                            3 => String::from("highest"),
+                            _ => String::from("never"),
+                        }
+                    }),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Label {
+                    position: Point::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 92, 33,
+                    ),
+                    text: String::from("play:"),
+                    tooltip: Some(String::from("whether overlapping notes get their own voices")),
+                    colour: FG2,
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Choice {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 121, 32, 36, 7,
+                    ),
+                    value: DynVar::new(
+                        |s| {
+                            let mono = s.player.instruments()[s.selected_instrument].mono;
+                            u32::from(mono.enabled) + u32::from(mono.enabled && mono.legato)
+                        },
+                        |s, x| s.edit_mono(|mono| {
+                            mono.enabled = x > 0;
+                            mono.legato = x > 1;
+                        }),
+                    ),
+                    num_values: 3,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    foreground: FG,
+                    make_label: Box::new(|x| {
+                        match x {
+                            0 => String::from("poly"),
+                            1 => String::from("mono"),
+                            _ => String::from("legato"),
+                        }
+                    }),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Label {
+                    position: Point::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 92, 43,
+                    ),
+                    text: String::from("glide:"),
+                    tooltip: Some(String::from("how long a mono instrument slides between notes")),
+                    colour: FG2,
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Knob {
+                    center: Point::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 126, 45,
+                    ),
+                    radius: 5,
+                    border_width: 1,
+                    background: KNOB_BG,
+                    border: KNOB_BORDER,
+                    state: ButtonState::Off,
+                    min_value: 0.0,
+                    max_value: 1.0,
+                    value: DynVar::new(
+                        |s| s.player.instruments()[s.selected_instrument].mono.glide,
+                        |s, x| s.edit_mono(|mono| mono.glide = x),
+                    ),
+                    make_tooltip: Box::new(|x, _s| {
+                        format!("glide {:.2}s", x)
+                    }),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Label {
+                    position: Point::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 92, 54,
+                    ),
+                    text: String::from("lfo:"),
+                    tooltip: Some(String::from("slow oscillators for vibrato and tremolo")),
+                    colour: FG2,
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Choice {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 109, 53, 9, 7,
+                    ),
+                    value: DynVar::new(
+                        |s| s.current_lfo as u32,
+                        |s, x| s.current_lfo = x as usize,
+                    ),
+                    num_values: synth::lfo::MAX_LFOS as u32,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    foreground: FG,
+                    make_label: Box::new(|x| format!("{}", x + 1)),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Choice {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 121, 53, 15, 7,
+                    ),
+                    value: DynVar::new(
+                        |s| s.player.instruments()[s.selected_instrument].lfos[s.current_lfo].shape.to_u32(),
+                        |s, x| s.edit_lfo(|lfo| lfo.shape = synth::WaveType::from(x)),
+                    ),
+                    num_values: 4,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    foreground: FG,
+                    make_label: Box::new(|x| {
+                        match x {
+                            0 => String::from("\x11"),
+                            1 => String::from("\x13"),
+                            2 => String::from("\x12"),
+                            _ => String::from("\x14"),
+                        }
+                    }),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Choice {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 138, 53, 19, 7,
+                    ),
+                    value: DynVar::new(
+                        |s| s.player.instruments()[s.selected_instrument].lfos[s.current_lfo].sync.into(),
+                        |s, x| s.edit_lfo(|lfo| lfo.sync = x == 1),
+                    ),
+                    num_values: 2,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    foreground: FG,
+                    make_label: Box::new(|x| {
+                        match x {
+                            0 => String::from("hz"),
+                            _ => String::from("beat"),
+                        }
+                    }),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Choice {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 138, 62, 19, 7,
+                    ),
+                    value: DynVar::new(
+                        |s| s.player.instruments()[s.selected_instrument].lfos[s.current_lfo].key_sync.into(),
+                        |s, x| s.edit_lfo(|lfo| lfo.key_sync = x == 1),
+                    ),
+                    num_values: 2,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    foreground: FG,
+                    make_label: Box::new(|x| {
+                        match x {
+                            0 => String::from("free"),
+                            _ => String::from("key"),
+                        }
+                    }),
+                }) as Box<dyn Element>);
+                
+                // the selected LFO's rate, delay and fade-in.
+                for (j, max) in [20.0, 2.0, 2.0].into_iter().enumerate() {
+                    elems.push(Box::new(Knob {
+                        center: Point::new(
+                            (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 97 + 14 * j as i32,
+                            66,
+                        ),
+                        radius: 5,
+                        border_width: 1,
+                        background: KNOB_BG,
+                        border: KNOB_BORDER,
+                        state: ButtonState::Off,
+                        min_value: 0.0,
+                        max_value: max,
+                        value: DynVar::new(
+                            move |s| {
+                                let lfo = s.player.instruments()[s.selected_instrument].lfos[s.current_lfo];
+                                [lfo.rate, lfo.delay, lfo.fade][j]
+                            },
+                            move |s, x| s.edit_lfo(|lfo| match j {
+                                0 => lfo.rate = x,
+                                1 => lfo.delay = x,
+                                _ => lfo.fade = x,
+                            }),
+                        ),
+                        make_tooltip: Box::new(move |x, s| {
+                            let sync = s.player.instruments()[s.selected_instrument].lfos[s.current_lfo].sync;
+                            
+                            match j {
+                                0 if sync => format!("rate {:.2}/beat", x),
+                                0 => format!("rate {:.2}hz", x),
+                                1 => format!("delay {:.2}s", x),
+                                _ => format!("fade {:.2}s", x),
+                            }
+                        }),
+                    }));
+                }
+                
+                elems.push(Box::new(Label {
+                    position: Point::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 92, 75,
+                    ),
+                    text: String::from("op depth:"),
+                    tooltip: Some(String::from("how far the lfo moves this operator")),
+                    colour: FG2,
+                }) as Box<dyn Element>);
+                
+                // how far the selected LFO moves the selected operator.
+                for (j, max) in [12.0, 1.0, 1.0, 1.0].into_iter().enumerate() {
+                    elems.push(Box::new(Knob {
+                        center: Point::new(
+                            (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 97 + 14 * j as i32,
+                            87,
+                        ),
+                        radius: 5,
+                        border_width: 1,
+                        background: KNOB_BG,
+                        border: KNOB_BORDER,
+                        state: ButtonState::Off,
+                        min_value: 0.0,
+                        max_value: max,
+                        value: DynVar::new(
+                            move |s| {
+                                let d = s.player.instruments()[s.selected_instrument]
+                                    .operators[s.current_op]
+                                    .lfos[s.current_lfo];
+                                
+                                [d.pitch, d.amplitude, d.send, d.receive][j]
+                            },
+                            move |s, x| {
+                                let lfo = s.current_lfo;
+                                
+                                s.edit_operator(|op| match j {
+                                    0 => op.lfos[lfo].pitch = x,
+                                    1 => op.lfos[lfo].amplitude = x,
+                                    2 => op.lfos[lfo].send = x,
+                                    _ => op.lfos[lfo].receive = x,
+                                });
+                            }),
+                        make_tooltip: Box::new(move |x, _s| {
+                            match j {
+                                0 => format!("pitch {:.2}st", x),
+                                1 => format!("amp {:.2}", x),
+                                2 => format!("send {:.2}", x),
+                                _ => format!("recv {:.2}", x),
+                            }
+                        }),
+                    }));
+                }
+                
+                elems.push(Box::new(Label {
+                    position: Point::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 92, 99,
+                    ),
+                    text: String::from("mod:"),
+                    tooltip: Some(String::from("routes in the modulation matrix")),
+                    colour: FG2,
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Choice {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 109, 98, 9, 7,
+                    ),
+                    value: DynVar::new(
+                        |s| s.current_route as u32,
+                        |s, x| s.current_route = x as usize,
+                    ),
+                    num_values: synth::modulation::MAX_ROUTES as u32,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    foreground: FG,
+                    make_label: Box::new(|x| format!("{}", x + 1)),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Knob {
+                    center: Point::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 126, 101,
+                    ),
+                    radius: 5,
+                    border_width: 1,
+                    background: KNOB_BG,
+                    border: KNOB_BORDER,
+                    state: ButtonState::Off,
+                    min_value: -1.0,
+                    max_value: 1.0,
+                    value: DynVar::new(
+                        |s| s.player.instruments()[s.selected_instrument].routes[s.current_route].amount,
+                        |s, x| s.edit_route(|route| route.amount = x),
+                    ),
+                    make_tooltip: Box::new(|x, _s| {
+                        format!("amount {:.2}", x)
+                    }),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Choice {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 92, 108, 30, 7,
+                    ),
+                    value: DynVar::new(
+                        |s| s.player.instruments()[s.selected_instrument].routes[s.current_route].source.to_u32(),
+                        |s, x| s.edit_route(|route| {
+                            route.source = synth::modulation::Source::from(x, route.source.index().unwrap_or(0));
+                        }),
+                    ),
+                    num_values: 6,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    foreground: FG,
+                    make_label: Box::new(|x| {
+                        match x {
+                            0 => String::from("vel"),
+                            1 => String::from("key"),
+                            2 => String::from("lfo"),
+                            3 => String::from("env"),
+                            4 => String::from("wheel"),
+                            _ => String::from("touch"),
+                        }
+                    }),
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Stepper {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 124, 108, 15, 7,
                     ),
-                    tooltip: None,
-                    colour: FG2,
-                    get_text: Box::new(|s| {
-                        let p = s.player.lock().unwrap();
-                        let num_ops = p.instruments[s.selected_instrument].operators.len();
-                        format!("op: {}/{}", s.current_op + 1, num_ops)
-                    }),
+                    value: DynVar::new(
+                        |s| {
+                            let source = s.player.instruments()[s.selected_instrument].routes[s.current_route].source;
+                            source.index().map_or(1, |i| i as i32 + 1)
+                        },
+                        |s, x| s.edit_route(|route| {
+                            route.source = synth::modulation::Source::from(route.source.to_u32(), x as usize - 1);
+                        }),
+                    ),
+                    min_value: 1,
+                    max_value: synth::MAX_OPERATORS as i32,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    foreground: FG,
                 }) as Box<dyn Element>);
                 
-                elems.push(Box::new(Button {
+                elems.push(Box::new(Choice {
                     rect: Rect::new(
-                        SPECTRUM_WIDTH as i32 + 61,
-                        14, 7, 7,
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 92, 117, 30, 7,
                     ),
-                    kind: ButtonType::Momentary { label: String::from(">") },
-                    state: ButtonState::Off,
-                    value: false,
+                    value: DynVar::new(
+                        |s| s.player.instruments()[s.selected_instrument].routes[s.current_route].destination.to_u32(),
+                        |s, x| s.edit_route(|route| {
+                            route.destination = synth::modulation::Destination::from(x, route.destination.index(), 0);
+                        }),
+                    ),
+                    num_values: 6,
                     background: CONTROL_BG,
                     background_hover: CONTROL_HOVER,
-                    background_active: CONTROL_ACTIVE,
-                    foreground: FG2,
-                    on_change: Box::new(|pressed, s| {
-                        if !pressed {
-                            let p = s.player.lock().unwrap();
-                            let num_ops = p.instruments[s.selected_instrument].operators.len();
-                            s.current_op = (s.current_op + 1) % num_ops;
+                    foreground: FG,
+                    make_label: Box::new(|x| {
+                        match x {
+                            0 => String::from("pitch"),
+                            1 => String::from("att"),
+                            2 => String::from("dec"),
+                            3 => String::from("rel"),
+                            4 => String::from("send"),
+                            _ => String::from("effect"),
                         }
                     }),
                 }) as Box<dyn Element>);
                 
-                elems.push(Box::new(Button {
+                elems.push(Box::new(Stepper {
                     rect: Rect::new(
-                        SPECTRUM_WIDTH as i32 + 69,
-                        14, 7, 7,
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 124, 117, 15, 7,
                     ),
-                    kind: ButtonType::Momentary { label: String::from("\x16") },
-                    state: ButtonState::Off,
-                    value: false,
+                    value: DynVar::new(
+                        |s| s.player.instruments()[s.selected_instrument].routes[s.current_route].destination.index() as i32 + 1,
+                        |s, x| s.edit_route(|route| {
+                            let param = match route.destination {
+                                synth::modulation::Destination::Effect { param, .. } => param,
+                                _ => 0,
+                            };
+                            
+                            route.destination = synth::modulation::Destination::from(route.destination.to_u32(), x as usize - 1, param);
+                        }),
+                    ),
+                    min_value: 1,
+                    max_value: synth::MAX_OPERATORS as i32,
                     background: CONTROL_BG,
                     background_hover: CONTROL_HOVER,
-                    background_active: CONTROL_ACTIVE,
-                    foreground: FG2,
-                    on_change: Box::new(|_, _| {
-                        
-                    }),
+                    foreground: FG,
                 }) as Box<dyn Element>);
                 
-                elems.push(Box::new(Button {
+                elems.push(Box::new(Stepper {
                     rect: Rect::new(
-                        SPECTRUM_WIDTH as i32 + 77,
-                        14, 7, 7,
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 141, 117, 15, 7,
                     ),
-                    kind: ButtonType::Momentary { label: String::from("+") },
-                    state: ButtonState::Off,
-                    value: false,
+                    value: DynVar::new(
+                        |s| match s.player.instruments()[s.selected_instrument].routes[s.current_route].destination {
+                            synth::modulation::Destination::Effect { param, .. } => param as i32 + 1,
+                            _ => 1,
+                        },
+                        |s, x| s.edit_route(|route| {
+                            if let synth::modulation::Destination::Effect { param, .. } = &mut route.destination {
+                                *param = x as usize - 1;
+                            }
+                        }),
+                    ),
+                    min_value: 1,
+                    max_value: 8,
                     background: CONTROL_BG,
                     background_hover: CONTROL_HOVER,
-                    background_active: CONTROL_ACTIVE,
-                    foreground: FG2,
-                    on_change: Box::new(|_, _| {
+                    foreground: FG,
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(DynamicLabel {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 92, 127, 64, 5,
+                    ),
+                    tooltip: None,
+                    colour: FG2,
+                    get_text: Box::new(|s| {
+                        let instr = &s.player.instruments()[s.selected_instrument];
                         
+                        match instr.routes[s.current_route].destination {
+                            synth::modulation::Destination::Effect { effect, param } => {
+                                match instr.effects.get(effect).and_then(|e| e.params().get(param)) {
+                                    Some(name) => format!("fx {}: {}", effect + 1, name),
+                                    None => String::from("fx: none"),
+                                }
+                            },
+                            d => format!("op {}", d.index() + 1),
+                        }
                     }),
                 }) as Box<dyn Element>);
                 
                 elems.push(Box::new(Label {
                     position: Point::new(
-                        SPECTRUM_WIDTH as i32 + 11, 24,
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 92, 139,
+                    ),
+                    text: String::from("live:"),
+                    tooltip: Some(String::from("the mod wheel and aftertouch, while playing")),
+                    colour: FG2,
+                }) as Box<dyn Element>);
+                
+                for j in 0..2 {
+                    elems.push(Box::new(Knob {
+                        center: Point::new(
+                            (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 116 + 14 * j,
+                            141,
+                        ),
+                        radius: 5,
+                        border_width: 1,
+                        background: KNOB_BG,
+                        border: KNOB_BORDER,
+                        state: ButtonState::Off,
+                        min_value: 0.0,
+                        max_value: 1.0,
+                        value: DynVar::new(
+                            move |s| {
+                                let controls = s.player.instruments()[s.selected_instrument].controls;
+                                [controls.mod_wheel, controls.aftertouch][j as usize]
+                            },
+                            move |s, x| {
+                                let instrument = s.selected_instrument;
+                                
+                                s.player.edit_controls(instrument, |c| match j {
+                                    0 => c.mod_wheel = x,
+                                    _ => c.aftertouch = x,
+                                });
+                            }),
+                        make_tooltip: Box::new(move |x, _s| {
+                            match j {
+                                0 => format!("wheel {:.2}", x),
+                                _ => format!("touch {:.2}", x),
+                            }
+                        }),
+                    }));
+                }
+                
+                elems.push(Box::new(Label {
+                    position: Point::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 11, 24,
                     ),
                     text: String::from("wave:"),
                     tooltip: None,
@@ -377,20 +1095,18 @@ impl Window {
                 
                 elems.push(Box::new(Stepper {
                     rect: Rect::new(
-                        SPECTRUM_WIDTH as i32 + 33, 23, 24, 7,
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 33, 23, 24, 7,
                     ),
                     value: DynVar::new(
                         |s| {
-                            let p = s.player.lock().unwrap();
-                            p.instruments[s.selected_instrument]
+                            s.player.instruments()[s.selected_instrument]
                                 .operators[s.current_op]
                                 .transpose as i32
                         },
                         |s, x| {
-                            let mut p = s.player.lock().unwrap();
-                            p.instruments[s.selected_instrument]
-                                .operators[s.current_op]
-                                .transpose = x as f32;
+                            s.edit_operator(|op| {
+                                op.transpose = x as f32;
+                            });
                         }
                     ),
                     min_value: 1,
@@ -402,20 +1118,18 @@ impl Window {
                 
                 elems.push(Box::new(Choice {
                     rect: Rect::new(
-                        SPECTRUM_WIDTH as i32 + 58, 23, 12, 7,
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 58, 23, 12, 7,
                     ),
                     value: DynVar::new(
                         |s| {
-                            let p = s.player.lock().unwrap();
-                            p.instruments[s.selected_instrument]
+                            s.player.instruments()[s.selected_instrument]
                                 .operators[s.current_op]
                                 .fixed.into()
                         },
                         |s, x| {
-                            let mut p = s.player.lock().unwrap();
-                            p.instruments[s.selected_instrument]
-                                .operators[s.current_op]
-                                .fixed = x == 1;
+                            s.edit_operator(|op| {
+                                op.fixed = x == 1;
+                            });
                         }
                     ),
                     num_values: 2,
@@ -432,21 +1146,19 @@ impl Window {
                 
                 elems.push(Box::new(Choice {
                     rect: Rect::new(
-                        SPECTRUM_WIDTH as i32 + 72, 23, 15, 7,
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 72, 23, 15, 7,
                     ),
                     value: DynVar::new(
                         |s| {
-                            let p = s.player.lock().unwrap();
-                            p.instruments[s.selected_instrument]
+                            s.player.instruments()[s.selected_instrument]
                                 .operators[s.current_op]
                                 .wave
                                 .to_u32()
                         },
                         |s, x| {
-                            let mut p = s.player.lock().unwrap();
-                            p.instruments[s.selected_instrument]
-                                .operators[s.current_op]
-                                .wave = synth::WaveType::from(x);
+                            s.edit_operator(|op| {
+                                op.wave = synth::WaveType::from(x);
+                            });
                         }
                     ),
                     num_values: 4,
@@ -463,8 +1175,45 @@ impl Window {
                     }),
                 }) as Box<dyn Element>);
                 
+                elems.push(Box::new(Label {
+                    position: Point::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 11, 33,
+                    ),
+                    text: String::from("smooth:"),
+                    tooltip: Some(String::from("band-limits square, triangle and saw waves, so they don't alias")),
+                    colour: FG2,
+                }) as Box<dyn Element>);
+                
+                elems.push(Box::new(Choice {
+                    rect: Rect::new(
+                        (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 41, 32, 16, 7,
+                    ),
+                    value: DynVar::new(
+                        |s| {
+                            s.player.instruments()[s.selected_instrument]
+                                .operators[s.current_op]
+                                .band_limited.into()
+                        },
+                        |s, x| {
+                            s.edit_operator(|op| {
+                                op.band_limited = x == 1;
+                            });
+                        }
+                    ),
+                    num_values: 2,
+                    background: CONTROL_BG,
+                    background_hover: CONTROL_HOVER,
+                    foreground: FG,
+                    make_label: Box::new(|x| {
+                        match x {
+                            0 => String::from("off"),
+                            _ => String::from("on"),
+                        }
+                    }),
+                }) as Box<dyn Element>);
+                
                 for i in 0..synth::NUM_CHANNELS as i32 {
-                    let left = SPECTRUM_WIDTH as i32 + 12;
+                    let left = (SPECTRUM_WIDTH + MIXER_WIDTH) as i32 + 12;
                     let top = ((SPECTRUM_HEIGHT + 2) * 4 + 7) as i32 - 15 * (synth::NUM_CHANNELS as i32 - i - 1) - 1;
                     
                     elems.push(Box::new(Label {
@@ -483,19 +1232,16 @@ impl Window {
                         ),
                         value: DynVar::new(
                             move |s| {
-                                let p = s.player.lock().unwrap();
-                                p.instruments[s.selected_instrument]
+                                s.player.instruments()[s.selected_instrument]
                                     .operators[s.current_op]
                                     .connections[i as usize]
                                     .kind
                                     .to_u32()
                             },
                             move |s, n| {
-                                let mut p = s.player.lock().unwrap();
-                                p.instruments[s.selected_instrument]
-                                    .operators[s.current_op]
-                                    .connections[i as usize]
-                                    .kind = synth::ReceiveKind::from(n);
+                                s.edit_operator(|op| {
+                                    op.connections[i as usize].kind = synth::ReceiveKind::from(n);
+                                });
                             },
                         ),
                         num_values: 3,
@@ -524,18 +1270,15 @@ impl Window {
                         max_value: 4.0,
                         value: DynVar::new(
                             move |s| {
-                                let p = s.player.lock().unwrap();
-                                p.instruments[s.selected_instrument]
+                                s.player.instruments()[s.selected_instrument]
                                     .operators[s.current_op]
                                     .connections[i as usize]
                                     .receive
                             },
                             move |s, n| {
-                                let mut p = s.player.lock().unwrap();
-                                p.instruments[s.selected_instrument]
-                                    .operators[s.current_op]
-                                    .connections[i as usize]
-                                    .receive = n;
+                                s.edit_operator(|op| {
+                                    op.connections[i as usize].receive = n;
+                                });
                             }),
                         make_tooltip: Box::new(move |x, _s| {
                             format!("recv {:.2}", x)
@@ -556,18 +1299,15 @@ impl Window {
                         max_value: 4.0,
                         value: DynVar::new(
                             move |s| {
-                                let p = s.player.lock().unwrap();
-                                p.instruments[s.selected_instrument]
+                                s.player.instruments()[s.selected_instrument]
                                     .operators[s.current_op]
                                     .connections[i as usize]
                                     .send
                             },
                             move |s, n| {
-                                let mut p = s.player.lock().unwrap();
-                                p.instruments[s.selected_instrument]
-                                    .operators[s.current_op]
-                                    .connections[i as usize]
-                                    .send = n;
+                                s.edit_operator(|op| {
+                                    op.connections[i as usize].send = n;
+                                });
                             }),
                         make_tooltip: Box::new(move |x, _s| {
                             format!("send {:.2}", x)
@@ -602,14 +1342,7 @@ impl Window {
                             ),
                             value: DynVar::new(
                                 |s| s.song.bpm as i32,
-                                |s, v| {
-                                    s.song.bpm = v as u32;
-                                    let mut player = s.player.lock().unwrap();
-                                    let old_bps = player.bps;
-                                    let new_bps = v as f64 / 60.0;
-                                    player.bps = new_bps;
-                                    player.playhead /= new_bps / old_bps;
-                                },
+                                |s, v| s.edit(Edit::Bpm { from: s.song.bpm, to: v as u32 }),
                             ),
                             min_value: 1,
                             max_value: 999,
@@ -623,7 +1356,7 @@ impl Window {
                                 (SPECTRUM_HEIGHT + 2) as i32 * 4 + 22,
                             ),
                             text: String::from("bpm"),
-                            tooltip: Some(String::from("beats per minute")),
+                            tooltip: Some(String::from("beats per minute at the start")),
                             colour: DIM_LABEL,
                         }),
                         Box::new(Stepper {
@@ -634,8 +1367,11 @@ impl Window {
                                 7,
                             ),
                             value: DynVar::new(
-                                |s| s.song.beats_per_bar as i32,
-                                |s, v| s.song.beats_per_bar = v as u32,
+                                |s| s.song.meter.numerator as i32,
+                                |s, v| s.edit(Edit::Meter {
+                                    from: s.song.meter,
+                                    to: song::Meter::new(v as u32, s.song.meter.denominator),
+                                }),
                             ),
                             min_value: 1,
                             max_value: 64,
@@ -648,13 +1384,46 @@ impl Window {
                                 50,
                                 (SPECTRUM_HEIGHT + 2) as i32 * 4 + 22,
                             ),
-                            text: String::from("/4"),
+                            text: String::from("/"),
                             tooltip: Some(String::from("time signature")),
                             colour: DIM_LABEL,
                         }),
+                        Box::new(Stepper {
+                            rect: Rect::new(
+                                53,
+                                (SPECTRUM_HEIGHT + 2) as i32 * 4 + 21,
+                                11,
+                                7,
+                            ),
+                            // the denominator is always a power of two, so
+                            // each step doubles or halves it.
+                            value: DynVar::new(
+                                |s| s.song.meter.denominator as i32,
+                                |s, v| {
+                                    let denom = s.song.meter.denominator;
+                                    let to = if v as u32 > denom {
+                                        (denom * 2).min(64)
+                                    } else if (v as u32) < denom {
+                                        (denom / 2).max(1)
+                                    } else {
+                                        denom
+                                    };
+                                    
+                                    s.edit(Edit::Meter {
+                                        from: s.song.meter,
+                                        to: song::Meter::new(s.song.meter.numerator, to),
+                                    });
+                                },
+                            ),
+                            min_value: 1,
+                            max_value: 64,
+                            background: CONTROL_BG,
+                            background_hover: CONTROL_HOVER,
+                            foreground: FG2,
+                        }) as Box<dyn Element>,
                         Box::new(Slider {
                             rect: Rect::new(
-                                62,
+                                67,
                                 (SPECTRUM_HEIGHT + 2) as i32 * 4 + 21,
                                 16,
                                 7,
@@ -684,13 +1453,39 @@ impl Window {
                         }) as Box<dyn Element>,
                         Box::new(Label {
                             position: Point::new(
-                                80,
+                                85,
                                 (SPECTRUM_HEIGHT + 2) as i32 * 4 + 22,
                             ),
                             text: String::from("QZ."),
                             tooltip: Some(String::from("quanta per beat")),
                             colour: DIM_LABEL,
                         }),
+                        Box::new(Stepper {
+                            rect: Rect::new(
+                                100,
+                                (SPECTRUM_HEIGHT + 2) as i32 * 4 + 21,
+                                7,
+                                7,
+                            ),
+                            value: DynVar::new(
+                                |s| s.octave as i32,
+                                |s, v| s.octave = v as u32,
+                            ),
+                            min_value: 0,
+                            max_value: MAX_OCTAVE as i32,
+                            background: CONTROL_BG,
+                            background_hover: CONTROL_HOVER,
+                            foreground: FG2,
+                        }) as Box<dyn Element>,
+                        Box::new(Label {
+                            position: Point::new(
+                                109,
+                                (SPECTRUM_HEIGHT + 2) as i32 * 4 + 22,
+                            ),
+                            text: String::from("OCT"),
+                            tooltip: Some(String::from("keyboard octave (- and =)")),
+                            colour: DIM_LABEL,
+                        }),
                         Box::new(Button {
                             rect: Rect::new(
                                 SCREEN_WIDTH as i32 / 2 - 35,
@@ -705,22 +1500,15 @@ impl Window {
                             background_hover: CONTROL_HOVER,
                             background_active: CONTROL_ACTIVE,
                             foreground: FG,
-                            on_change: {
-                                let chan = self.state.note_channel.clone();
-                                
-                                Box::new(move |pressed, s| {
-                                    if !pressed {
-                                        let mut player = s.player.lock().unwrap();
-                                        
-                                        if !player.paused {
-                                            player.flush_notes();
-                                            s.song.sequence(chan.clone());
-                                        }
-                                        
-                                        player.playhead = 0.0;
+                            on_change: Box::new(|pressed, s| {
+                                if !pressed {
+                                    if !s.player.paused() {
+                                        s.player.sequence(&s.song);
                                     }
-                                })
-                            },
+                                    
+                                    s.player.seek(0.0);
+                                }
+                            }),
                         }),
                         Box::new(Button {
                             rect: Rect::new(
@@ -740,20 +1528,23 @@ impl Window {
                             background_hover: CONTROL_HOVER,
                             background_active: CONTROL_ACTIVE,
                             foreground: FG,
-                            on_change: {
-                                let chan = self.state.note_channel.clone();
+                            on_change: Box::new(|playing, s| {
+                                if !playing {
+                                    s.player.set_paused(true);
+                                    return;
+                                }
                                 
-                                Box::new(move |playing, s| {
-                                    let mut player = s.player.lock().unwrap();
-                                    
-                                    if playing {
-                                        player.flush_notes();
-                                        s.song.sequence(chan.clone());
-                                    }
-                                    
-                                    player.paused = !playing;
-                                })
-                            },
+                                s.player.sequence(&s.song);
+                                
+                                if s.record && s.count_in {
+                                    let beat = s.player.tempo().beats_at(s.player.playhead());
+                                    let time = song::Time::from_divs((beat * song::BEAT_DIVISIONS as f64) as u32);
+                                    let bar = s.song.meter_map().meter_at(time).bar_divs();
+                                    s.player.count_in(bar as f64 / song::BEAT_DIVISIONS as f64);
+                                } else {
+                                    s.player.set_paused(false);
+                                }
+                            }),
                         }),
                         Box::new(Button {
                             rect: Rect::new(
@@ -792,20 +1583,20 @@ impl Window {
                             background_active: CONTROL_ACTIVE,
                             foreground: FG,
                             on_change: Box::new(|on, s| {
-                                s.player.lock().unwrap().mute = !on;
+                                s.player.set_mute(!on);
                             }),
                         }),
                         Box::new(Slider {
                             rect: Rect::new(
                                 SCREEN_WIDTH as i32 / 2 + 12,
                                 (SPECTRUM_HEIGHT + 2) as i32 * 4 + 21,
-                                36,
+                                24,
                                 7,
                             ),
                             state: ButtonState::Off,
                             value: DynVar::new(
-                                |s| (s.player.lock().unwrap().volume * 16.0) as i32,
-                                |s, v| s.player.lock().unwrap().volume = v as f32 / 16.0,
+                                |s| (s.player.volume() * 16.0) as i32,
+                                |s, v| s.player.set_volume(v as f32 / 16.0),
                             ),
                             min_value: 0,
                             max_value: 32,
@@ -818,6 +1609,86 @@ impl Window {
                                 format!("volume: {}%", (100.0 * val as f32 / 16.0) as u32)
                             }),
                         }),
+                        Box::new(Button {
+                            rect: Rect::new(
+                                SCREEN_WIDTH as i32 / 2 + 37,
+                                (SPECTRUM_HEIGHT + 2) as i32 * 4 + 21,
+                                11,
+                                7,
+                            ),
+                            kind: ButtonType::Toggle {
+                                on_label: String::from("\x05"),
+                                off_label: String::from("\x05"),
+                                off_foreground: DIM_LABEL,
+                            },
+                            state: ButtonState::Off,
+                            value: false,
+                            background: CONTROL_BG,
+                            background_hover: CONTROL_HOVER,
+                            background_active: CONTROL_ACTIVE,
+                            foreground: RECORD,
+                            on_change: Box::new(|on, s| s.record = on),
+                        }),
+                        Box::new(Button {
+                            rect: Rect::new(
+                                SCREEN_WIDTH as i32 / 2 + 49,
+                                (SPECTRUM_HEIGHT + 2) as i32 * 4 + 21,
+                                13,
+                                7,
+                            ),
+                            kind: ButtonType::Toggle {
+                                on_label: String::from("DUB"),
+                                off_label: String::from("REP"),
+                                off_foreground: FG,
+                            },
+                            state: ButtonState::Off,
+                            value: true,
+                            background: CONTROL_BG,
+                            background_hover: CONTROL_HOVER,
+                            background_active: CONTROL_ACTIVE,
+                            foreground: FG,
+                            on_change: Box::new(|on, s| s.overdub = on),
+                        }),
+                        Box::new(Button {
+                            rect: Rect::new(
+                                SCREEN_WIDTH as i32 / 2 + 63,
+                                (SPECTRUM_HEIGHT + 2) as i32 * 4 + 21,
+                                10,
+                                7,
+                            ),
+                            kind: ButtonType::Toggle {
+                                on_label: String::from("QZ"),
+                                off_label: String::from("QZ"),
+                                off_foreground: DIM_LABEL,
+                            },
+                            state: ButtonState::Off,
+                            value: true,
+                            background: CONTROL_BG,
+                            background_hover: CONTROL_HOVER,
+                            background_active: CONTROL_ACTIVE,
+                            foreground: FG,
+                            on_change: Box::new(|on, s| s.record_quantize = on),
+                        }),
+                        Box::new(Button {
+                            rect: Rect::new(
+                                SCREEN_WIDTH as i32 / 2 + 74,
+                                (SPECTRUM_HEIGHT + 2) as i32 * 4 + 21,
+                                10,
+                                7,
+                            ),
+                            kind: ButtonType::Toggle {
+                                on_label: String::from("IN"),
+                                off_label: String::from("IN"),
+                                off_foreground: DIM_LABEL,
+                            },
+                            state: ButtonState::Off,
+                            value: false,
+                            background: CONTROL_BG,
+                            background_hover: CONTROL_HOVER,
+                            background_active: CONTROL_ACTIVE,
+                            foreground: FG,
+                            on_change: Box::new(|on, s| s.count_in = on),
+                        }),
                         Box::new(Label {
                             position: Point::new(
                                 SCREEN_WIDTH as i32 / 2 + 85,
@@ -896,23 +1767,16 @@ impl Window {
                     scroll_x: 0.0,
                     scroll_y: 160.0,
                     num_octaves: 9,
+                    drag: None,
                     drag_start: None,
                     drag_end: None,
                     temp_note: None,
                     place_dur: song::BEAT_DIVISIONS,
-                    to_delete: None,
-                    on_change: {
-                        let chan = self.state.note_channel.clone();
-                        
-                        Box::new(move |s| {
-                            let mut player = s.player.lock().unwrap();
-                            
-                            if !player.paused {
-                                player.flush_notes();
-                                s.song.sequence(chan.clone());
-                            }
-                        })
-                    }
+                    hovered: None,
+                    loop_start: None,
+                    selection: Vec::new(),
+                    preview: Vec::new(),
+                    velocities: Vec::new(),
                 }) as Box<dyn Element>
             ]),
             background: PANEL_BG,
@@ -0,0 +1,249 @@
+//! checks that notes start and stop on exactly the right sample, however they
+//! are sent to the player.
+
+use cancrizans::player::Player;
+use cancrizans::song::{Meter, Note, Song, TempoChange, Time, BEAT_DIVISIONS};
+use cancrizans::synth::{self, Instrument, Operator, WaveType};
+
+const SAMPLE_RATES: [u32; 3] = [8000, 44100, 48000];
+
+/// an instrument which plays a square wave with no attack or release, so the
+/// output is never zero while a note is playing, and always zero otherwise.
+/// the wave isn't band-limited, since that would smooth it through zero.
+fn square() -> Instrument {
+    let mut instr = Instrument::new(4);
+    
+    Operator::new(WaveType::Square, true, 110.0)
+        .band_limited(false)
+        .env(0.0, 0.0, 1.0, 0.0)
+        .send(0, 1.0)
+        .add(&mut instr);
+    
+    instr
+}
+
+/// an instrument with just one voice, which is always being stolen. notes
+/// take a while to fade out after they're let go of.
+fn single_voice() -> Instrument {
+    let mut instr = Instrument::new(1);
+    
+    Operator::new(WaveType::Square, true, 110.0)
+        .band_limited(false)
+        .env(0.0, 0.0, 1.0, 0.5)
+        .send(0, 1.0)
+        .add(&mut instr);
+    
+    instr
+}
+
+/// plays a song on copies of an instrument, sending its notes to the player
+/// in the given order, and returns every sample until a little after the
+/// last note ends.
+fn render(song: &Song, instrument: fn() -> Instrument, notes: &[(usize, Note)], sample_rate: u32,
+    loop_region: Option<(Time, Time)>, seconds: f64) -> Vec<f32> {
+    let (mut player, chan) = Player::new();
+    let dt = 1.0 / sample_rate as f64;
+    
+    player.instruments = song.parts.iter().map(|_| instrument()).collect();
+    player.tempo = song.tempo_map();
+    player.loop_region = loop_region;
+    player.paused = false;
+    
+    for &note in notes {
+        chan.send(note).unwrap();
+    }
+    
+    (0..(seconds * sample_rate as f64) as usize).map(|_| synth::downmix(player.sample(dt))).collect()
+}
+
+/// finds the first sample of each stretch of sound, and the first sample of
+/// the silence after it.
+fn find_notes(samples: &[f32]) -> Vec<(usize, usize)> {
+    let mut found = Vec::new();
+    let mut start = None;
+    
+    for (i, &s) in samples.iter().enumerate() {
+        match start {
+            None if s != 0.0 => start = Some(i),
+            Some(n) if s == 0.0 => {
+                found.push((n, i));
+                start = None;
+            },
+            _ => {},
+        }
+    }
+    
+    found
+}
+
+/// works out which sample a note starting at `seconds` should start on, i.e.
+/// the first sample at or after that time.
+fn sample_at(seconds: f64, sample_rate: u32) -> usize {
+    (seconds * sample_rate as f64 - 1.0e-3).ceil() as usize
+}
+
+/// the samples each note in the song should start and stop on, assuming no
+/// two notes overlap unless they start and stop together.
+fn expected(song: &Song, sample_rate: u32) -> Vec<(usize, usize)> {
+    let tempo = song.tempo_map();
+    let mut expected: Vec<(usize, usize)> = song.parts
+        .iter()
+        .flatten()
+        .map(|n| (sample_at(n.start_time(&tempo), sample_rate), sample_at(n.end_time(&tempo), sample_rate)))
+        .collect();
+    
+    expected.sort();
+    expected.dedup();
+    expected
+}
+
+/// a song with notes scattered at awkward times, including chords split
+/// across parts, all separated by a little silence.
+fn awkward_song(bpm: u32) -> Song {
+    let mut song = Song::new(3, bpm, Meter::new(4, 4));
+    
+    for i in 0..24u32 {
+        let start = Time::new(i, (i * 37) % 48);
+        let duration = 1 + (i * 29) % 47;
+        
+        // every third note is a chord of three notes, so they can't cancel out.
+        let parts = if i % 3 == 0 { 0..3 } else { 0..1 };
+        
+        for part in parts {
+            song.add_note(part, Note::new(24 + part as u32 * 7 + i, start.beat, start.division, duration, 1.0));
+        }
+    }
+    
+    song
+}
+
+fn sequenced(song: &Song) -> Vec<(usize, Note)> {
+    let mut notes: Vec<(usize, Note)> = song.parts
+        .iter()
+        .enumerate()
+        .flat_map(|(i, part)| part.iter().map(move |&n| (i, n)))
+        .collect();
+    
+    notes.sort_by_key(|(_, n)| n.start.as_divs());
+    notes
+}
+
+#[test]
+fn notes_start_and_stop_on_the_right_sample() {
+    for bpm in [60, 97, 143] {
+        let song = awkward_song(bpm);
+        let seconds = song.duration() + 0.1;
+        
+        for rate in SAMPLE_RATES {
+            let samples = render(&song, square, &sequenced(&song), rate, None, seconds);
+            assert_eq!(find_notes(&samples), expected(&song, rate), "{}bpm at {}Hz", bpm, rate);
+        }
+    }
+}
+
+#[test]
+fn notes_sent_out_of_order_are_still_on_time() {
+    let song = awkward_song(120);
+    let seconds = song.duration() + 0.1;
+    
+    // send the notes backwards, and then shuffle them about a bit more.
+    let mut notes = sequenced(&song);
+    notes.reverse();
+    
+    let len = notes.len();
+    
+    for i in (0..len).step_by(3) {
+        notes.swap(i, (i * 7) % len);
+    }
+    
+    for rate in SAMPLE_RATES {
+        let samples = render(&song, square, &notes, rate, None, seconds);
+        assert_eq!(find_notes(&samples), expected(&song, rate), "{}Hz", rate);
+    }
+}
+
+#[test]
+fn notes_follow_tempo_changes() {
+    let mut song = awkward_song(90);
+    
+    song.tempo_changes = vec![
+        TempoChange { time: Time::new(6, 0), bpm: 150.0, ramp: true },
+        TempoChange { time: Time::new(13, 40), bpm: 71.5, ramp: false },
+        TempoChange { time: Time::new(20, 0), bpm: 200.0, ramp: true },
+    ];
+    
+    let seconds = song.duration() + 0.1;
+    
+    for rate in SAMPLE_RATES {
+        let samples = render(&song, square, &sequenced(&song), rate, None, seconds);
+        assert_eq!(find_notes(&samples), expected(&song, rate), "{}Hz", rate);
+    }
+}
+
+#[test]
+fn loops_restart_on_the_right_sample() {
+    let mut song = Song::new(1, 60, Meter::new(4, 4));
+    song.add_note(0, Note::new(36, 1, 0, BEAT_DIVISIONS / 2, 1.0));
+    
+    // the loop ends part-way through a sample, so it's harder to get right.
+    let loop_region = (Time::new(1, 0), Time::new(2, 17));
+    let loop_length = loop_region.1.as_beats() - loop_region.0.as_beats();
+    
+    for rate in SAMPLE_RATES {
+        let samples = render(&song, square, &sequenced(&song), rate, Some(loop_region), 6.0);
+        let found = find_notes(&samples);
+        let per_loop = loop_length * rate as f64;
+        
+        assert_eq!(found[0], (sample_at(1.0, rate), sample_at(1.5, rate)), "{}Hz", rate);
+        
+        // the playhead wraps on the first sample at or after the end of the
+        // loop, and that sample should be the first of the note again.
+        assert_eq!(found[1].0, sample_at(loop_region.1.as_beats(), rate), "{}Hz", rate);
+        
+        // after that, it wraps after either a whole number of samples just
+        // under or just over the length of the loop, depending on how far
+        // past the start it ended up last time.
+        for pair in found[1..].windows(2) {
+            let gap = (pair[1].0 - pair[0].0) as f64;
+            assert!(gap == per_loop.floor() || gap == per_loop.ceil(), "{}Hz", rate);
+        }
+        
+        for &(start, end) in &found {
+            assert!((end - start).abs_diff(rate as usize / 2) <= 1, "{}Hz", rate);
+        }
+    }
+}
+
+#[test]
+fn stolen_voices_start_the_new_note_on_time() {
+    let mut song = Song::new(1, 97, Meter::new(4, 4));
+    let mut loud = Vec::new();
+    
+    // quiet notes and loud ones take turns on a single voice. each loud note
+    // steals it from a quiet one, which is sometimes still held and
+    // sometimes fading out.
+    for i in 0..16u32 {
+        let quiet = Time::new(2 * i, (i * 37) % 48);
+        let at = quiet.add(24 + (i * 13) % 23);
+        
+        song.add_note(0, Note::new(36, quiet.beat, quiet.division, 20 + (i * 7) % 9, 0.25));
+        song.add_note(0, Note::new(43, at.beat, at.division, 30, 1.0));
+        loud.push(at);
+    }
+    
+    let tempo = song.tempo_map();
+    let seconds = song.duration() + 0.1;
+    
+    for rate in SAMPLE_RATES {
+        let samples = render(&song, single_voice, &sequenced(&song), rate, None, seconds);
+        
+        // the quiet notes (and what's left of them) are never louder than a
+        // quarter, so a loud note is there from the first sample above a half.
+        let onsets: Vec<usize> = (1..samples.len())
+            .filter(|&i| samples[i].abs() > 0.5 && samples[i - 1].abs() <= 0.5)
+            .collect();
+        
+        let expected: Vec<usize> = loud.iter().map(|t| sample_at(tempo.seconds_at(t.as_beats()), rate)).collect();
+        assert_eq!(onsets, expected, "{}Hz", rate);
+    }
+}